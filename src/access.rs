@@ -0,0 +1,155 @@
+use std::net::IpAddr;
+
+/// ### CIDR地址块
+///
+/// 如`10.0.0.0/8`或`2001:db8::/32`。不带前缀长度的地址只匹配它本身
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    /// 解析CIDR，格式无效或前缀长度超出范围时返回错误说明
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let (address, prefix) = match text.split_once('/') {
+            Some((a, p)) => (a, Some(p)),
+            None => (text, None),
+        };
+        let network: IpAddr = address.parse().map_err(|_| format!("无效的IP地址{}", address))?;
+        let max = match network {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        let prefix = match prefix {
+            Some(p) => p.parse::<u8>().ok().filter(|p| *p <= max).ok_or(format!("无效的前缀长度{}", p))?,
+            None => max,
+        };
+        Ok(Self { network: network.to_canonical(), prefix })
+    }
+
+    /// 检查IP地址是否属于该地址块。IPv4映射的IPv6地址按IPv4地址处理
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            },
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            },
+            _ => false,
+        }
+    }
+}
+
+/// 一条访问控制规则。`cidr`为`None`时匹配所有地址
+#[derive(Debug, Clone)]
+struct AccessRule {
+    allow: bool,
+    cidr: Option<Cidr>,
+}
+
+/// ### 访问控制列表 AccessList
+///
+/// 由`allow 10.0.0.0/8`、`deny all`形式的规则构成。规则按顺序检查，使用第一条匹配的规则，
+/// 没有规则匹配时允许访问
+#[derive(Debug, Clone, Default)]
+pub struct AccessList {
+    rules: Vec<AccessRule>,
+}
+
+impl AccessList {
+    /// 解析规则列表，任何一条规则无效时返回错误说明
+    pub fn parse(rules: &[String]) -> Result<Self, String> {
+        let rules = rules.iter().map(|rule| {
+            let (action, target) = rule.trim().split_once(char::is_whitespace)
+                .ok_or(format!("无效的访问控制规则{}", rule))?;
+            let allow = match action {
+                "allow" => true,
+                "deny" => false,
+                _ => return Err(format!("无效的访问控制规则{}，只能以allow或deny开头", rule)),
+            };
+            let cidr = match target.trim() {
+                "all" => None,
+                t => Some(Cidr::parse(t)?),
+            };
+            Ok(AccessRule { allow, cidr })
+        }).collect::<Result<_, String>>()?;
+        Ok(Self { rules })
+    }
+
+    /// 是否没有任何规则
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// 检查IP地址是否允许访问
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        self.rules.iter()
+            .find(|r| r.cidr.is_none_or(|c| c.contains(ip)))
+            .is_none_or(|r| r.allow)
+    }
+}
+
+/// 确定客户端的真实地址
+///
+/// 对端地址属于受信任的代理时，从右向左检查`X-Forwarded-For`，使用第一个不受信任的地址。
+/// 全部地址都受信任时使用最左边的地址。其他情况下使用对端地址
+///
+/// ## 参数
+/// - `peer`: TCP连接的对端地址
+/// - `forwarded_for`: `X-Forwarded-For`请求头的值
+/// - `trusted`: 受信任的代理
+pub fn client_ip(peer: IpAddr, forwarded_for: Option<&str>, trusted: &[Cidr]) -> IpAddr {
+    let is_trusted = |ip: IpAddr| trusted.iter().any(|c| c.contains(ip));
+    if !is_trusted(peer) {
+        return peer.to_canonical();
+    }
+    let forwarded: Vec<IpAddr> = forwarded_for.unwrap_or_default()
+        .split(',')
+        .filter_map(|a| a.trim().parse().ok())
+        .collect();
+    forwarded.iter().rev()
+        .find(|ip| !is_trusted(**ip))
+        .or(forwarded.first())
+        .map(|ip| ip.to_canonical())
+        .unwrap_or(peer.to_canonical())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(text: &str) -> IpAddr {
+        text.parse().unwrap()
+    }
+
+    #[test]
+    fn test_access_list() {
+        let rules: Vec<String> = ["deny 192.168.1.1", "allow 192.168.0.0/16", "allow 2001:db8::/32", "deny all"]
+            .iter().map(|r| r.to_string()).collect();
+        let list = AccessList::parse(&rules).unwrap();
+
+        assert!(!list.allows(ip("192.168.1.1")));
+        assert!(list.allows(ip("192.168.7.7")));
+        assert!(list.allows(ip("::ffff:192.168.7.7")));
+        assert!(list.allows(ip("2001:db8:1::1")));
+        assert!(!list.allows(ip("10.0.0.1")));
+        assert!(AccessList::default().allows(ip("10.0.0.1")));
+        assert!(Cidr::parse("0.0.0.0/0").unwrap().contains(ip("8.8.8.8")));
+        assert!(AccessList::parse(&["allow 10.0.0.0/33".to_string()]).is_err());
+        assert!(AccessList::parse(&["permit all".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_client_ip() {
+        let trusted = [Cidr::parse("10.0.0.0/8").unwrap()];
+
+        assert_eq!(client_ip(ip("203.0.113.9"), Some("1.2.3.4"), &trusted), ip("203.0.113.9"));
+        assert_eq!(client_ip(ip("10.0.0.1"), Some("1.2.3.4, 198.51.100.7, 10.0.0.2"), &trusted), ip("198.51.100.7"));
+        assert_eq!(client_ip(ip("10.0.0.1"), Some("10.0.0.3"), &trusted), ip("10.0.0.3"));
+        assert_eq!(client_ip(ip("10.0.0.1"), None, &trusted), ip("10.0.0.1"));
+    }
+}
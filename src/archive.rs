@@ -0,0 +1,285 @@
+use std::{
+    fs::{self, File},
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use bytes::Bytes;
+use chrono::{DateTime, Datelike, Local, Timelike};
+use flate2::{write::GzEncoder, Compression};
+use tokio::sync::mpsc::Sender;
+use zip::{
+    write::SimpleFileOptions,
+    CompressionMethod,
+    ZipWriter,
+};
+
+use crate::util::is_hidden;
+
+/// 向通道写入数据时的缓冲区大小
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// 文件夹打包下载的格式
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ArchiveFormat {
+    Zip,
+    TarGz,
+}
+
+impl ArchiveFormat {
+    /// 通过`archive`查询参数的值确定打包格式，无法识别时返回`None`
+    pub fn from_query(value: &str) -> Option<Self> {
+        match value {
+            "zip" => Some(ArchiveFormat::Zip),
+            "tar.gz" | "tgz" => Some(ArchiveFormat::TarGz),
+            _ => None,
+        }
+    }
+
+    /// 压缩包的文件扩展名
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ArchiveFormat::Zip => "zip",
+            ArchiveFormat::TarGz => "tar.gz",
+        }
+    }
+
+    /// 压缩包的MIME类型
+    pub fn mime(&self) -> &'static str {
+        match self {
+            ArchiveFormat::Zip => "application/zip",
+            ArchiveFormat::TarGz => "application/gzip",
+        }
+    }
+}
+
+/// 压缩包中的一项
+///
+/// - `path`: 文件在磁盘上的路径
+/// - `name`: 文件在压缩包中的路径，使用`/`分隔
+/// - `is_dir`: 是否为文件夹
+/// - `size`: 文件大小
+#[derive(Debug, Clone)]
+pub struct ArchiveEntry {
+    path: PathBuf,
+    name: String,
+    is_dir: bool,
+    size: u64,
+}
+
+/// 收集需要打包的目录树
+///
+/// 与普通的文件访问规则保持一致：
+/// - `show_hidden`为`false`时，跳过隐藏文件和隐藏文件夹
+/// - 不会进入符号链接指向的文件夹；符号链接指向的文件只有在wwwroot之内时才会被打包
+/// - 要打包的目录不在wwwroot之内时返回错误
+///
+/// ## 参数
+/// - `dir`: 要打包的目录
+/// - `root`: wwwroot
+/// - `show_hidden`: 是否包含隐藏文件
+pub fn collect_entries(dir: &Path, root: &Path, show_hidden: bool) -> io::Result<Vec<ArchiveEntry>> {
+    let root = root.canonicalize()?;
+    // 要打包的目录本身也必须在wwwroot之内
+    if !dir.canonicalize()?.starts_with(&root) {
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, "要打包的目录不在wwwroot之内"));
+    }
+    let top = dir.file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_else(|| "archive".to_string());
+    let mut entries = vec![ArchiveEntry {
+        path: dir.to_path_buf(),
+        name: top.clone(),
+        is_dir: true,
+        size: 0,
+    }];
+    let mut pending = vec![(dir.to_path_buf(), top)];
+    while let Some((current, prefix)) = pending.pop() {
+        let mut children: Vec<_> = fs::read_dir(&current)?.filter_map(|e| e.ok()).collect();
+        children.sort_by_key(|e| e.file_name());
+        for child in children {
+            let file_name = child.file_name().to_string_lossy().to_string();
+            if !show_hidden && is_hidden(&file_name) {
+                continue;
+            }
+            let name = format!("{}/{}", prefix, file_name);
+            let file_type = match child.file_type() {
+                Ok(t) => t,
+                Err(_) => continue,
+            };
+            if file_type.is_symlink() {
+                // 符号链接：只接受指向wwwroot之内的普通文件
+                match child.path().canonicalize() {
+                    Ok(target) if target.starts_with(&root) && target.is_file() => {},
+                    _ => continue,
+                }
+            }
+            let metadata = match fs::metadata(child.path()) {
+                Ok(m) => m,
+                Err(_) => continue,
+            };
+            if metadata.is_dir() {
+                pending.push((child.path(), name.clone()));
+            }
+            entries.push(ArchiveEntry {
+                path: child.path(),
+                name,
+                is_dir: metadata.is_dir(),
+                size: if metadata.is_dir() { 0 } else { metadata.len() },
+            });
+        }
+    }
+    Ok(entries)
+}
+
+/// 计算所有文件的总大小
+pub fn total_size(entries: &[ArchiveEntry]) -> u64 {
+    entries.iter().map(|e| e.size).sum()
+}
+
+/// 将收集好的目录树打包，写入`writer`
+///
+/// 打包过程中会再次检查文件大小，防止文件在收集之后变大，使总大小超过`max_size`。
+pub fn write_archive<W: Write>(format: ArchiveFormat, entries: &[ArchiveEntry], writer: W, max_size: u64) -> io::Result<()> {
+    let mut budget = max_size;
+    match format {
+        ArchiveFormat::TarGz => {
+            let mut builder = tar::Builder::new(GzEncoder::new(writer, Compression::default()));
+            for entry in entries {
+                if entry.is_dir {
+                    builder.append_dir(&entry.name, &entry.path)?;
+                } else {
+                    let mut file = File::open(&entry.path)?;
+                    let metadata = file.metadata()?;
+                    budget = consume_budget(budget, metadata.len())?;
+                    let mut header = tar::Header::new_gnu();
+                    header.set_metadata(&metadata);
+                    builder.append_data(&mut header, &entry.name, &mut file)?;
+                }
+            }
+            builder.into_inner()?.finish()?.flush()
+        },
+        ArchiveFormat::Zip => {
+            let mut zip = ZipWriter::new_stream(writer);
+            for entry in entries {
+                let mut options = SimpleFileOptions::default()
+                    .compression_method(CompressionMethod::Deflated)
+                    .large_file(entry.size > u32::MAX as u64);
+                if let Some(time) = fs::metadata(&entry.path).ok().and_then(|m| m.modified().ok()) {
+                    if let Some(t) = zip_time(time.into()) {
+                        options = options.last_modified_time(t);
+                    }
+                }
+                if entry.is_dir {
+                    zip.add_directory(format!("{}/", entry.name), options).map_err(io::Error::other)?;
+                } else {
+                    let file = File::open(&entry.path)?;
+                    zip.start_file(&entry.name, options).map_err(io::Error::other)?;
+                    // 多读取一个字节，以便发现超出预算的文件
+                    let copied = io::copy(&mut file.take(budget.saturating_add(1)), &mut zip)?;
+                    budget = consume_budget(budget, copied)?;
+                }
+            }
+            zip.finish().map_err(io::Error::other)?.flush()
+        },
+    }
+}
+
+/// 从剩余预算中扣除文件大小，超出预算时返回错误
+fn consume_budget(budget: u64, size: u64) -> io::Result<u64> {
+    budget.checked_sub(size)
+        .ok_or_else(|| io::Error::other("压缩包的总大小超过了上限"))
+}
+
+/// 将本地时间转换为ZIP格式的时间，超出ZIP能表示的范围时返回`None`
+fn zip_time(time: DateTime<Local>) -> Option<zip::DateTime> {
+    zip::DateTime::from_date_and_time(
+        u16::try_from(time.year()).ok()?,
+        time.month() as u8,
+        time.day() as u8,
+        time.hour() as u8,
+        time.minute() as u8,
+        time.second() as u8,
+    ).ok()
+}
+
+/// 将同步的`Write`接口转换为向通道发送数据块，用于在阻塞线程中生成流式响应体
+///
+/// 数据以`CHUNK_SIZE`为单位发送。接收端关闭（例如客户端断开连接）时，写入会返回`BrokenPipe`错误。
+pub struct ChannelWriter {
+    sender: Sender<io::Result<Bytes>>,
+    buffer: Vec<u8>,
+}
+
+impl ChannelWriter {
+    pub fn new(sender: Sender<io::Result<Bytes>>) -> Self {
+        Self {
+            sender,
+            buffer: Vec::with_capacity(CHUNK_SIZE),
+        }
+    }
+
+    fn send_buffer(&mut self) -> io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        let chunk = Bytes::from(std::mem::replace(&mut self.buffer, Vec::with_capacity(CHUNK_SIZE)));
+        self.sender.blocking_send(Ok(chunk))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "接收端已关闭"))
+    }
+}
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        if self.buffer.len() >= CHUNK_SIZE {
+            self.send_buffer()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.send_buffer()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, io::Read};
+
+    use flate2::read::GzDecoder;
+
+    use super::{collect_entries, total_size, write_archive, ArchiveFormat};
+
+    #[test]
+    fn test_tar_gz_archive() {
+        let root = std::env::temp_dir().join(format!("webserver-archive-{}", std::process::id()));
+        let dir = root.join("dir");
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::write(dir.join("a.txt"), b"hello").unwrap();
+        fs::write(dir.join("sub/b.txt"), b"world!").unwrap();
+        fs::write(dir.join(".secret"), b"hidden").unwrap();
+
+        let entries = collect_entries(&dir, &root, false).unwrap();
+        assert_eq!(total_size(&entries), 11);
+
+        let mut output = Vec::new();
+        write_archive(ArchiveFormat::TarGz, &entries, &mut output, 1024).unwrap();
+        let mut archive = tar::Archive::new(GzDecoder::new(&output[..]));
+        let mut names = Vec::new();
+        for entry in archive.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let mut content = String::new();
+            entry.read_to_string(&mut content).unwrap();
+            names.push(entry.path().unwrap().to_string_lossy().to_string());
+        }
+        assert_eq!(names, vec!["dir", "dir/a.txt", "dir/sub", "dir/sub/b.txt"]);
+
+        // 要打包的目录在wwwroot之外
+        assert!(collect_entries(&std::env::temp_dir(), &root, false).is_err());
+
+        // 超出大小上限时应当失败
+        assert!(write_archive(ArchiveFormat::Zip, &entries, &mut Vec::new(), 10).is_err());
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,495 @@
+use std::{
+    path::{Path, PathBuf},
+    process::Stdio,
+    time::Duration,
+};
+
+use bytes::Bytes;
+use log::{error, warn};
+use regex::Regex;
+use tokio::{
+    io::AsyncWriteExt,
+    process::Command,
+    time::timeout,
+};
+
+use crate::{
+    config::Config,
+    exception::Exception,
+    param::{HttpVersion, SCRIPT_EXTENSIONS, SERVER_NAME},
+    request::Request,
+    util::{join_under, strip_port},
+};
+
+/// CGI程序的响应
+///
+/// - `status`: 状态码
+/// - `reason`: CGI程序通过`Status`头给出的状态说明，可能没有
+/// - `headers`: 其余的响应头
+/// - `body`: 响应体
+#[derive(Debug, Clone)]
+pub struct CgiResponse {
+    pub status: u16,
+    pub reason: Option<String>,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+/// CGI脚本在URL中的位置
+///
+/// - `script_name`: 脚本的URL路径，对应`SCRIPT_NAME`
+/// - `path_info`: URL中脚本路径之后的部分，对应`PATH_INFO`
+#[derive(Debug, Clone)]
+pub struct ScriptLocation {
+    pub script_name: String,
+    pub path_info: String,
+}
+
+impl ScriptLocation {
+    /// 通过脚本在磁盘上的路径和请求路径确定脚本的URL位置
+    ///
+    /// ## 参数
+    /// - `script`: 脚本在磁盘上的路径
+    /// - `root`: 脚本所在的根目录
+    /// - `base`: 根目录对应的URL前缀，只在location使用`alias`时不为空
+    /// - `request_path`: 请求路径
+    pub fn new(script: &Path, root: &str, base: &str, request_path: &str) -> Self {
+        let relative = script.strip_prefix(root)
+            .map(|p| p.to_string_lossy().replace('\\', "/"))
+            .unwrap_or_default();
+        let script_name = format!("{}/{}", base.trim_end_matches('/'), relative.trim_start_matches('/'));
+        let path_info = match request_path.strip_prefix(&script_name) {
+            Some(rest) => rest.to_string(),
+            None => String::new(),
+        };
+        Self { script_name, path_info }
+    }
+}
+
+/// 执行CGI脚本所用的命令
+///
+/// - `program`: 要执行的程序
+/// - `args`: 命令行参数
+#[derive(Debug, Clone)]
+pub struct Invocation {
+    pub program: String,
+    pub args: Vec<String>,
+}
+
+/// 确定执行脚本所用的命令，不是CGI脚本时返回`None`
+///
+/// - CGI目录下的文件本身就是CGI程序
+/// - 其他文件按扩展名查找`interpreters`中配置的解释器，脚本路径作为最后一个参数
+///
+/// ## 参数
+/// - `script`: 脚本在磁盘上的路径
+/// - `request_path`: 请求路径
+/// - `config`: Web服务器配置
+pub fn program_for(script: &Path, request_path: &str, config: &Config) -> Option<Invocation> {
+    // 程序的工作目录会被切换到脚本所在目录，因此需要使用绝对路径
+    let absolute = || script.canonicalize()
+        .unwrap_or_else(|_| script.to_path_buf())
+        .to_string_lossy()
+        .to_string();
+    if let Some(cgi_bin) = config.cgi().cgi_bin() {
+        if request_path.starts_with(&cgi_bin) {
+            return Some(Invocation {
+                program: absolute(),
+                args: Vec::new(),
+            });
+        }
+    }
+    let extension = script.extension()?.to_string_lossy().to_string();
+    let interpreter = config.interpreter(&extension)?;
+    let program = interpreter.program()?;
+    let mut args = interpreter.args().to_vec();
+    args.push(absolute());
+    Some(Invocation {
+        program: program.to_string(),
+        args,
+    })
+}
+
+/// 判断文件是否是没有可用解释器的脚本。这类文件不能以源码形式发送给浏览器
+///
+/// 在`interpreters`中被禁用的扩展名，以及`SCRIPT_EXTENSIONS`中的扩展名都属于此类
+pub fn is_disabled_script(script: &Path, config: &Config) -> bool {
+    let extension = match script.extension() {
+        Some(e) => e.to_string_lossy().to_lowercase(),
+        None => return false,
+    };
+    config.interpreter(&extension).is_some() || SCRIPT_EXTENSIONS.contains(&extension.as_str())
+}
+
+/// 在`PATH`中查找程序，返回程序的完整路径。程序名包含路径分隔符时直接检查该路径
+pub fn find_program(program: &str) -> Option<PathBuf> {
+    let path = Path::new(program);
+    if path.components().count() > 1 {
+        return path.is_file().then(|| path.to_path_buf());
+    }
+    let paths = std::env::var_os("PATH")?;
+    std::env::split_paths(&paths).find_map(|dir| {
+        let candidate = dir.join(program);
+        if candidate.is_file() {
+            return Some(candidate);
+        }
+        // Windows下的可执行文件带有.exe扩展名
+        let exe = candidate.with_extension("exe");
+        (cfg!(windows) && exe.is_file()).then_some(exe)
+    })
+}
+
+/// 运行解释器以获取其版本号，无法获取时返回`None`
+///
+/// 版本信息可能输出到标准输出或标准错误（如Python 2），两者都会被检查
+///
+/// ## 参数
+/// - `program`: 解释器的路径
+/// - `version_args`: 获取版本所用的参数，如`--version`
+pub fn probe_version(program: &Path, version_args: &[String]) -> Option<String> {
+    let output = std::process::Command::new(program)
+        .args(version_args)
+        .stdin(Stdio::null())
+        .output()
+        .ok()?;
+    let text = [output.stdout, output.stderr].concat();
+    extract_version(&String::from_utf8_lossy(&text))
+}
+
+/// 从版本信息中提取第一个形如`x.y`或`x.y.z`的版本号
+///
+/// 例如`PHP 8.1.2-1ubuntu2.14 (cgi-fcgi)`中的`8.1.2`，`This is perl 5, version 34, subversion 0 (v5.34.0)`中的`5.34.0`
+fn extract_version(text: &str) -> Option<String> {
+    let re = Regex::new(r"\d+\.\d+(\.\d+)?").unwrap();
+    re.find(text).map(|m| m.as_str().to_string())
+}
+
+/// 判断脚本是否应当转发给FastCGI服务器执行
+///
+/// 配置了FastCGI服务器时，CGI目录之外的`.php`文件交给FastCGI服务器执行
+///
+/// ## 参数
+/// - `script`: 脚本在磁盘上的路径
+/// - `request_path`: 请求路径
+/// - `config`: Web服务器配置
+pub fn is_fastcgi(script: &Path, request_path: &str, config: &Config) -> bool {
+    if config.fastcgi().is_none() {
+        return false;
+    }
+    if let Some(cgi_bin) = config.cgi().cgi_bin() {
+        if request_path.starts_with(&cgi_bin) {
+            return false;
+        }
+    }
+    script.extension().is_some_and(|e| e.eq_ignore_ascii_case("php"))
+}
+
+/// 按照RFC 3875构建CGI程序的环境变量（meta-variables）
+///
+/// ## 参数
+/// - `request`: 来自浏览器的`request`
+/// - `config`: Web服务器配置
+/// - `script`: 脚本在磁盘上的路径。程序的工作目录会被切换，因此应当是绝对路径
+/// - `location`: 脚本在URL中的位置
+pub fn build_env(request: &Request, config: &Config, script: &Path, location: &ScriptLocation) -> Vec<(String, String)> {
+    let host = request.header("host").unwrap_or("localhost");
+    // Host头可能带有端口号，SERVER_NAME中不应包含端口
+    let server_name = strip_port(host);
+    let request_uri = match request.query().is_empty() {
+        true => request.path().to_string(),
+        false => format!("{}?{}", request.path(), request.query()),
+    };
+    let root = Path::new(config.document_root(request).0);
+    let root = root.canonicalize().unwrap_or_else(|_| root.to_path_buf());
+    let port = match (request.is_secure(), config.tls()) {
+        (true, Some(tls)) => tls.port(),
+        _ => config.port(),
+    };
+    let protocol = match request.version() {
+        HttpVersion::V1_0 => "HTTP/1.0",
+        HttpVersion::V1_1 => "HTTP/1.1",
+        HttpVersion::V2 => "HTTP/2",
+        HttpVersion::V3 => "HTTP/3",
+    };
+    let mut env = vec![
+        ("GATEWAY_INTERFACE".to_string(), "CGI/1.1".to_string()),
+        ("SERVER_SOFTWARE".to_string(), SERVER_NAME.to_string()),
+        ("SERVER_NAME".to_string(), server_name.to_string()),
+        ("SERVER_PORT".to_string(), port.to_string()),
+        ("SERVER_PROTOCOL".to_string(), protocol.to_string()),
+        ("REQUEST_METHOD".to_string(), request.method().to_string()),
+        ("REQUEST_SCHEME".to_string(), request.scheme().to_string()),
+        ("REQUEST_URI".to_string(), request_uri),
+        ("QUERY_STRING".to_string(), request.query().to_string()),
+        ("SCRIPT_NAME".to_string(), location.script_name.clone()),
+        ("SCRIPT_FILENAME".to_string(), script.to_string_lossy().to_string()),
+        ("DOCUMENT_ROOT".to_string(), root.to_string_lossy().to_string()),
+        // php-cgi要求设置REDIRECT_STATUS，否则会拒绝执行
+        ("REDIRECT_STATUS".to_string(), "200".to_string()),
+    ];
+    if request.is_secure() {
+        env.push(("HTTPS".to_string(), "on".to_string()));
+        // 客户端证书，变量名与Apache的mod_ssl相同
+        match request.client_cert() {
+            Some(cert) => {
+                env.push(("SSL_CLIENT_VERIFY".to_string(), "SUCCESS".to_string()));
+                env.push(("SSL_CLIENT_S_DN".to_string(), cert.subject.clone()));
+                env.push(("SSL_CLIENT_I_DN".to_string(), cert.issuer.clone()));
+                env.push(("SSL_CLIENT_M_SERIAL".to_string(), cert.serial.clone()));
+                for kind in ["DNS", "Email", "URI", "IP"] {
+                    let values = cert.sans.iter().filter(|(k, _)| *k == kind);
+                    for (index, (_, value)) in values.enumerate() {
+                        env.push((format!("SSL_CLIENT_SAN_{}_{}", kind, index), value.clone()));
+                    }
+                }
+            },
+            None => env.push(("SSL_CLIENT_VERIFY".to_string(), "NONE".to_string())),
+        }
+    }
+    if !location.path_info.is_empty() {
+        env.push(("PATH_INFO".to_string(), location.path_info.clone()));
+        // PATH_INFO来自请求，离开根目录时不设置PATH_TRANSLATED
+        if let Some(translated) = join_under(&root, &location.path_info) {
+            env.push(("PATH_TRANSLATED".to_string(), translated.to_string_lossy().to_string()));
+        }
+    }
+    if let Some(user) = request.remote_user() {
+        env.push(("AUTH_TYPE".to_string(), "Basic".to_string()));
+        env.push(("REMOTE_USER".to_string(), user.to_string()));
+    }
+    if let Some(addr) = request.remote_addr() {
+        env.push(("REMOTE_ADDR".to_string(), addr.ip().to_string()));
+        env.push(("REMOTE_HOST".to_string(), addr.ip().to_string()));
+        env.push(("REMOTE_PORT".to_string(), addr.port().to_string()));
+    }
+    if !request.body().is_empty() {
+        env.push(("CONTENT_LENGTH".to_string(), request.body().len().to_string()));
+    }
+    if let Some(content_type) = request.header("content-type") {
+        env.push(("CONTENT_TYPE".to_string(), content_type.to_string()));
+    }
+    // 其余请求头以HTTP_为前缀传递。已经通过其他变量传递的头，以及包含认证信息的头不会被传递。
+    // `Proxy`不是标准的请求头，传递后会成为HTTP_PROXY，使脚本通过客户端指定的代理发出请求（httpoxy）
+    for (name, value) in request.headers() {
+        let excluded = ["content-length", "content-type", "authorization", "proxy-authorization", "proxy"];
+        if excluded.iter().any(|e| name.eq_ignore_ascii_case(e)) {
+            continue;
+        }
+        let key = format!("HTTP_{}", name.to_uppercase().replace('-', "_"));
+        match env.iter_mut().find(|(k, _)| *k == key) {
+            // 同名的请求头合并为一个变量
+            Some((_, v)) => {
+                v.push_str(", ");
+                v.push_str(value);
+            },
+            None => env.push((key, value.clone())),
+        }
+    }
+    // 保留PATH，以便脚本能够找到其他程序
+    if let Ok(path) = std::env::var("PATH") {
+        env.push(("PATH".to_string(), path));
+    }
+    env
+}
+
+/// 执行CGI程序，将请求体写入其标准输入，并返回其标准输出
+///
+/// ## 参数
+/// - `invocation`: 要执行的命令
+/// - `script`: 脚本在磁盘上的路径，程序的工作目录被设置为脚本所在的目录
+/// - `env`: 环境变量，见`build_env`
+/// - `body`: 请求体
+/// - `limit`: 最长运行时间，超时后程序会被终止
+/// - `id`: 用于日志的TCP连接编号
+///
+/// ## 返回
+/// - 程序的标准输出。超时返回`Exception::GatewayTimeout`
+pub async fn execute(invocation: &Invocation, script: &Path, env: &[(String, String)], body: Bytes, limit: Duration, id: u128) -> Result<Vec<u8>, Exception> {
+    let program = &invocation.program;
+    let mut command = Command::new(program);
+    command.args(&invocation.args)
+        .env_clear()
+        .envs(env.iter().map(|(k, v)| (k, v)))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        // 等待超时后`child`会被丢弃，此时终止程序
+        .kill_on_drop(true);
+    if let Some(dir) = script.parent() {
+        command.current_dir(dir);
+    }
+    let mut child = match command.spawn() {
+        Ok(c) => c,
+        Err(e) => {
+            error!("[ID{}]无法启动CGI程序{}：{}", id, program, e);
+            return Err(Exception::CgiExecuteFailed);
+        }
+    };
+    // 在单独的任务中写入请求体，避免请求体和输出都很大时双方互相等待
+    if let Some(mut stdin) = child.stdin.take() {
+        tokio::spawn(async move {
+            // 脚本可能不读取请求体就退出，此时写入失败是正常的
+            let _ = stdin.write_all(&body).await;
+        });
+    }
+    let output = match timeout(limit, child.wait_with_output()).await {
+        Ok(Ok(o)) => o,
+        Ok(Err(e)) => {
+            error!("[ID{}]等待CGI程序{}时出错：{}", id, program, e);
+            return Err(Exception::CgiExecuteFailed);
+        },
+        Err(_) => {
+            error!("[ID{}]CGI程序{}运行超过{}秒，已被终止", id, program, limit.as_secs());
+            return Err(Exception::GatewayTimeout);
+        },
+    };
+    if !output.stderr.is_empty() {
+        warn!("[ID{}]CGI程序输出了错误信息：{}", id, String::from_utf8_lossy(&output.stderr).trim_end());
+    }
+    if !output.status.success() && output.stdout.is_empty() {
+        error!("[ID{}]CGI程序{}执行失败：{}", id, program, output.status);
+        return Err(Exception::CgiScriptError);
+    }
+    Ok(output.stdout)
+}
+
+/// 解析CGI程序的输出
+///
+/// 输出由响应头和响应体组成，二者以空行分隔，换行符可以是`\r\n`或`\n`。
+/// - `Status`头指定状态码和说明
+/// - `Location`头表示重定向，没有`Status`头时状态码为302
+/// - 其余的头原样作为响应头
+pub fn parse_response(output: &[u8]) -> Result<CgiResponse, Exception> {
+    let (head, body) = match find_head_end(output) {
+        Some((head_end, body_start)) => (&output[..head_end], &output[body_start..]),
+        None => return Err(Exception::InvalidCgiResponse),
+    };
+    let head = match std::str::from_utf8(head) {
+        Ok(h) => h,
+        Err(_) => return Err(Exception::InvalidCgiResponse),
+    };
+    let mut status = None;
+    let mut reason = None;
+    let mut headers = Vec::new();
+    for line in head.lines() {
+        let (name, value) = match line.split_once(':') {
+            Some((n, v)) => (n.trim(), v.trim()),
+            None => return Err(Exception::InvalidCgiResponse),
+        };
+        if name.eq_ignore_ascii_case("status") {
+            let (code, text) = value.split_once(' ').unwrap_or((value, ""));
+            match code.parse::<u16>() {
+                Ok(c) if (100..600).contains(&c) => status = Some(c),
+                _ => return Err(Exception::InvalidCgiResponse),
+            }
+            if !text.trim().is_empty() {
+                reason = Some(text.trim().to_string());
+            }
+        } else {
+            headers.push((name.to_string(), value.to_string()));
+        }
+    }
+    let has_location = headers.iter().any(|(n, _)| n.eq_ignore_ascii_case("location"));
+    let status = match (status, has_location) {
+        (Some(s), _) => s,
+        (None, true) => 302,
+        (None, false) => 200,
+    };
+    Ok(CgiResponse {
+        status,
+        reason,
+        headers,
+        body: body.to_vec(),
+    })
+}
+
+/// 查找响应头的结尾。返回响应头的结束位置和响应体的起始位置
+pub fn find_head_end(output: &[u8]) -> Option<(usize, usize)> {
+    let mut i = 0;
+    while i < output.len() {
+        if output[i..].starts_with(b"\r\n\r\n") {
+            return Some((i, i + 4));
+        }
+        if output[i..].starts_with(b"\n\n") {
+            return Some((i, i + 2));
+        }
+        if output[i..].starts_with(b"\n\r\n") {
+            return Some((i, i + 3));
+        }
+        i += 1;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use bytes::Bytes;
+
+    use super::{build_env, execute, extract_version, parse_response, Invocation, ScriptLocation};
+    use crate::{config::Config, exception::Exception, param::HttpVersion, request::Request};
+
+    #[test]
+    fn test_parse_response() {
+        let output = b"Status: 404 Nope\r\nContent-Type: text/plain\r\nX-Powered-By: PHP\r\n\r\nmissing";
+        let response = parse_response(output).unwrap();
+        assert_eq!(response.status, 404);
+        assert_eq!(response.reason.as_deref(), Some("Nope"));
+        assert_eq!(response.headers.len(), 2);
+        assert_eq!(response.body, b"missing");
+
+        let redirect = parse_response(b"Location: /new\n\n").unwrap();
+        assert_eq!(redirect.status, 302);
+
+        assert!(parse_response(b"<html>no headers</html>").is_err());
+    }
+
+    #[test]
+    fn test_build_env_headers() {
+        let headers = vec![
+            ("Host".to_string(), "example.com".to_string()),
+            ("Proxy".to_string(), "http://attacker:8080".to_string()),
+            ("Authorization".to_string(), "Basic YWxpY2U6cGFzcw==".to_string()),
+            ("X-Custom".to_string(), "a".to_string()),
+            ("x-custom".to_string(), "b".to_string()),
+        ];
+        let request = Request::from_parts("GET", "/index.php?a=1", HttpVersion::V1_1, headers, 0).unwrap();
+        let location = ScriptLocation { script_name: "/index.php".to_string(), path_info: String::new() };
+        let env = build_env(&request, &Config::new(), std::path::Path::new("/srv/index.php"), &location);
+        let get = |key: &str| env.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str());
+
+        assert_eq!(get("HTTP_PROXY"), None);
+        assert_eq!(get("HTTP_AUTHORIZATION"), None);
+        assert_eq!(get("HTTP_X_CUSTOM"), Some("a, b"));
+        assert_eq!(get("QUERY_STRING"), Some("a=1"));
+    }
+
+    #[test]
+    fn test_extract_version() {
+        assert_eq!(extract_version("PHP 8.1.2-1ubuntu2.14 (cgi-fcgi) (built: Aug 18 2023)").as_deref(), Some("8.1.2"));
+        assert_eq!(extract_version("This is perl 5, version 34, subversion 0 (v5.34.0)").as_deref(), Some("5.34.0"));
+        assert_eq!(extract_version("Python 3.10").as_deref(), Some("3.10"));
+        assert_eq!(extract_version("sh: Illegal option --"), None);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_execute_timeout() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let script = std::env::temp_dir().join(format!("webserver-cgi-{}.sh", std::process::id()));
+        std::fs::write(&script, "#!/bin/sh\nsleep 10\n").unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let invocation = Invocation {
+            program: script.to_string_lossy().to_string(),
+            args: Vec::new(),
+        };
+
+        let start = Instant::now();
+        let result = execute(&invocation, &script, &[], Bytes::new(), Duration::from_millis(200), 0).await;
+        assert!(matches!(result, Err(Exception::GatewayTimeout)));
+        assert!(start.elapsed() < Duration::from_secs(5));
+        std::fs::remove_file(&script).unwrap();
+    }
+}
@@ -1,12 +1,29 @@
 use serde_derive::Deserialize;
 use serde_derive::Serialize;
-use num_cpus;
 
+use std::collections::HashMap;
 use std::fs::File;
 use std::io::prelude::*;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Semaphore;
 use core::str;
 use log::{error, warn};
 
+use crate::access::{self, AccessList, Cidr};
+use crate::fastcgi::FastCgiClient;
+use crate::http_cache::HttpCache;
+use crate::location::{self, Location};
+use crate::mime::MimeTable;
+use crate::param::{HttpRequestMethod, MAX_HEADER_SIZE};
+use crate::request::Request;
+use crate::rewrite::RewriteRule;
+use crate::sse::EventHub;
+use crate::upstream::{Strategy, UpstreamGroup};
+use crate::util::{strip_port, wildcard_matches};
+use crate::websocket::{self, WebSocketHandler};
+
 /// Config
 /// 
 /// 储存服务器需要的配置信息
@@ -18,6 +35,28 @@ use log::{error, warn};
 /// - `local`: 是否工作在内网。
 ///     - 如果设置为`true`，则监听IP是`127.0.0.1`
 ///     - 如果设置为`false`，则监听IP是`0.0.0.0`
+/// - `error_pages`: 自定义错误页面。键为状态码（如`"404"`）或状态码范围（如`"5xx"`），值为相对于`www_root`的文件路径。
+///   文件中的`{{status}}`、`{{reason}}`、`{{path}}`会被替换为状态码、状态说明和请求路径。
+/// - `mime`: MIME类型相关的配置，见[MimeConfig]
+/// - `show_hidden`: 是否在文件列表和打包下载中包含以`.`开头的隐藏文件，默认为`false`。直接请求隐藏文件不受影响，如`/.well-known/`
+/// - `archive`: 文件夹打包下载的配置，见[ArchiveConfig]
+/// - `max_body_size`: 请求体的最大长度，以字节为单位。超过该长度的请求会得到413响应
+/// - `cgi`: CGI相关的配置，见[CgiConfig]
+/// - `interpreters`: 扩展名到解释器的映射，见[InterpreterConfig]。默认只有`php`，使用`php-cgi`执行
+/// - `fastcgi`: FastCGI相关的配置，见[FastCgiConfig]
+/// - `proxy`: 反向代理的路径，见[ProxyConfig]
+/// - `upstreams`: 上游组，键为上游组的名称，见[UpstreamConfig]
+/// - `http_cache`: 动态响应缓存的配置，见[HttpCacheConfig]
+/// - `websocket`: WebSocket的配置，见[WebSocketConfig]
+/// - `sse`: Server-Sent Events的配置，见[SseConfig]
+/// - `tls`: HTTPS的配置，见[TlsConfig]
+/// - `client_auth`: 需要客户端证书的路径，见[ClientAuthConfig]
+/// - `http2`: HTTP/2的配置，见[Http2Config]
+/// - `http3`: HTTP/3的配置，见[Http3Config]
+/// - `vhosts`: 基于名称的虚拟主机，在配置文件中写作`[[vhost]]`，见[VhostConfig]
+/// - `locations`: 不属于任何虚拟主机的请求使用的路径规则，在配置文件中写作`[[location]]`，见[LocationConfig]
+/// - `rewrites`: URL重写和重定向规则，在配置文件中写作`[[rewrite]]`，见[RewriteConfig]
+/// - `access`: 按客户端IP地址的访问控制，见[AccessConfig]
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Config {
     www_root: String,
@@ -25,6 +64,527 @@ pub struct Config {
     worker_threads: usize,
     cache_size: usize,
     local: bool,
+    #[serde(default)]
+    error_pages: HashMap<String, String>,
+    #[serde(default)]
+    mime: MimeConfig,
+    #[serde(skip)]
+    mime_table: MimeTable,
+    #[serde(default)]
+    show_hidden: bool,
+    #[serde(default)]
+    archive: ArchiveConfig,
+    #[serde(default = "Config::max_body_size_value")]
+    max_body_size: usize,
+    #[serde(default)]
+    cgi: CgiConfig,
+    #[serde(skip, default = "Config::cgi_permits_value")]
+    cgi_permits: Arc<Semaphore>,
+    #[serde(default = "Config::interpreters_value")]
+    interpreters: HashMap<String, InterpreterConfig>,
+    #[serde(default)]
+    fastcgi: FastCgiConfig,
+    #[serde(skip)]
+    fastcgi_client: Option<Arc<FastCgiClient>>,
+    #[serde(default)]
+    proxy: Vec<ProxyConfig>,
+    #[serde(default)]
+    upstreams: HashMap<String, UpstreamConfig>,
+    #[serde(skip)]
+    upstream_groups: HashMap<String, Arc<UpstreamGroup>>,
+    #[serde(default)]
+    http_cache: HttpCacheConfig,
+    #[serde(skip)]
+    http_cache_store: Option<Arc<HttpCache>>,
+    #[serde(default)]
+    websocket: WebSocketConfig,
+    #[serde(skip)]
+    websocket_handlers: HashMap<String, Arc<dyn WebSocketHandler>>,
+    #[serde(default)]
+    sse: SseConfig,
+    #[serde(skip, default = "Config::event_hub_value")]
+    event_hub: Arc<EventHub>,
+    #[serde(default)]
+    tls: TlsConfig,
+    #[serde(default)]
+    client_auth: Vec<ClientAuthConfig>,
+    #[serde(default)]
+    http2: Http2Config,
+    #[serde(default)]
+    http3: Http3Config,
+    #[serde(default, rename = "vhost")]
+    vhosts: Vec<VhostConfig>,
+    #[serde(default, rename = "location")]
+    locations: Vec<LocationConfig>,
+    #[serde(default, rename = "rewrite")]
+    rewrites: Vec<RewriteConfig>,
+    #[serde(skip)]
+    rewrite_rules: Vec<RewriteRule>,
+    #[serde(default)]
+    access: AccessConfig,
+    #[serde(skip)]
+    fallback_vhost: VhostConfig,
+}
+
+/// MimeConfig
+/// 
+/// MIME类型相关的配置
+/// 
+/// - `default_type`: 无法确定类型时使用的默认MIME类型
+/// - `default_charset`: 为`text/*`类型附加的默认charset。设置为空字符串以禁用
+/// - `types_file`: 系统`mime.types`文件的路径，如`/etc/mime.types`。可以不设置
+/// - `sniff`: 是否对没有扩展名（或扩展名未知）的文件进行内容嗅探
+/// - `types`: 扩展名到MIME类型的映射，会覆盖内置的MIME表和`types_file`中的项
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MimeConfig {
+    #[serde(default = "MimeConfig::default_type_value")]
+    default_type: String,
+    #[serde(default = "MimeConfig::default_charset_value")]
+    default_charset: String,
+    #[serde(default)]
+    types_file: Option<String>,
+    #[serde(default = "MimeConfig::sniff_value")]
+    sniff: bool,
+    #[serde(default)]
+    types: HashMap<String, String>,
+}
+
+/// ArchiveConfig
+/// 
+/// 文件夹打包下载的配置。启用后，可以通过`?archive=zip`或`?archive=tar.gz`将文件夹下载为压缩包
+/// 
+/// - `enabled`: 是否启用打包下载，默认不启用
+/// - `max_size`: 允许打包的文件总大小上限，以字节为单位
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ArchiveConfig {
+    #[serde(default = "ArchiveConfig::enabled_value")]
+    enabled: bool,
+    #[serde(default = "ArchiveConfig::max_size_value")]
+    max_size: u64,
+}
+
+/// CgiConfig
+/// 
+/// CGI/1.1网关的配置
+/// 
+/// - `cgi_bin`: CGI目录的URL前缀，如`/cgi-bin/`。该目录下的文件会被当作CGI程序直接执行。设置为空字符串以禁用
+/// - `timeout`: CGI程序的最长运行时间，以秒为单位。超时的程序会被终止，请求得到504响应
+/// - `max_processes`: 同时运行的CGI程序数量上限。达到上限时，新的请求得到503响应
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CgiConfig {
+    #[serde(default)]
+    cgi_bin: String,
+    #[serde(default = "CgiConfig::timeout_value")]
+    timeout: u64,
+    #[serde(default = "CgiConfig::max_processes_value")]
+    max_processes: usize,
+}
+
+/// InterpreterConfig
+/// 
+/// 执行某种扩展名的脚本所用的解释器。解释器以CGI/1.1的方式运行，脚本的路径作为最后一个参数传入
+/// 
+/// - `program`: 解释器的路径，如`php-cgi`、`python3`、`/usr/bin/perl`。设置为空字符串以禁用该扩展名，此时该类文件既不会被执行，也不会以源码的形式发送
+/// - `args`: 位于脚本路径之前的额外参数
+/// - `version_args`: 启动时获取解释器版本所用的参数，默认为`--version`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct InterpreterConfig {
+    #[serde(default)]
+    program: String,
+    #[serde(default)]
+    args: Vec<String>,
+    #[serde(default = "InterpreterConfig::version_args_value")]
+    version_args: Vec<String>,
+}
+
+/// ProxyConfig
+/// 
+/// 反向代理。请求路径以`prefix`开头的请求会被原样转发给上游服务器，上游的响应以流的形式返回浏览器
+/// 
+/// - `prefix`: 路径前缀，如`/api/`。按路径的层级匹配，`/api`能匹配`/api`和`/api/users`，但不能匹配`/apis`
+/// - `upstream`: 上游服务器的地址，如`127.0.0.1:8080`；或者`upstreams`中的上游组名称，此时按照上游组的负载均衡策略选择后端
+/// - `timeout`: 连接上游和等待响应头的超时时间，以秒为单位。超时的请求会得到504响应
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ProxyConfig {
+    prefix: String,
+    upstream: String,
+    #[serde(default = "ProxyConfig::timeout_value")]
+    timeout: u64,
+}
+
+/// UpstreamConfig
+/// 
+/// 上游组的配置。反向代理的`upstream`设置为上游组的名称时，请求会被分配给组内的后端
+/// 
+/// - `servers`: 后端地址列表，如`["127.0.0.1:8081", "127.0.0.1:8082"]`
+/// - `strategy`: 负载均衡策略，可以是`round_robin`（默认）、`least_conn`或`ip_hash`
+/// - `max_fails`: 被动失败标记。连续失败达到该次数的后端会被暂时移出
+/// - `fail_timeout`: 后端被暂时移出的时长，以秒为单位。之后会重新尝试该后端
+/// - `health_check`: 主动健康检查的路径，如`/health`。设置为空字符串以禁用主动健康检查
+/// - `health_interval`: 主动健康检查的间隔，以秒为单位
+/// - `health_timeout`: 单次健康检查的超时时间，以秒为单位
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UpstreamConfig {
+    #[serde(default)]
+    servers: Vec<String>,
+    #[serde(default)]
+    strategy: Strategy,
+    #[serde(default = "UpstreamConfig::max_fails_value")]
+    max_fails: u32,
+    #[serde(default = "UpstreamConfig::fail_timeout_value")]
+    fail_timeout: u64,
+    #[serde(default)]
+    health_check: String,
+    #[serde(default = "UpstreamConfig::health_interval_value")]
+    health_interval: u64,
+    #[serde(default = "UpstreamConfig::health_timeout_value")]
+    health_timeout: u64,
+}
+
+/// HttpCacheConfig
+/// 
+/// 动态响应缓存的配置。启用后，反向代理、CGI和FastCGI生成的响应会按照其`Cache-Control`、`Expires`和`Vary`头被缓存。
+/// 缓存可以通过`PURGE`方法（只接受来自本机的请求）或控制台的`purge`命令清除
+/// 
+/// - `enabled`: 是否启用
+/// - `max_size`: 缓存的内存预算，以字节为单位。超出时淘汰最久未使用的响应
+/// - `max_entry_size`: 单个响应体的大小上限，以字节为单位。更大的响应不会被缓存
+/// - `key`: 缓存键的模板，可以使用`{scheme}`、`{host}`、`{path}`、`{query}`和`{header:名称}`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HttpCacheConfig {
+    #[serde(default)]
+    enabled: bool,
+    #[serde(default = "HttpCacheConfig::max_size_value")]
+    max_size: usize,
+    #[serde(default = "HttpCacheConfig::max_entry_size_value")]
+    max_entry_size: usize,
+    #[serde(default = "HttpCacheConfig::key_value")]
+    key: String,
+}
+
+/// WebSocketConfig
+/// 
+/// WebSocket的配置。请求路径与`endpoints`中的某一项完全相同的升级请求由对应的处理器处理；
+/// 匹配反向代理的升级请求会被转发给上游服务器，握手成功后在浏览器和上游之间建立双向隧道
+/// 
+/// - `endpoints`: 路径到处理器名称的映射，如`"/ws/echo" = "echo"`。目前内置的处理器只有`echo`
+/// - `max_message_size`: 单条消息的大小上限，以字节为单位。超过上限时以1009状态码关闭连接
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WebSocketConfig {
+    #[serde(default)]
+    endpoints: HashMap<String, String>,
+    #[serde(default = "WebSocketConfig::max_message_size_value")]
+    max_message_size: usize,
+}
+
+/// SseConfig
+/// 
+/// Server-Sent Events的配置。对`endpoints`中路径的GET请求会得到一个保持打开的`text/event-stream`响应，
+/// 之后发布到对应频道的事件会被立即推送。来自本机的POST请求会将请求体作为事件数据发布到该频道，`?event=`参数指定事件类型
+/// 
+/// - `endpoints`: 路径到频道名称的映射，如`"/events/builds" = "builds"`
+/// - `keep_alive`: 心跳间隔，以秒为单位。空闲时定期发送注释，以保持连接并及时发现断开的浏览器
+/// - `history`: 每个频道保留的最近事件数量，浏览器重连时按照`Last-Event-ID`补发
+/// - `retry`: 建议浏览器的重连间隔，以毫秒为单位
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SseConfig {
+    #[serde(default)]
+    endpoints: HashMap<String, String>,
+    #[serde(default = "SseConfig::keep_alive_value")]
+    keep_alive: u64,
+    #[serde(default = "SseConfig::history_value")]
+    history: usize,
+    #[serde(default = "SseConfig::retry_value")]
+    retry: u64,
+}
+
+/// TlsConfig
+/// 
+/// HTTPS的配置。配置了证书时，服务器在`port`上额外监听HTTPS连接
+/// 
+/// - `port`: HTTPS监听端口
+/// - `certificates`: 证书列表，见[CertificateConfig]。握手时按照SNI主机名选择证书，没有匹配时使用第一个证书
+/// - `alpn`: 通过ALPN提供的应用层协议，按优先级排列
+/// - `versions`: 允许的TLS版本，可以是`1.2`和`1.3`
+/// - `redirect_http`: 是否将普通HTTP端口上的请求重定向到HTTPS
+/// - `client_ca`: PEM格式的CA证书文件路径。设置后，握手时会请求客户端证书并用这些CA验证，见[ClientAuthConfig]。设置为空字符串以禁用
+/// 
+/// 收到SIGHUP信号时，服务器从原来的路径重新加载全部证书
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TlsConfig {
+    #[serde(default = "TlsConfig::port_value")]
+    port: u16,
+    #[serde(default)]
+    certificates: Vec<CertificateConfig>,
+    #[serde(default = "TlsConfig::alpn_value")]
+    alpn: Vec<String>,
+    #[serde(default = "TlsConfig::versions_value")]
+    versions: Vec<String>,
+    #[serde(default)]
+    redirect_http: bool,
+    #[serde(default)]
+    client_ca: String,
+}
+
+/// CertificateConfig
+/// 
+/// 一个TLS证书
+/// 
+/// - `cert`: PEM格式的证书链文件路径，服务器证书在前，中间证书在后
+/// - `key`: PEM格式的私钥文件路径，支持PKCS#8、PKCS#1和SEC1格式
+/// - `hosts`: 使用该证书的主机名，如`["example.com", "*.example.com"]`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CertificateConfig {
+    cert: String,
+    key: String,
+    #[serde(default)]
+    hosts: Vec<String>,
+}
+
+/// ClientAuthConfig
+/// 
+/// 按路径使用客户端证书（双向TLS）。需要在`[tls]`中设置`client_ca`。
+/// 只有匹配的路径才会使用客户端证书，证书的主体和SAN会被传递给CGI程序，并记录在访问日志中
+/// 
+/// - `prefix`: 路径前缀，匹配规则与[ProxyConfig]相同
+/// - `mode`: `require`表示必须提供经过验证的证书，否则返回403；`optional`表示提供了证书时使用，没有时照常处理
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ClientAuthConfig {
+    prefix: String,
+    #[serde(default)]
+    mode: ClientAuthMode,
+}
+
+/// 客户端证书的要求
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ClientAuthMode {
+    #[default]
+    Require,
+    Optional,
+}
+
+/// Http2Config
+/// 
+/// HTTP/2的配置。启用时，HTTPS连接通过ALPN协商`h2`，普通HTTP连接支持以连接前言直接开始的h2c（prior knowledge）
+/// 
+/// - `enabled`: 是否启用HTTP/2
+/// - `max_concurrent_streams`: 每个连接上同时处理的最大流数量
+/// - `initial_window_size`: 每个流的初始流量控制窗口大小，以字节为单位
+/// - `max_frame_size`: 允许客户端发送的最大帧大小，以字节为单位，取值范围为16384到16777215
+/// - `max_header_list_size`: 请求头列表的最大长度，以字节为单位
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Http2Config {
+    #[serde(default = "Http2Config::enabled_value")]
+    enabled: bool,
+    #[serde(default = "Http2Config::max_concurrent_streams_value")]
+    max_concurrent_streams: u32,
+    #[serde(default = "Http2Config::initial_window_size_value")]
+    initial_window_size: u32,
+    #[serde(default = "Http2Config::max_frame_size_value")]
+    max_frame_size: u32,
+    #[serde(default = "Http2Config::max_header_list_size_value")]
+    max_header_list_size: u32,
+}
+
+/// Http3Config
+/// 
+/// HTTP/3的配置。启用时，服务器在HTTPS端口的同一端口号上额外监听UDP，通过QUIC提供HTTP/3，与HTTPS共用证书。
+/// HTTPS响应会带有`Alt-Svc`响应头，告知浏览器可以改用HTTP/3。需要在`[tls]`中配置证书
+/// 
+/// - `enabled`: 是否启用HTTP/3
+/// - `max_concurrent_streams`: 每个连接上同时处理的最大请求数量
+/// - `idle_timeout`: 连接的空闲超时时间，以秒为单位
+/// - `alt_svc_max_age`: `Alt-Svc`的有效期，以秒为单位
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Http3Config {
+    #[serde(default)]
+    enabled: bool,
+    #[serde(default = "Http3Config::max_concurrent_streams_value")]
+    max_concurrent_streams: u32,
+    #[serde(default = "Http3Config::idle_timeout_value")]
+    idle_timeout: u64,
+    #[serde(default = "Http3Config::alt_svc_max_age_value")]
+    alt_svc_max_age: u64,
+}
+
+/// VhostConfig
+/// 
+/// 基于名称的虚拟主机。请求按照`Host`请求头（HTTP/2和HTTP/3中为`:authority`）选择虚拟主机：先精确匹配，
+/// 再匹配`*.example.com`形式的通配符。都不匹配时使用`default = true`的虚拟主机，没有默认虚拟主机时使用顶层的`www_root`和`error_pages`
+/// 
+/// - `server_names`: 虚拟主机的主机名，如`["example.com", "*.example.com"]`
+/// - `root`: 该虚拟主机的Web根路径
+/// - `index`: 请求根路径`/`时返回的文件，相对于`root`
+/// - `listing`: 是否允许列出目录。设置为`false`时，请求目录（包括打包下载）会得到403响应
+/// - `error_pages`: 自定义错误页面，格式与顶层的`error_pages`相同，路径相对于`root`
+/// - `headers`: 添加到该虚拟主机所有响应中的响应头
+/// - `default`: 是否是默认虚拟主机
+/// - `location_configs`: 该虚拟主机的路径规则，在配置文件中写作`[[vhost.location]]`，见[LocationConfig]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct VhostConfig {
+    #[serde(default)]
+    server_names: Vec<String>,
+    root: String,
+    #[serde(default = "VhostConfig::index_value")]
+    index: String,
+    #[serde(default = "VhostConfig::listing_value")]
+    listing: bool,
+    #[serde(default)]
+    error_pages: HashMap<String, String>,
+    #[serde(default)]
+    headers: HashMap<String, String>,
+    #[serde(default)]
+    default: bool,
+    #[serde(default, rename = "location")]
+    location_configs: Vec<LocationConfig>,
+    #[serde(skip)]
+    locations: Vec<Arc<Location>>,
+}
+
+/// LocationConfig
+/// 
+/// nginx风格的路径规则，覆盖虚拟主机中对匹配路径的处理方式。每个请求在解析完成后选择一次location，优先级与nginx相同：
+/// `=`完全匹配，其次是带`^~`的最长前缀，然后是按顺序的第一个正则表达式，最后是最长前缀。前缀按路径的层级匹配
+/// 
+/// - `path`: 匹配规则，如`/static/`、`= /login`、`^~ /assets/`、`~ \.php$`、`~* \.(png|jpg)$`
+/// - `root`: 覆盖虚拟主机的根路径，完整的请求路径拼接在其后。设置为空字符串以使用虚拟主机的根路径
+/// - `alias`: 用该目录替换请求路径中location的前缀，不能用于正则表达式location。设置为空字符串以禁用
+/// - `autoindex`: 是否允许列出目录，不设置时使用虚拟主机的`listing`
+/// - `methods`: 允许的请求方法，如`["GET", "HEAD"]`。其他方法得到405响应。为空时允许所有方法
+/// - `headers`: 添加到响应中的响应头，与虚拟主机的同名响应头冲突时覆盖之
+/// - `client_auth`: 客户端证书的要求，可以是`require`或`optional`，不设置时使用顶层的`client_auth`
+/// - `proxy`: 反向代理的上游服务器地址或上游组名称，请求会被原样转发。设置为空字符串以禁用
+/// - `proxy_timeout`: 反向代理的超时时间，以秒为单位
+/// - `cache`: 是否经过动态响应缓存
+/// - `rate_limit`: 按客户端IP地址限流，见[RateLimitConfig]。超过限制的请求得到429响应
+/// - `access`: 按客户端IP地址的访问控制规则，格式与顶层的`[access]`中的`rules`相同。设置时代替顶层的规则。
+///   规则无效时服务器拒绝启动
+/// - `htpasswd`: 启用HTTP Basic认证，使用该Apache风格的htpasswd文件验证用户。文件改变时自动重新载入。
+///   启动时文件无法读取则服务器拒绝启动。设置为空字符串以禁用
+/// - `realm`: Basic认证的领域，在`WWW-Authenticate`响应头中发送给浏览器
+/// - `try_files`: 按顺序尝试的候选文件，如`["$uri", "$uri.html", "$uri/", "/index.html"]`。`$uri`代表请求路径，
+///   以`/`结尾的候选是目录。最后一项是都不存在时的回退，可以是文件或`=404`形式的状态码，状态码必须是服务器支持的标准状态码。为空时直接使用请求路径
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LocationConfig {
+    path: String,
+    #[serde(default)]
+    root: String,
+    #[serde(default)]
+    alias: String,
+    #[serde(default)]
+    autoindex: Option<bool>,
+    #[serde(default)]
+    methods: Vec<HttpRequestMethod>,
+    #[serde(default)]
+    headers: HashMap<String, String>,
+    #[serde(default)]
+    client_auth: Option<ClientAuthMode>,
+    #[serde(default)]
+    proxy: String,
+    #[serde(default = "ProxyConfig::timeout_value")]
+    proxy_timeout: u64,
+    #[serde(default = "LocationConfig::cache_value")]
+    cache: bool,
+    #[serde(default)]
+    rate_limit: Option<RateLimitConfig>,
+    #[serde(default)]
+    access: Vec<String>,
+    #[serde(default)]
+    htpasswd: String,
+    #[serde(default = "LocationConfig::realm_value")]
+    realm: String,
+    #[serde(default)]
+    try_files: Vec<String>,
+}
+
+/// RateLimitConfig
+/// 
+/// 令牌桶限流的配置。每个客户端IP地址最多可以连续发出`burst`个请求，之后每秒可以发出`rate`个请求。IPv6客户端按/64网段计算
+/// 
+/// - `rate`: 每秒补充的请求数，必须是正数
+/// - `burst`: 令牌桶的容量
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RateLimitConfig {
+    rate: f64,
+    #[serde(default = "RateLimitConfig::burst_value")]
+    burst: u32,
+}
+
+/// AccessConfig
+/// 
+/// 按客户端IP地址的访问控制。location设置了`access`时使用location的规则，否则使用这里的规则
+/// 
+/// - `rules`: 按顺序检查的规则，如`["deny 192.168.1.1", "allow 192.168.0.0/16", "allow 2001:db8::/32", "deny all"]`。
+///   使用第一条匹配的规则，没有规则匹配时允许访问
+/// - `trusted_proxies`: 受信任的代理的CIDR。来自这些地址的请求使用`X-Forwarded-For`中的客户端地址。地址无效时服务器拒绝启动
+/// - `deny_action`: 拒绝访问时的处理方式，见[DenyAction]
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct AccessConfig {
+    #[serde(default)]
+    rules: Vec<String>,
+    #[serde(default)]
+    trusted_proxies: Vec<String>,
+    #[serde(default)]
+    deny_action: DenyAction,
+    #[serde(skip)]
+    list: AccessList,
+    #[serde(skip)]
+    trusted: Vec<Cidr>,
+}
+
+/// 拒绝访问时的处理方式
+/// 
+/// - `Forbidden`: 返回403
+/// - `Close`: 不发送响应，直接关闭连接。HTTP/2和HTTP/3只重置请求所在的流
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum DenyAction {
+    #[default]
+    Forbidden,
+    Close,
+}
+
+/// RewriteConfig
+/// 
+/// URL重写规则。规则在选择location之前按顺序匹配经过百分号解码的请求路径，所有条件都满足时生效
+/// 
+/// - `pattern`: 匹配请求路径的正则表达式
+/// - `replacement`: 替换后的路径，可以带有查询字符串。可以用`$1`或`${name}`引用捕获组。
+///   没有查询字符串时保留原来的查询字符串
+/// - `status`: 为0时在服务器内部重写，然后从第一条规则重新开始匹配；为301、302、307或308时返回重定向
+/// - `hosts`: 只匹配这些主机名的请求，可以使用`*.example.com`形式的通配符。为空时匹配所有主机名
+/// - `methods`: 只匹配这些请求方法。为空时匹配所有方法
+/// - `query`: 只匹配查询字符串满足该正则表达式的请求。为空时不检查查询字符串
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RewriteConfig {
+    pattern: String,
+    replacement: String,
+    #[serde(default)]
+    status: u16,
+    #[serde(default)]
+    hosts: Vec<String>,
+    #[serde(default)]
+    methods: Vec<HttpRequestMethod>,
+    #[serde(default)]
+    query: String,
+}
+
+/// FastCgiConfig
+/// 
+/// FastCGI的配置。设置`address`后，`.php`文件会被转发给FastCGI服务器（如php-fpm）执行，而不再调用`interpreters`中配置的解释器
+/// 
+/// - `address`: FastCGI服务器的地址，如`127.0.0.1:9000`或`unix:/run/php/php-fpm.sock`。设置为空字符串以禁用
+/// - `max_idle`: 最多保留多少条空闲连接以供复用
+/// - `timeout`: 连接服务器和等待响应头的超时时间，以秒为单位。超时的请求会得到504响应
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FastCgiConfig {
+    #[serde(default)]
+    address: String,
+    #[serde(default = "FastCgiConfig::max_idle_value")]
+    max_idle: usize,
+    #[serde(default = "FastCgiConfig::timeout_value")]
+    timeout: u64,
 }
 
 impl Config {
@@ -36,6 +596,36 @@ impl Config {
             worker_threads: 0,
             cache_size: 5,
             local: true,
+            error_pages: HashMap::new(),
+            mime: MimeConfig::default(),
+            mime_table: MimeTable::from_config(&MimeConfig::default()),
+            show_hidden: false,
+            archive: ArchiveConfig::default(),
+            max_body_size: Self::max_body_size_value(),
+            cgi: CgiConfig::default(),
+            cgi_permits: Self::cgi_permits_value(),
+            interpreters: Self::interpreters_value(),
+            fastcgi: FastCgiConfig::default(),
+            fastcgi_client: None,
+            proxy: Vec::new(),
+            upstreams: HashMap::new(),
+            upstream_groups: HashMap::new(),
+            http_cache: HttpCacheConfig::default(),
+            http_cache_store: None,
+            websocket: WebSocketConfig::default(),
+            websocket_handlers: HashMap::new(),
+            sse: SseConfig::default(),
+            event_hub: Self::event_hub_value(),
+            tls: TlsConfig::default(),
+            client_auth: Vec::new(),
+            http2: Http2Config::default(),
+            http3: Http3Config::default(),
+            vhosts: Vec::new(),
+            locations: Vec::new(),
+            rewrites: Vec::new(),
+            rewrite_rules: Vec::new(),
+            access: AccessConfig::default(),
+            fallback_vhost: VhostConfig::fallback(".", &HashMap::new()),
         }
     }
 
@@ -56,12 +646,12 @@ impl Config {
             Err(e) => panic!("Error Reading file: {}", e)
         };
 
-        // 尝试读配置文件，若成功则返回，若失败则返回默认值
-        let mut raw_config = match toml::from_str(&str_val) {
+        // 配置文件无效时不能退回默认配置，否则其中的location、访问控制、认证、限流和重写规则都会失效
+        let mut raw_config: Config = match toml::from_str(&str_val) {
             Ok(t) => t,
-            Err(_) => {
-                error!("无法成功从配置文件构建配置对象，使用默认配置");
-                Config::new()
+            Err(e) => {
+                error!("无法成功从配置文件构建配置对象：{}", e);
+                panic!("无法成功从配置文件构建配置对象：{}", e);
             }
         };
         // config要求自动确定worker threads数量，使用当前cpu核心数量
@@ -72,11 +662,96 @@ impl Config {
             warn!("cache_size被设置为0，但目前尚不支持禁用缓存，因此该值将被改为5。");
             raw_config.cache_size = 5;
         }
+        raw_config.mime_table = MimeTable::from_config(&raw_config.mime);
+        raw_config.upstream_groups = raw_config.upstreams.iter()
+            .map(|(name, upstream)| (name.clone(), Arc::new(UpstreamGroup::new(name, upstream))))
+            .collect();
+        if raw_config.http_cache.enabled {
+            raw_config.http_cache_store = Some(Arc::new(HttpCache::new(&raw_config.http_cache)));
+        }
+        for (path, name) in &raw_config.websocket.endpoints {
+            match websocket::handler(name) {
+                Some(handler) => {
+                    raw_config.websocket_handlers.insert(path.clone(), handler);
+                },
+                None => error!("WebSocket路径{}使用了未知的处理器{}，该路径将被忽略", path, name),
+            }
+        }
+        raw_config.event_hub = Arc::new(EventHub::new(&raw_config.sse));
+        raw_config.cgi_permits = Arc::new(Semaphore::new(raw_config.cgi.max_processes()));
+        raw_config.fastcgi_client = raw_config.fastcgi.address().map(|address| {
+            Arc::new(FastCgiClient::new(address, raw_config.fastcgi.max_idle, raw_config.fastcgi.timeout()))
+        });
+        raw_config.fallback_vhost = VhostConfig::fallback(&raw_config.www_root, &raw_config.error_pages);
+        raw_config.fallback_vhost.location_configs = raw_config.locations.clone();
+        // location无效时不能忽略，否则其中的访问控制、认证等限制会失效，请求将落入限制更少的location
+        for vhost in raw_config.vhosts.iter_mut().chain([&mut raw_config.fallback_vhost]) {
+            vhost.locations = vhost.location_configs.iter().map(|location| {
+                match Location::new(location) {
+                    Ok(l) => Arc::new(l),
+                    Err(e) => {
+                        error!("location {}无效：{}", location.path, e);
+                        panic!("location {}无效：{}", location.path, e);
+                    },
+                }
+            }).collect();
+        }
+        // 访问控制规则无效时不能忽略，否则本应被拒绝的客户端将可以访问
+        raw_config.access.list = match AccessList::parse(&raw_config.access.rules) {
+            Ok(l) => l,
+            Err(e) => {
+                error!("访问控制规则无效：{}", e);
+                panic!("访问控制规则无效：{}", e);
+            }
+        };
+        raw_config.access.trusted = raw_config.access.trusted_proxies.iter().map(|proxy| {
+            match Cidr::parse(proxy) {
+                Ok(c) => c,
+                Err(e) => {
+                    error!("受信任的代理{}无效：{}", proxy, e);
+                    panic!("受信任的代理{}无效：{}", proxy, e);
+                },
+            }
+        }).collect();
+        // 重写规则无效时不能忽略，否则原本会被重写或重定向的请求将访问到原来的路径
+        raw_config.rewrite_rules = raw_config.rewrites.iter().map(|rewrite| {
+            match RewriteRule::new(rewrite) {
+                Ok(r) => r,
+                Err(e) => {
+                    error!("重写规则{}无效：{}", rewrite.pattern, e);
+                    panic!("重写规则{}无效：{}", rewrite.pattern, e);
+                },
+            }
+        }).collect();
+        if raw_config.vhosts.iter().filter(|v| v.default).count() > 1 {
+            warn!("配置了多个默认虚拟主机，只有第一个会生效");
+        }
         raw_config
     }
 }
 
 impl Config {
+    fn max_body_size_value() -> usize {
+        8 * 1024 * 1024
+    }
+
+    fn cgi_permits_value() -> Arc<Semaphore> {
+        Arc::new(Semaphore::new(CgiConfig::max_processes_value()))
+    }
+
+    fn event_hub_value() -> Arc<EventHub> {
+        Arc::new(EventHub::new(&SseConfig::default()))
+    }
+
+    fn interpreters_value() -> HashMap<String, InterpreterConfig> {
+        let php = InterpreterConfig {
+            program: "php-cgi".to_string(),
+            args: Vec::new(),
+            version_args: vec!["-v".to_string()],
+        };
+        HashMap::from([("php".to_string(), php)])
+    }
+
     /// 获取 WWW root
     pub fn www_root(&self) -> &str {
         &self.www_root
@@ -101,4 +776,1056 @@ impl Config {
     pub fn local(&self) -> bool {
         self.local
     }
+
+    /// 获取MIME表
+    pub fn mime(&self) -> &MimeTable {
+        &self.mime_table
+    }
+
+    /// 是否允许访问隐藏文件
+    pub fn show_hidden(&self) -> bool {
+        self.show_hidden
+    }
+
+    /// 获取打包下载的配置
+    pub fn archive(&self) -> &ArchiveConfig {
+        &self.archive
+    }
+
+    /// 获取请求体的最大长度
+    pub fn max_body_size(&self) -> usize {
+        self.max_body_size
+    }
+
+    /// 获取CGI配置
+    pub fn cgi(&self) -> &CgiConfig {
+        &self.cgi
+    }
+
+    /// 获取全部解释器配置
+    pub fn interpreters(&self) -> &HashMap<String, InterpreterConfig> {
+        &self.interpreters
+    }
+
+    /// 查找扩展名对应的解释器配置，扩展名大小写不敏感，可以带有前导的`.`
+    pub fn interpreter(&self, extension: &str) -> Option<&InterpreterConfig> {
+        self.interpreters.iter()
+            .find(|(k, _)| k.trim_start_matches('.').eq_ignore_ascii_case(extension))
+            .map(|(_, v)| v)
+    }
+
+    /// 获取限制CGI程序并发数量的信号量
+    pub fn cgi_permits(&self) -> &Arc<Semaphore> {
+        &self.cgi_permits
+    }
+
+    /// 获取FastCGI客户端，未配置FastCGI服务器时返回`None`
+    pub fn fastcgi(&self) -> Option<&Arc<FastCgiClient>> {
+        self.fastcgi_client.as_ref()
+    }
+
+    /// 查找请求路径对应的反向代理。有多个匹配时，使用前缀最长的一个
+    pub fn proxy_for(&self, path: &str) -> Option<&ProxyConfig> {
+        self.proxy.iter()
+            .filter(|p| p.matches(path))
+            .max_by_key(|p| p.prefix.len())
+    }
+
+    /// 查找上游组，不存在时返回`None`
+    pub fn upstream_group(&self, name: &str) -> Option<&Arc<UpstreamGroup>> {
+        self.upstream_groups.get(name)
+    }
+
+    /// 获取全部上游组，按名称排序
+    pub fn upstream_groups(&self) -> Vec<&Arc<UpstreamGroup>> {
+        let mut groups: Vec<_> = self.upstream_groups.values().collect();
+        groups.sort_by_key(|g| g.name());
+        groups
+    }
+
+    /// 获取动态响应缓存，未启用时返回`None`
+    pub fn http_cache(&self) -> Option<&Arc<HttpCache>> {
+        self.http_cache_store.as_ref()
+    }
+
+    /// 获取WebSocket配置
+    pub fn websocket(&self) -> &WebSocketConfig {
+        &self.websocket
+    }
+
+    /// 查找请求路径对应的WebSocket处理器，路径需要完全匹配
+    pub fn websocket_handler(&self, path: &str) -> Option<&Arc<dyn WebSocketHandler>> {
+        self.websocket_handlers.get(path)
+    }
+
+    /// 获取Server-Sent Events配置
+    pub fn sse(&self) -> &SseConfig {
+        &self.sse
+    }
+
+    /// 查找请求路径对应的SSE频道名称，路径需要完全匹配
+    pub fn sse_channel(&self, path: &str) -> Option<&str> {
+        self.sse.endpoints.get(path).map(|c| c.as_str())
+    }
+
+    /// 获取事件中心，用于向SSE频道发布事件
+    pub fn event_hub(&self) -> &Arc<EventHub> {
+        &self.event_hub
+    }
+
+    /// 获取HTTPS配置，没有配置证书时返回`None`
+    pub fn tls(&self) -> Option<&TlsConfig> {
+        match self.tls.certificates.is_empty() {
+            true => None,
+            false => Some(&self.tls),
+        }
+    }
+
+    pub fn http2(&self) -> &Http2Config {
+        &self.http2
+    }
+
+    /// 获取HTTP/3配置，没有启用HTTP/3或没有配置HTTPS时返回`None`
+    pub fn http3(&self) -> Option<&Http3Config> {
+        match self.http3.enabled && self.tls().is_some() {
+            true => Some(&self.http3),
+            false => None,
+        }
+    }
+
+    /// 查找请求路径对应的客户端证书要求。有多个匹配时，使用前缀最长的一个
+    pub fn client_auth_for(&self, path: &str) -> Option<&ClientAuthConfig> {
+        self.client_auth.iter()
+            .filter(|c| prefix_matches(&c.prefix, path))
+            .max_by_key(|c| c.prefix.len())
+    }
+
+    /// 按照请求的主机名选择虚拟主机
+    /// 
+    /// ## 参数
+    /// - `host`: `Host`请求头的值，可以带有端口号
+    /// 
+    /// ## 返回
+    /// - 精确匹配的虚拟主机，其次是通配符匹配的虚拟主机，再次是默认虚拟主机，最后是由顶层配置构成的虚拟主机
+    pub fn vhost_for(&self, host: Option<&str>) -> &VhostConfig {
+        if let Some(host) = host.map(|h| strip_port(h).trim_end_matches('.').to_lowercase()) {
+            let exact = self.vhosts.iter()
+                .find(|v| v.server_names.iter().any(|n| n.eq_ignore_ascii_case(&host)));
+            let wildcard = || self.vhosts.iter()
+                .find(|v| v.server_names.iter().any(|n| wildcard_matches(&n.to_lowercase(), &host)));
+            if let Some(vhost) = exact.or_else(wildcard) {
+                return vhost;
+            }
+        }
+        self.vhosts.iter().find(|v| v.default).unwrap_or(&self.fallback_vhost)
+    }
+
+    /// 为请求选择虚拟主机中的location，见[location::resolve]
+    pub fn location_for(&self, request: &Request) -> Option<Arc<Location>> {
+        let vhost = self.vhost_for(request.header("host"));
+        location::resolve(&vhost.locations, request.path()).cloned()
+    }
+
+    /// 获取请求对应的客户端证书要求。请求匹配的location设置了`client_auth`时使用location的，否则按路径查找顶层的`client_auth`
+    pub fn client_auth_mode_for(&self, request: &Request) -> Option<ClientAuthMode> {
+        request.location()
+            .and_then(|l| l.client_auth())
+            .or_else(|| self.client_auth_for(request.path()).map(|c| c.mode()))
+    }
+
+    /// 获取请求对应的文件系统根目录和它对应的URL前缀。location设置了`root`或`alias`时使用location的，否则使用虚拟主机的根路径
+    pub fn document_root<'a>(&'a self, request: &'a Request) -> (&'a str, &'a str) {
+        match request.location().and_then(|l| l.document_root()) {
+            Some(root) => root,
+            None => (self.vhost_for(request.header("host")).root(), ""),
+        }
+    }
+
+    /// 确定请求的客户端地址，来自受信任的代理的请求使用`X-Forwarded-For`中的地址，见[access::client_ip]
+    pub fn client_ip(&self, request: &Request) -> Option<IpAddr> {
+        let peer = request.remote_addr()?.ip();
+        Some(access::client_ip(peer, request.header("x-forwarded-for"), &self.access.trusted))
+    }
+
+    /// 检查请求的客户端地址是否允许访问。location设置了访问控制规则时使用location的规则
+    pub fn access_allowed(&self, request: &Request) -> bool {
+        let list = request.location()
+            .and_then(|l| l.access())
+            .unwrap_or(&self.access.list);
+        match self.client_ip(request) {
+            Some(ip) => list.allows(ip),
+            None => true,
+        }
+    }
+
+    /// 获取拒绝访问时的处理方式
+    pub fn deny_action(&self) -> DenyAction {
+        self.access.deny_action
+    }
+
+    /// 获取URL重写规则
+    pub fn rewrite_rules(&self) -> &[RewriteRule] {
+        &self.rewrite_rules
+    }
+
+    /// 获取虚拟主机的数量，不包括由顶层配置构成的虚拟主机
+    pub fn vhost_count(&self) -> usize {
+        self.vhosts.len()
+    }
+}
+
+impl Default for MimeConfig {
+    fn default() -> Self {
+        Self {
+            default_type: Self::default_type_value(),
+            default_charset: Self::default_charset_value(),
+            types_file: None,
+            sniff: Self::sniff_value(),
+            types: HashMap::new(),
+        }
+    }
+}
+
+impl MimeConfig {
+    fn default_type_value() -> String {
+        "application/octet-stream".to_string()
+    }
+
+    fn default_charset_value() -> String {
+        "utf-8".to_string()
+    }
+
+    fn sniff_value() -> bool {
+        true
+    }
+
+    /// 获取默认MIME类型
+    pub fn default_type(&self) -> &str {
+        &self.default_type
+    }
+
+    /// 获取默认charset，为空时返回`None`
+    pub fn default_charset(&self) -> Option<&str> {
+        match self.default_charset.is_empty() {
+            true => None,
+            false => Some(&self.default_charset),
+        }
+    }
+
+    /// 获取系统`mime.types`文件的路径
+    pub fn types_file(&self) -> Option<&str> {
+        self.types_file.as_deref()
+    }
+
+    /// 是否启用内容嗅探
+    pub fn sniff(&self) -> bool {
+        self.sniff
+    }
+
+    /// 获取自定义的MIME映射
+    pub fn types(&self) -> &HashMap<String, String> {
+        &self.types
+    }
+}
+
+impl Default for ArchiveConfig {
+    fn default() -> Self {
+        Self {
+            enabled: Self::enabled_value(),
+            max_size: Self::max_size_value(),
+        }
+    }
+}
+
+impl ArchiveConfig {
+    fn enabled_value() -> bool {
+        false
+    }
+
+    fn max_size_value() -> u64 {
+        1024 * 1024 * 1024
+    }
+
+    /// 是否启用打包下载
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// 获取打包的文件总大小上限
+    pub fn max_size(&self) -> u64 {
+        self.max_size
+    }
+}
+
+impl Default for CgiConfig {
+    fn default() -> Self {
+        Self {
+            cgi_bin: String::new(),
+            timeout: Self::timeout_value(),
+            max_processes: Self::max_processes_value(),
+        }
+    }
+}
+
+impl CgiConfig {
+    fn timeout_value() -> u64 {
+        30
+    }
+
+    fn max_processes_value() -> usize {
+        16
+    }
+
+    /// 获取CGI程序的最长运行时间
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout)
+    }
+
+    /// 获取同时运行的CGI程序数量上限
+    pub fn max_processes(&self) -> usize {
+        self.max_processes
+    }
+
+    /// 获取CGI目录的URL前缀（总是以`/`开头和结尾），禁用时返回`None`
+    pub fn cgi_bin(&self) -> Option<String> {
+        let trimmed = self.cgi_bin.trim_matches('/');
+        match trimmed.is_empty() {
+            true => None,
+            false => Some(format!("/{}/", trimmed)),
+        }
+    }
+}
+
+impl InterpreterConfig {
+    fn version_args_value() -> Vec<String> {
+        vec!["--version".to_string()]
+    }
+
+    /// 获取解释器的路径，禁用时返回`None`
+    pub fn program(&self) -> Option<&str> {
+        match self.program.is_empty() {
+            true => None,
+            false => Some(&self.program),
+        }
+    }
+
+    /// 获取位于脚本路径之前的额外参数
+    pub fn args(&self) -> &[String] {
+        &self.args
+    }
+
+    /// 获取启动时获取版本所用的参数
+    pub fn version_args(&self) -> &[String] {
+        &self.version_args
+    }
+}
+
+impl ProxyConfig {
+    fn timeout_value() -> u64 {
+        60
+    }
+
+    /// 为location中的反向代理构建配置
+    pub fn new(prefix: &str, upstream: &str, timeout: u64) -> Self {
+        Self {
+            prefix: prefix.to_string(),
+            upstream: upstream.to_string(),
+            timeout,
+        }
+    }
+
+    /// 检查请求路径是否属于该反向代理
+    pub fn matches(&self, path: &str) -> bool {
+        prefix_matches(&self.prefix, path)
+    }
+
+    /// 获取路径前缀
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    /// 获取上游服务器的地址
+    pub fn upstream(&self) -> &str {
+        &self.upstream
+    }
+
+    /// 获取超时时间
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout)
+    }
+}
+
+impl ClientAuthConfig {
+    /// 获取客户端证书的要求
+    pub fn mode(&self) -> ClientAuthMode {
+        self.mode
+    }
+}
+
+/// 按路径的层级匹配前缀，`/api`能匹配`/api`和`/api/users`，但不能匹配`/apis`
+pub(crate) fn prefix_matches(prefix: &str, path: &str) -> bool {
+    let prefix = prefix.trim_end_matches('/');
+    match path.strip_prefix(prefix) {
+        Some(rest) => rest.is_empty() || rest.starts_with('/') || prefix.is_empty(),
+        None => false,
+    }
+}
+
+impl Default for UpstreamConfig {
+    fn default() -> Self {
+        Self {
+            servers: Vec::new(),
+            strategy: Strategy::default(),
+            max_fails: Self::max_fails_value(),
+            fail_timeout: Self::fail_timeout_value(),
+            health_check: String::new(),
+            health_interval: Self::health_interval_value(),
+            health_timeout: Self::health_timeout_value(),
+        }
+    }
+}
+
+impl UpstreamConfig {
+    fn max_fails_value() -> u32 {
+        3
+    }
+
+    fn fail_timeout_value() -> u64 {
+        10
+    }
+
+    fn health_interval_value() -> u64 {
+        5
+    }
+
+    fn health_timeout_value() -> u64 {
+        2
+    }
+
+    /// 获取后端地址列表
+    pub fn servers(&self) -> &[String] {
+        &self.servers
+    }
+
+    /// 获取负载均衡策略
+    pub fn strategy(&self) -> Strategy {
+        self.strategy
+    }
+
+    /// 获取被暂时移出之前允许的连续失败次数，至少为1
+    pub fn max_fails(&self) -> u32 {
+        self.max_fails.max(1)
+    }
+
+    /// 获取后端被暂时移出的时长
+    pub fn fail_timeout(&self) -> Duration {
+        Duration::from_secs(self.fail_timeout)
+    }
+
+    /// 获取主动健康检查的路径，禁用时返回`None`
+    pub fn health_check(&self) -> Option<&str> {
+        match self.health_check.is_empty() {
+            true => None,
+            false => Some(&self.health_check),
+        }
+    }
+
+    /// 获取主动健康检查的间隔，至少为1秒
+    pub fn health_interval(&self) -> Duration {
+        Duration::from_secs(self.health_interval.max(1))
+    }
+
+    /// 获取单次健康检查的超时时间
+    pub fn health_timeout(&self) -> Duration {
+        Duration::from_secs(self.health_timeout)
+    }
+}
+
+impl Default for HttpCacheConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_size: Self::max_size_value(),
+            max_entry_size: Self::max_entry_size_value(),
+            key: Self::key_value(),
+        }
+    }
+}
+
+impl HttpCacheConfig {
+    fn max_size_value() -> usize {
+        64 * 1024 * 1024
+    }
+
+    fn max_entry_size_value() -> usize {
+        8 * 1024 * 1024
+    }
+
+    fn key_value() -> String {
+        "{scheme}://{host}{path}?{query}".to_string()
+    }
+
+    /// 获取缓存的内存预算
+    pub fn max_size(&self) -> usize {
+        self.max_size
+    }
+
+    /// 获取单个响应体的大小上限
+    pub fn max_entry_size(&self) -> usize {
+        self.max_entry_size
+    }
+
+    /// 获取缓存键的模板
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+}
+
+impl Default for WebSocketConfig {
+    fn default() -> Self {
+        Self {
+            endpoints: HashMap::new(),
+            max_message_size: Self::max_message_size_value(),
+        }
+    }
+}
+
+impl WebSocketConfig {
+    fn max_message_size_value() -> usize {
+        16 * 1024 * 1024
+    }
+
+    /// 获取单条消息的大小上限
+    pub fn max_message_size(&self) -> usize {
+        self.max_message_size
+    }
+}
+
+impl Default for SseConfig {
+    fn default() -> Self {
+        Self {
+            endpoints: HashMap::new(),
+            keep_alive: Self::keep_alive_value(),
+            history: Self::history_value(),
+            retry: Self::retry_value(),
+        }
+    }
+}
+
+impl SseConfig {
+    fn keep_alive_value() -> u64 {
+        15
+    }
+
+    fn history_value() -> usize {
+        100
+    }
+
+    fn retry_value() -> u64 {
+        3000
+    }
+
+    /// 获取心跳间隔
+    pub fn keep_alive(&self) -> Duration {
+        Duration::from_secs(self.keep_alive.max(1))
+    }
+
+    /// 获取每个频道保留的最近事件数量
+    pub fn history(&self) -> usize {
+        self.history
+    }
+
+    /// 获取建议的重连间隔，以毫秒为单位
+    pub fn retry(&self) -> u64 {
+        self.retry
+    }
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        Self {
+            port: Self::port_value(),
+            certificates: Vec::new(),
+            alpn: Self::alpn_value(),
+            versions: Self::versions_value(),
+            redirect_http: false,
+            client_ca: String::new(),
+        }
+    }
+}
+
+impl TlsConfig {
+    fn port_value() -> u16 {
+        443
+    }
+
+    fn alpn_value() -> Vec<String> {
+        vec!["h2".to_string(), "http/1.1".to_string()]
+    }
+
+    fn versions_value() -> Vec<String> {
+        vec!["1.2".to_string(), "1.3".to_string()]
+    }
+
+    /// 获取HTTPS监听端口
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// 获取全部证书
+    pub fn certificates(&self) -> &[CertificateConfig] {
+        &self.certificates
+    }
+
+    /// 获取ALPN协议列表
+    pub fn alpn(&self) -> &[String] {
+        &self.alpn
+    }
+
+    /// 获取允许的TLS版本
+    pub fn versions(&self) -> &[String] {
+        &self.versions
+    }
+
+    /// 是否将普通HTTP请求重定向到HTTPS
+    pub fn redirect_http(&self) -> bool {
+        self.redirect_http
+    }
+
+    /// 获取验证客户端证书的CA文件路径，禁用时返回`None`
+    pub fn client_ca(&self) -> Option<&str> {
+        match self.client_ca.is_empty() {
+            true => None,
+            false => Some(&self.client_ca),
+        }
+    }
+}
+
+impl Default for Http2Config {
+    fn default() -> Self {
+        Self {
+            enabled: Self::enabled_value(),
+            max_concurrent_streams: Self::max_concurrent_streams_value(),
+            initial_window_size: Self::initial_window_size_value(),
+            max_frame_size: Self::max_frame_size_value(),
+            max_header_list_size: Self::max_header_list_size_value(),
+        }
+    }
+}
+
+impl Http2Config {
+    fn enabled_value() -> bool {
+        true
+    }
+
+    fn max_concurrent_streams_value() -> u32 {
+        128
+    }
+
+    fn initial_window_size_value() -> u32 {
+        65535
+    }
+
+    fn max_frame_size_value() -> u32 {
+        16384
+    }
+
+    fn max_header_list_size_value() -> u32 {
+        MAX_HEADER_SIZE as u32
+    }
+
+    /// 是否启用HTTP/2
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// 获取每个连接上同时处理的最大流数量
+    pub fn max_concurrent_streams(&self) -> u32 {
+        self.max_concurrent_streams
+    }
+
+    /// 获取每个流的初始流量控制窗口大小
+    pub fn initial_window_size(&self) -> u32 {
+        self.initial_window_size
+    }
+
+    /// 获取允许的最大帧大小，超出范围的值会被修正到范围内
+    pub fn max_frame_size(&self) -> u32 {
+        self.max_frame_size.clamp(16384, 16777215)
+    }
+
+    /// 获取请求头列表的最大长度
+    pub fn max_header_list_size(&self) -> u32 {
+        self.max_header_list_size
+    }
+}
+
+impl Default for Http3Config {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_concurrent_streams: Self::max_concurrent_streams_value(),
+            idle_timeout: Self::idle_timeout_value(),
+            alt_svc_max_age: Self::alt_svc_max_age_value(),
+        }
+    }
+}
+
+impl Http3Config {
+    fn max_concurrent_streams_value() -> u32 {
+        100
+    }
+
+    fn idle_timeout_value() -> u64 {
+        30
+    }
+
+    fn alt_svc_max_age_value() -> u64 {
+        86400
+    }
+
+    /// 获取每个连接上同时处理的最大请求数量
+    pub fn max_concurrent_streams(&self) -> u32 {
+        self.max_concurrent_streams
+    }
+
+    /// 获取连接的空闲超时时间
+    pub fn idle_timeout(&self) -> Duration {
+        Duration::from_secs(self.idle_timeout)
+    }
+
+    /// 获取`Alt-Svc`的有效期，以秒为单位
+    pub fn alt_svc_max_age(&self) -> u64 {
+        self.alt_svc_max_age
+    }
+}
+
+impl CertificateConfig {
+    /// 获取证书链文件路径
+    pub fn cert(&self) -> &str {
+        &self.cert
+    }
+
+    /// 获取私钥文件路径
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    /// 获取使用该证书的主机名
+    pub fn hosts(&self) -> &[String] {
+        &self.hosts
+    }
+}
+
+impl Default for VhostConfig {
+    fn default() -> Self {
+        Self::fallback(".", &HashMap::new())
+    }
+}
+
+impl VhostConfig {
+    fn index_value() -> String {
+        "index.html".to_string()
+    }
+
+    fn listing_value() -> bool {
+        true
+    }
+
+    /// 由顶层的`www_root`和`error_pages`构成的虚拟主机，在没有匹配的虚拟主机时使用
+    fn fallback(root: &str, error_pages: &HashMap<String, String>) -> Self {
+        Self {
+            server_names: Vec::new(),
+            root: root.to_string(),
+            index: Self::index_value(),
+            listing: Self::listing_value(),
+            error_pages: error_pages.clone(),
+            headers: HashMap::new(),
+            default: false,
+            location_configs: Vec::new(),
+            locations: Vec::new(),
+        }
+    }
+
+    /// 获取Web根路径
+    pub fn root(&self) -> &str {
+        &self.root
+    }
+
+    /// 获取index文件相对于`root`的路径
+    pub fn index(&self) -> &str {
+        &self.index
+    }
+
+    /// 是否允许列出目录
+    pub fn listing(&self) -> bool {
+        self.listing
+    }
+
+    /// 获取添加到响应中的响应头
+    pub fn headers(&self) -> &HashMap<String, String> {
+        &self.headers
+    }
+
+    /// 用于日志的虚拟主机名称，即第一个主机名
+    pub fn name(&self) -> &str {
+        self.server_names.first().map(|n| n.as_str()).unwrap_or("-")
+    }
+
+    /// 查找状态码对应的自定义错误页面
+    /// 
+    /// 优先匹配精确的状态码（如`404`），其次匹配状态码范围（如`4xx`）。
+    /// 
+    /// ## 返回
+    /// - 错误页面相对于`root`的路径。如果没有配置，返回`None`
+    pub fn error_page(&self, code: u16) -> Option<&str> {
+        if let Some(page) = self.error_pages.get(&code.to_string()) {
+            return Some(page);
+        }
+        let class = code / 100;
+        self.error_pages.iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(&format!("{}xx", class)))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+impl LocationConfig {
+    fn cache_value() -> bool {
+        true
+    }
+
+    fn realm_value() -> String {
+        "Restricted".to_string()
+    }
+
+    /// 获取匹配规则
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// 获取根路径，没有设置时返回`None`
+    pub fn root(&self) -> Option<&str> {
+        match self.root.is_empty() {
+            true => None,
+            false => Some(&self.root),
+        }
+    }
+
+    /// 获取alias，没有设置时返回`None`
+    pub fn alias(&self) -> Option<&str> {
+        match self.alias.is_empty() {
+            true => None,
+            false => Some(&self.alias),
+        }
+    }
+
+    /// 是否允许列出目录，没有设置时返回`None`
+    pub fn autoindex(&self) -> Option<bool> {
+        self.autoindex
+    }
+
+    /// 获取允许的请求方法
+    pub fn methods(&self) -> &[HttpRequestMethod] {
+        &self.methods
+    }
+
+    /// 获取添加到响应中的响应头
+    pub fn headers(&self) -> &HashMap<String, String> {
+        &self.headers
+    }
+
+    /// 获取客户端证书的要求，没有设置时返回`None`
+    pub fn client_auth(&self) -> Option<ClientAuthMode> {
+        self.client_auth
+    }
+
+    /// 获取反向代理的上游，没有设置时返回`None`
+    pub fn proxy(&self) -> Option<&str> {
+        match self.proxy.is_empty() {
+            true => None,
+            false => Some(&self.proxy),
+        }
+    }
+
+    /// 获取反向代理的超时时间，以秒为单位
+    pub fn proxy_timeout(&self) -> u64 {
+        self.proxy_timeout
+    }
+
+    /// 是否经过动态响应缓存
+    pub fn cache(&self) -> bool {
+        self.cache
+    }
+
+    /// 获取限流配置
+    pub fn rate_limit(&self) -> Option<&RateLimitConfig> {
+        self.rate_limit.as_ref()
+    }
+
+    /// 获取访问控制规则
+    pub fn access(&self) -> &[String] {
+        &self.access
+    }
+
+    /// 获取htpasswd文件的路径，没有设置时返回`None`
+    pub fn htpasswd(&self) -> Option<&str> {
+        match self.htpasswd.is_empty() {
+            true => None,
+            false => Some(&self.htpasswd),
+        }
+    }
+
+    /// 获取Basic认证的领域
+    pub fn realm(&self) -> &str {
+        &self.realm
+    }
+
+    /// 获取按顺序尝试的候选文件
+    pub fn try_files(&self) -> &[String] {
+        &self.try_files
+    }
+}
+
+impl RewriteConfig {
+    /// 获取匹配请求路径的正则表达式
+    pub fn pattern(&self) -> &str {
+        &self.pattern
+    }
+
+    /// 获取替换后的路径
+    pub fn replacement(&self) -> &str {
+        &self.replacement
+    }
+
+    /// 获取重定向的状态码，内部重写时为0
+    pub fn status(&self) -> u16 {
+        self.status
+    }
+
+    /// 获取主机名条件
+    pub fn hosts(&self) -> &[String] {
+        &self.hosts
+    }
+
+    /// 获取请求方法条件
+    pub fn methods(&self) -> &[HttpRequestMethod] {
+        &self.methods
+    }
+
+    /// 获取查询字符串条件，没有设置时返回`None`
+    pub fn query(&self) -> Option<&str> {
+        match self.query.is_empty() {
+            true => None,
+            false => Some(&self.query),
+        }
+    }
+}
+
+impl RateLimitConfig {
+    fn burst_value() -> u32 {
+        1
+    }
+
+    /// 获取每秒补充的请求数
+    pub fn rate(&self) -> f64 {
+        self.rate
+    }
+
+    /// 获取令牌桶的容量，至少为1
+    pub fn burst(&self) -> u32 {
+        self.burst.max(1)
+    }
+}
+
+impl Default for FastCgiConfig {
+    fn default() -> Self {
+        Self {
+            address: String::new(),
+            max_idle: Self::max_idle_value(),
+            timeout: Self::timeout_value(),
+        }
+    }
+}
+
+impl FastCgiConfig {
+    fn max_idle_value() -> usize {
+        8
+    }
+
+    fn timeout_value() -> u64 {
+        60
+    }
+
+    /// 获取FastCGI服务器的地址，禁用时返回`None`
+    pub fn address(&self) -> Option<&str> {
+        match self.address.is_empty() {
+            true => None,
+            false => Some(&self.address),
+        }
+    }
+
+    /// 获取超时时间
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_vhost_for() {
+        let mut config: Config = toml::from_str(r#"
+            www_root = "./files/html/"
+            port = 7878
+            worker_threads = 0
+            cache_size = 5
+            local = true
+
+            [[vhost]]
+            server_names = ["example.com", "*.example.com"]
+            root = "/srv/example"
+            listing = false
+
+            [[vhost]]
+            server_names = ["api.example.com"]
+            root = "/srv/api"
+            [vhost.error_pages]
+            4xx = "4xx.html"
+        "#).unwrap();
+        config.fallback_vhost = VhostConfig::fallback(&config.www_root, &config.error_pages);
+
+        assert_eq!(config.vhost_for(Some("Example.COM:8080")).root(), "/srv/example");
+        // 精确匹配优先于通配符
+        assert_eq!(config.vhost_for(Some("api.example.com")).root(), "/srv/api");
+        assert_eq!(config.vhost_for(Some("api.example.com")).error_page(404), Some("4xx.html"));
+        assert_eq!(config.vhost_for(Some("www.example.com.")).root(), "/srv/example");
+        assert!(!config.vhost_for(Some("www.example.com")).listing());
+        // 没有默认虚拟主机时使用顶层配置
+        assert_eq!(config.vhost_for(Some("other.org")).root(), "./files/html/");
+        assert_eq!(config.vhost_for(None).index(), "index.html");
+
+        config.vhosts[1].default = true;
+        assert_eq!(config.vhost_for(Some("other.org")).root(), "/srv/api");
+    }
+    #[test]
+    fn test_from_toml() {
+        let config = Config::from_toml("files/config.toml");
+        assert!(config.port() > 0);
+
+        // 无效的配置文件不能退回默认配置，而是拒绝启动
+        let path = std::env::temp_dir().join(format!("webserver-config-{}.toml", std::process::id()));
+        let invalid = [
+            "[[location]]\npath = '/private/'\nrate_limit = { burst = 2 }\n",
+            "[access]\ntrusted_proxies = ['10.0.0.0/33']\n",
+        ];
+        let base = "www_root = '.'\nport = 7878\nworker_threads = 0\ncache_size = 5\nlocal = true\n";
+        for content in invalid {
+            std::fs::write(&path, format!("{}{}", base, content)).unwrap();
+            let filename = path.to_str().unwrap().to_string();
+            assert!(std::panic::catch_unwind(|| Config::from_toml(&filename)).is_err(), "{}", content);
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
 }
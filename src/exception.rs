@@ -3,11 +3,16 @@ use std::fmt;
 #[derive(Debug, Copy, Clone)]
 pub enum Exception {
     RequestIsNotUtf8,
+    MalformedRequest,
     UnSupportedRequestMethod,
     UnsupportedHttpVersion,
     FileNotFound,
-    PHPExecuteFailed,
-    PHPCodeError,
+    CgiExecuteFailed,
+    CgiScriptError,
+    InvalidCgiResponse,
+    BadGateway,
+    GatewayTimeout,
+    StatusCode(u16),
 }
 
 use Exception::*;
@@ -16,11 +21,16 @@ impl fmt::Display for Exception {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             RequestIsNotUtf8 => write!(f, "Request bytes can't be parsed in UTF-8"),
+            MalformedRequest => write!(f, "Malformed request line"),
             UnSupportedRequestMethod => write!(f, "Unsupported request method"),
             UnsupportedHttpVersion => write!(f, "Unsupported HTTP version"),
             FileNotFound => write!(f, "File not found (404)"),
-            PHPExecuteFailed => write!(f, "Couldn't invoke PHP interpreter"),
-            PHPCodeError => write!(f, "An error happened in php code"),
+            CgiExecuteFailed => write!(f, "Couldn't invoke CGI program"),
+            CgiScriptError => write!(f, "An error happened in CGI script"),
+            InvalidCgiResponse => write!(f, "CGI program returned an invalid response"),
+            BadGateway => write!(f, "Upstream server is unavailable or returned an invalid response"),
+            GatewayTimeout => write!(f, "Upstream server timed out"),
+            StatusCode(code) => write!(f, "Respond with status code {}", code),
         }
     }
 }
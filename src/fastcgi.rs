@@ -0,0 +1,421 @@
+use std::{
+    fmt,
+    io,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use bytes::Bytes;
+use log::{debug, warn};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+    sync::mpsc::Sender,
+    time::timeout,
+};
+
+use crate::{
+    cgi::{self, CgiResponse},
+    exception::Exception,
+    param::MAX_HEADER_SIZE,
+};
+
+const FCGI_VERSION_1: u8 = 1;
+
+const FCGI_BEGIN_REQUEST: u8 = 1;
+const FCGI_END_REQUEST: u8 = 3;
+const FCGI_PARAMS: u8 = 4;
+const FCGI_STDIN: u8 = 5;
+const FCGI_STDOUT: u8 = 6;
+const FCGI_STDERR: u8 = 7;
+
+const FCGI_RESPONDER: u16 = 1;
+const FCGI_KEEP_CONN: u8 = 1;
+const FCGI_REQUEST_COMPLETE: u8 = 0;
+
+/// 每条连接上同时只有一个请求，因此请求ID固定为1
+const REQUEST_ID: u16 = 1;
+
+/// 单条记录的最大内容长度
+const MAX_RECORD_LEN: usize = 65535;
+
+/// 响应头、响应头之后已经读到的响应体，以及响应提前结束时的END_REQUEST内容
+type Head = (CgiResponse, Vec<u8>, Option<Vec<u8>>);
+
+/// 读取响应头时的错误
+///
+/// - `Stale`: 在收到任何记录之前读取失败，复用的连接可能已经被服务器关闭
+/// - `Failed`: 其他错误，不能重试
+enum HeadError {
+    Stale(io::Error),
+    Failed(Exception),
+}
+
+/// 到FastCGI服务器的连接，可以是TCP连接，也可以是Unix套接字
+trait Connection: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Connection for T {}
+
+/// ### FastCGI 客户端 FastCgiClient
+///
+/// 将请求转发给FastCGI服务器（如php-fpm），并维护一个空闲连接池以复用连接。
+///
+/// - `address`: 服务器地址。`unix:`开头的地址表示Unix套接字，否则是`host:port`形式的TCP地址
+/// - `idle`: 空闲连接池
+/// - `max_idle`: 空闲连接池的最大容量
+/// - `timeout`: 连接和等待响应头的超时时间
+pub struct FastCgiClient {
+    address: String,
+    idle: Mutex<Vec<Box<dyn Connection>>>,
+    max_idle: usize,
+    timeout: Duration,
+}
+
+impl fmt::Debug for FastCgiClient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FastCgiClient")
+            .field("address", &self.address)
+            .field("max_idle", &self.max_idle)
+            .field("timeout", &self.timeout)
+            .finish()
+    }
+}
+
+impl FastCgiClient {
+    pub fn new(address: &str, max_idle: usize, timeout: Duration) -> Self {
+        Self {
+            address: address.to_string(),
+            idle: Mutex::new(Vec::new()),
+            max_idle,
+            timeout,
+        }
+    }
+
+    /// 获取服务器地址
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
+    /// 向FastCGI服务器发送一个请求
+    ///
+    /// ## 参数
+    /// - `params`: FastCGI参数，与CGI的环境变量相同
+    /// - `body`: 请求体
+    /// - `sender`: 响应体的发送端。响应头之后的数据会被逐块发送到这里
+    /// - `id`: 用于日志的TCP连接编号
+    ///
+    /// ## 返回
+    /// - 解析好的响应头（`body`为空）。响应体在后台任务中继续读取，通过`sender`发送
+    /// - 无法连接或协议出错时返回`Exception::BadGateway`，超时返回`Exception::GatewayTimeout`
+    pub async fn request(
+        self: &Arc<Self>,
+        params: &[(String, String)],
+        body: &[u8],
+        sender: Sender<io::Result<Bytes>>,
+        id: u128,
+    ) -> Result<CgiResponse, Exception> {
+        // 连接池中的连接可能已经被服务器关闭。写入通常仍会成功，之后读取第一条记录时才会遇到EOF，
+        // 因此在收到任何记录之前出错时，都换一条新连接重试一次
+        let pooled = self.idle.lock().unwrap().pop();
+        if let Some(mut conn) = pooled {
+            let result = match self.begin(&mut conn, params, body).await {
+                Ok(()) => self.read_head(&mut conn, id).await,
+                Err(e) => Err(HeadError::Stale(e)),
+            };
+            match result {
+                Ok(head) => return self.respond(conn, head, sender, id).await,
+                Err(HeadError::Stale(e)) => debug!("[ID{}]复用的FastCGI连接已失效：{}，重新连接", id, e),
+                Err(HeadError::Failed(e)) => return Err(e),
+            }
+        }
+        let mut conn = self.fresh(params, body, id).await?;
+        let head = match self.read_head(&mut conn, id).await {
+            Ok(head) => head,
+            Err(HeadError::Stale(e)) => {
+                warn!("[ID{}]读取FastCGI响应时出错：{}", id, e);
+                return Err(Exception::BadGateway);
+            },
+            Err(HeadError::Failed(e)) => return Err(e),
+        };
+        self.respond(conn, head, sender, id).await
+    }
+
+    /// 读取记录，直到得到完整的响应头
+    ///
+    /// ## 返回
+    /// - 解析好的响应头、响应头之后已经读到的响应体，以及响应在响应头结束之前就结束时的END_REQUEST内容
+    /// - 在收到任何记录之前读取出错时返回`HeadError::Stale`，其他错误返回`HeadError::Failed`
+    async fn read_head(&self, conn: &mut Box<dyn Connection>, id: u128) -> Result<Head, HeadError> {
+        let mut stdout = Vec::new();
+        let mut received = false;
+        loop {
+            let record = match timeout(self.timeout, read_record(conn)).await {
+                Ok(Ok(r)) => r,
+                Ok(Err(e)) if !received => return Err(HeadError::Stale(e)),
+                Ok(Err(e)) => {
+                    warn!("[ID{}]读取FastCGI响应时出错：{}", id, e);
+                    return Err(HeadError::Failed(Exception::BadGateway));
+                },
+                Err(_) => {
+                    warn!("[ID{}]等待FastCGI响应超时", id);
+                    return Err(HeadError::Failed(Exception::GatewayTimeout));
+                },
+            };
+            received = true;
+            match record {
+                (FCGI_STDOUT, content) => {
+                    stdout.extend_from_slice(&content);
+                    if let Some((_, body_start)) = cgi::find_head_end(&stdout) {
+                        let rest = stdout.split_off(body_start);
+                        let response = cgi::parse_response(&stdout).map_err(HeadError::Failed)?;
+                        return Ok((response, rest, None));
+                    }
+                    if stdout.len() > MAX_HEADER_SIZE {
+                        warn!("[ID{}]FastCGI响应头过长", id);
+                        return Err(HeadError::Failed(Exception::InvalidCgiResponse));
+                    }
+                },
+                (FCGI_STDERR, content) => log_stderr(&content, id),
+                (FCGI_END_REQUEST, content) => {
+                    // 响应在响应头结束之前就结束了，尝试把全部输出当作响应头解析
+                    stdout.extend_from_slice(b"\r\n\r\n");
+                    let response = cgi::parse_response(&stdout).map_err(HeadError::Failed)?;
+                    return Ok((response, Vec::new(), Some(content)));
+                },
+                (t, _) => debug!("[ID{}]忽略未知的FastCGI记录类型：{}", id, t),
+            }
+        }
+    }
+
+    /// 发送已经读到的响应体，并在后台任务中继续读取剩余的响应体
+    async fn respond(self: &Arc<Self>, conn: Box<dyn Connection>, head: Head, sender: Sender<io::Result<Bytes>>, id: u128) -> Result<CgiResponse, Exception> {
+        let (response, rest, end_request) = head;
+        if let Some(end_request) = end_request {
+            self.release(conn, &end_request);
+            return Ok(response);
+        }
+        if !rest.is_empty() && sender.send(Ok(Bytes::from(rest))).await.is_err() {
+            return Ok(response);
+        }
+        tokio::spawn(Arc::clone(self).forward(conn, sender, id));
+        Ok(response)
+    }
+
+    /// 在后台继续读取响应体，并将连接归还连接池
+    async fn forward(self: Arc<Self>, mut conn: Box<dyn Connection>, sender: Sender<io::Result<Bytes>>, id: u128) {
+        loop {
+            match read_record(&mut conn).await {
+                Ok((FCGI_STDOUT, content)) => {
+                    if !content.is_empty() && sender.send(Ok(Bytes::from(content))).await.is_err() {
+                        // 浏览器已经断开，连接上还有未读完的数据，不能再复用
+                        return;
+                    }
+                },
+                Ok((FCGI_STDERR, content)) => log_stderr(&content, id),
+                Ok((FCGI_END_REQUEST, content)) => {
+                    self.release(conn, &content);
+                    return;
+                },
+                Ok(_) => {},
+                Err(e) => {
+                    warn!("[ID{}]读取FastCGI响应体时出错：{}", id, e);
+                    let _ = sender.send(Err(e)).await;
+                    return;
+                },
+            }
+        }
+    }
+
+    /// 建立新连接并发送请求
+    async fn fresh(&self, params: &[(String, String)], body: &[u8], id: u128) -> Result<Box<dyn Connection>, Exception> {
+        let mut conn = match timeout(self.timeout, self.connect()).await {
+            Ok(Ok(c)) => c,
+            Ok(Err(e)) => {
+                warn!("[ID{}]无法连接FastCGI服务器{}：{}", id, self.address, e);
+                return Err(Exception::BadGateway);
+            },
+            Err(_) => {
+                warn!("[ID{}]连接FastCGI服务器{}超时", id, self.address);
+                return Err(Exception::GatewayTimeout);
+            },
+        };
+        if let Err(e) = self.begin(&mut conn, params, body).await {
+            warn!("[ID{}]向FastCGI服务器发送请求时出错：{}", id, e);
+            return Err(Exception::BadGateway);
+        }
+        Ok(conn)
+    }
+
+    /// 建立到服务器的连接
+    async fn connect(&self) -> io::Result<Box<dyn Connection>> {
+        #[cfg(unix)]
+        if let Some(path) = self.address.strip_prefix("unix:") {
+            return Ok(Box::new(tokio::net::UnixStream::connect(path).await?));
+        }
+        Ok(Box::new(TcpStream::connect(&self.address).await?))
+    }
+
+    /// 发送BEGIN_REQUEST、PARAMS和STDIN记录
+    async fn begin(&self, conn: &mut Box<dyn Connection>, params: &[(String, String)], body: &[u8]) -> io::Result<()> {
+        let mut data = Vec::new();
+        let mut begin = Vec::with_capacity(8);
+        begin.extend_from_slice(&FCGI_RESPONDER.to_be_bytes());
+        begin.push(FCGI_KEEP_CONN);
+        begin.extend_from_slice(&[0; 5]);
+        push_record(&mut data, FCGI_BEGIN_REQUEST, &begin);
+        push_stream(&mut data, FCGI_PARAMS, &encode_params(params));
+        push_stream(&mut data, FCGI_STDIN, body);
+        conn.write_all(&data).await?;
+        conn.flush().await
+    }
+
+    /// 请求正常结束后，将连接归还连接池
+    fn release(&self, conn: Box<dyn Connection>, end_request: &[u8]) {
+        // END_REQUEST的内容：appStatus(4字节)、protocolStatus(1字节)、保留(3字节)
+        if end_request.get(4) != Some(&FCGI_REQUEST_COMPLETE) {
+            return;
+        }
+        let mut idle = self.idle.lock().unwrap();
+        if idle.len() < self.max_idle {
+            idle.push(conn);
+        }
+    }
+}
+
+/// 将FastCGI服务器的错误输出写入日志
+fn log_stderr(content: &[u8], id: u128) {
+    if !content.is_empty() {
+        warn!("[ID{}]FastCGI服务器输出了错误信息：{}", id, String::from_utf8_lossy(content).trim_end());
+    }
+}
+
+/// 向缓冲区写入一条记录
+fn push_record(data: &mut Vec<u8>, record_type: u8, content: &[u8]) {
+    let padding = (8 - content.len() % 8) % 8;
+    data.push(FCGI_VERSION_1);
+    data.push(record_type);
+    data.extend_from_slice(&REQUEST_ID.to_be_bytes());
+    data.extend_from_slice(&(content.len() as u16).to_be_bytes());
+    data.push(padding as u8);
+    data.push(0);
+    data.extend_from_slice(content);
+    data.extend(std::iter::repeat_n(0, padding));
+}
+
+/// 将一个数据流拆分为多条记录写入缓冲区，并以一条空记录结尾
+fn push_stream(data: &mut Vec<u8>, record_type: u8, content: &[u8]) {
+    for chunk in content.chunks(MAX_RECORD_LEN) {
+        push_record(data, record_type, chunk);
+    }
+    push_record(data, record_type, &[]);
+}
+
+/// 按照FastCGI的名值对格式编码参数
+fn encode_params(params: &[(String, String)]) -> Vec<u8> {
+    fn push_len(data: &mut Vec<u8>, len: usize) {
+        if len < 128 {
+            data.push(len as u8);
+        } else {
+            data.extend_from_slice(&(len as u32 | 0x8000_0000).to_be_bytes());
+        }
+    }
+    let mut data = Vec::new();
+    for (name, value) in params {
+        push_len(&mut data, name.len());
+        push_len(&mut data, value.len());
+        data.extend_from_slice(name.as_bytes());
+        data.extend_from_slice(value.as_bytes());
+    }
+    data
+}
+
+/// 读取一条记录，返回记录类型和内容
+async fn read_record<R: AsyncRead + Unpin + ?Sized>(reader: &mut R) -> io::Result<(u8, Vec<u8>)> {
+    let mut header = [0u8; 8];
+    reader.read_exact(&mut header).await?;
+    if header[0] != FCGI_VERSION_1 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "不支持的FastCGI版本"));
+    }
+    let len = u16::from_be_bytes([header[4], header[5]]) as usize;
+    let padding = header[6] as usize;
+    let mut content = vec![0; len + padding];
+    reader.read_exact(&mut content).await?;
+    content.truncate(len);
+    Ok((header[1], content))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::{
+        net::TcpListener,
+        sync::mpsc,
+    };
+
+    use super::*;
+
+    /// 一个简单的FastCGI响应器：读取请求，返回固定的响应
+    ///
+    /// 第一条连接在一次响应之后被关闭，模拟服务器关闭了空闲连接；第二条连接保持打开以供复用
+    async fn stub_responder(listener: TcpListener) {
+        for requests in [1, 2] {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            for _ in 0..requests {
+                let mut params = Vec::new();
+                let mut stdin = Vec::new();
+                loop {
+                    let (record_type, content) = read_record(&mut stream).await.unwrap();
+                    match record_type {
+                        FCGI_PARAMS => params.extend_from_slice(&content),
+                        FCGI_STDIN if content.is_empty() => break,
+                        FCGI_STDIN => stdin.extend_from_slice(&content),
+                        _ => {},
+                    }
+                }
+                assert!(params.windows(14).any(|w| w == b"REQUEST_METHOD"));
+                let mut data = Vec::new();
+                push_record(&mut data, FCGI_STDERR, b"notice");
+                push_record(&mut data, FCGI_STDOUT, b"Status: 201 Created\r\nContent-Type: text/plain\r\n\r\nbody:");
+                push_record(&mut data, FCGI_STDOUT, &stdin);
+                push_record(&mut data, FCGI_STDOUT, &[]);
+                push_record(&mut data, FCGI_END_REQUEST, &[0, 0, 0, 0, FCGI_REQUEST_COMPLETE, 0, 0, 0]);
+                stream.write_all(&data).await.unwrap();
+            }
+        }
+        std::future::pending::<()>().await;
+    }
+
+    #[tokio::test]
+    async fn test_request_and_reuse() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        tokio::spawn(stub_responder(listener));
+        let client = Arc::new(FastCgiClient::new(&address, 4, Duration::from_secs(5)));
+        let params = vec![("REQUEST_METHOD".to_string(), "POST".to_string())];
+
+        for body in [&b"first"[..], &b"second"[..], &b"third"[..]] {
+            let (sender, mut receiver) = mpsc::channel(4);
+            let response = client.request(&params, body, sender, 0).await.unwrap();
+            assert_eq!(response.status, 201);
+            let mut received = Vec::new();
+            while let Some(chunk) = receiver.recv().await {
+                received.extend_from_slice(&chunk.unwrap());
+            }
+            assert_eq!(received, [&b"body:"[..], body].concat());
+        }
+        // 第二次请求复用的连接已被关闭，换了一条新连接重试；第三次请求复用了新连接
+        assert_eq!(client.idle.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_connect_failure() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        drop(listener);
+        let client = Arc::new(FastCgiClient::new(&address, 4, Duration::from_secs(5)));
+        let (sender, _receiver) = mpsc::channel(4);
+        let result = client.request(&[], b"", sender, 0).await;
+        assert!(matches!(result, Err(Exception::BadGateway)));
+    }
+}
@@ -0,0 +1,260 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::PathBuf,
+    sync::Mutex,
+    time::SystemTime,
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use log::{info, warn};
+use md5::Md5;
+use ring::{hmac, rand::SystemRandom};
+use sha1::{Digest, Sha1};
+
+/// APR1和crypt使用的base64字母表
+const ITOA64: &[u8] = b"./0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+
+/// 最多缓存的验证成功的用户数，缓存满时不再缓存新的用户
+const MAX_VERIFIED: usize = 1024;
+
+/// 已经载入的htpasswd文件内容，以及载入时文件的修改时间
+///
+/// `verified`缓存验证成功的用户，值是密码哈希与密码的HMAC，不保存明文密码。文件改变时清空
+#[derive(Debug, Default)]
+struct State {
+    modified: Option<SystemTime>,
+    users: HashMap<String, String>,
+    verified: HashMap<String, Vec<u8>>,
+}
+
+/// ### htpasswd文件 Htpasswd
+///
+/// Apache风格的密码文件，每行是`用户名:密码哈希`。支持bcrypt（`$2y$`）、SHA1（`{SHA}`）和APR1（`$apr1$`）哈希。
+/// 每次验证前检查文件的修改时间，文件改变时重新载入。验证成功的用户名和密码被缓存，直到文件改变
+#[derive(Debug)]
+pub struct Htpasswd {
+    path: PathBuf,
+    state: Mutex<State>,
+    // 每个进程随机生成的HMAC密钥。没有密钥时，进程内存中的缓存可以被用来离线快速猜测密码，抵消bcrypt的计算量
+    key: hmac::Key,
+}
+
+impl Htpasswd {
+    /// 载入htpasswd文件，文件无法读取时返回错误说明
+    pub fn new(path: &str) -> Result<Self, String> {
+        let path = PathBuf::from(path);
+        let modified = fs::metadata(&path).and_then(|m| m.modified()).ok();
+        let content = fs::read_to_string(&path)
+            .map_err(|e| format!("无法读取htpasswd文件{}：{}", path.display(), e))?;
+        let state = State { modified, users: parse(&content), verified: HashMap::new() };
+        let key = hmac::Key::generate(hmac::HMAC_SHA256, &SystemRandom::new())
+            .map_err(|_| "无法生成htpasswd缓存的密钥".to_string())?;
+        info!("已载入htpasswd文件{}，共{}个用户", path.display(), state.users.len());
+        Ok(Self { path, state: Mutex::new(state), key })
+    }
+
+    /// 验证用户名和密码。用户不存在、哈希格式不支持或文件无法读取时验证失败
+    ///
+    /// bcrypt的计算量很大，因此哈希在阻塞线程中验证，不占用异步工作线程
+    pub async fn verify(&self, user: &str, password: &str) -> bool {
+        let hash = {
+            let mut state = self.state.lock().unwrap();
+            self.reload(&mut state);
+            match state.users.get(user) {
+                Some(hash) => hash.clone(),
+                None => return false,
+            }
+        };
+        let message = [hash.as_bytes(), b"\0", password.as_bytes()].concat();
+        if self.state.lock().unwrap().verified.get(user).is_some_and(|tag| hmac::verify(&self.key, &message, tag).is_ok()) {
+            return true;
+        }
+        let password = password.to_string();
+        let verified = tokio::task::spawn_blocking(move || verify_hash(&hash, &password))
+            .await
+            .unwrap_or(false);
+        if verified {
+            let mut state = self.state.lock().unwrap();
+            if state.verified.len() < MAX_VERIFIED || state.verified.contains_key(user) {
+                state.verified.insert(user.to_string(), hmac::sign(&self.key, &message).as_ref().to_vec());
+            }
+        }
+        verified
+    }
+
+    /// 文件的修改时间与载入时不同时重新载入。文件无法读取时清空全部用户
+    fn reload(&self, state: &mut State) {
+        let modified = fs::metadata(&self.path).and_then(|m| m.modified()).ok();
+        if modified.is_some() && modified == state.modified {
+            return;
+        }
+        state.modified = modified;
+        state.verified.clear();
+        state.users = match fs::read_to_string(&self.path) {
+            Ok(content) => {
+                let users = parse(&content);
+                info!("已载入htpasswd文件{}，共{}个用户", self.path.display(), users.len());
+                users
+            },
+            Err(e) => {
+                warn!("无法读取htpasswd文件{}：{}", self.path.display(), e);
+                HashMap::new()
+            },
+        };
+    }
+}
+
+/// 解析htpasswd文件，忽略空行和以`#`开头的注释
+fn parse(content: &str) -> HashMap<String, String> {
+    content.lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| line.split_once(':'))
+        .map(|(user, hash)| (user.to_string(), hash.to_string()))
+        .collect()
+}
+
+/// 使用哈希的格式对应的算法验证密码
+pub fn verify_hash(hash: &str, password: &str) -> bool {
+    if hash.starts_with("$2y$") || hash.starts_with("$2a$") || hash.starts_with("$2b$") {
+        bcrypt::verify(password, hash).unwrap_or(false)
+    } else if let Some(digest) = hash.strip_prefix("{SHA}") {
+        let expected = STANDARD.encode(Sha1::digest(password.as_bytes()));
+        constant_time_eq(digest.as_bytes(), expected.as_bytes())
+    } else if let Some(rest) = hash.strip_prefix("$apr1$") {
+        let salt = rest.split('$').next().unwrap_or_default();
+        constant_time_eq(hash.as_bytes(), apr1(password.as_bytes(), salt.as_bytes()).as_bytes())
+    } else {
+        warn!("不支持的htpasswd哈希格式，只支持bcrypt、{{SHA}}和$apr1$");
+        false
+    }
+}
+
+/// 计算Apache的APR1（基于MD5的crypt）哈希，返回`$apr1$salt$hash`形式的字符串
+fn apr1(password: &[u8], salt: &[u8]) -> String {
+    const MAGIC: &[u8] = b"$apr1$";
+    let salt = &salt[..salt.len().min(8)];
+
+    let alternate = Md5::new().chain_update(password).chain_update(salt).chain_update(password).finalize();
+    let mut context = Md5::new().chain_update(password).chain_update(MAGIC).chain_update(salt);
+    for chunk in (0..password.len()).step_by(16) {
+        context.update(&alternate[..(password.len() - chunk).min(16)]);
+    }
+    let mut length = password.len();
+    while length > 0 {
+        match length & 1 {
+            1 => context.update([0]),
+            _ => context.update(&password[..1]),
+        }
+        length >>= 1;
+    }
+    let mut digest = context.finalize();
+
+    for round in 0..1000 {
+        let mut context = Md5::new();
+        match round & 1 {
+            1 => context.update(password),
+            _ => context.update(digest),
+        }
+        if round % 3 != 0 {
+            context.update(salt);
+        }
+        if round % 7 != 0 {
+            context.update(password);
+        }
+        match round & 1 {
+            1 => context.update(digest),
+            _ => context.update(password),
+        }
+        digest = context.finalize();
+    }
+
+    let mut encoded = String::new();
+    let mut push = |value: u32, count: usize| {
+        for i in 0..count {
+            encoded.push(ITOA64[((value >> (6 * i)) & 0x3f) as usize] as char);
+        }
+    };
+    for (a, b, c) in [(0, 6, 12), (1, 7, 13), (2, 8, 14), (3, 9, 15), (4, 10, 5)] {
+        push(((digest[a] as u32) << 16) | ((digest[b] as u32) << 8) | digest[c] as u32, 4);
+    }
+    push(digest[11] as u32, 2);
+    format!("$apr1${}${}", String::from_utf8_lossy(salt), encoded)
+}
+
+/// 比较两个字节串，用时与内容无关
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// 解析`Authorization: Basic ...`请求头，返回用户名和密码。不是Basic认证或格式无效时返回`None`
+pub fn parse_authorization(header: &str) -> Option<(String, String)> {
+    let (scheme, credentials) = header.trim().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    let decoded = STANDARD.decode(credentials.trim()).ok()?;
+    let decoded = String::from_utf8(decoded).ok()?;
+    let (user, password) = decoded.split_once(':')?;
+    Some((user.to_string(), password.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verify_hash() {
+        assert!(verify_hash("$apr1$r31.....$HqJZimcKQFAMYayBlzkrA/", "myPassword"));
+        assert!(!verify_hash("$apr1$r31.....$HqJZimcKQFAMYayBlzkrA/", "mypassword"));
+        assert!(verify_hash("{SHA}W6ph5Mm5Pz8GgiULbPgzG37mj9g=", "password"));
+        let bcrypt = bcrypt::hash("secret", 4).unwrap();
+        assert!(verify_hash(&bcrypt, "secret"));
+        assert!(!verify_hash(&bcrypt, "Secret"));
+        assert!(!verify_hash("password", "password"));
+    }
+
+    #[tokio::test]
+    async fn test_reload() {
+        let path = std::env::temp_dir().join(format!("webserver-htpasswd-{}", std::process::id()));
+        assert!(Htpasswd::new(path.to_str().unwrap()).is_err());
+        let bcrypt = bcrypt::hash("secret", 4).unwrap();
+        fs::write(&path, format!("# users\nalice:{{SHA}}W6ph5Mm5Pz8GgiULbPgzG37mj9g=\ncarol:{}\n", bcrypt)).unwrap();
+        let htpasswd = Htpasswd::new(path.to_str().unwrap()).unwrap();
+        assert!(htpasswd.verify("alice", "password").await);
+        assert!(!htpasswd.verify("bob", "password").await);
+        assert!(htpasswd.verify("carol", "secret").await);
+        // 验证成功的结果被缓存，错误的密码不会命中缓存
+        assert!(htpasswd.state.lock().unwrap().verified.contains_key("carol"));
+        assert!(htpasswd.verify("carol", "secret").await);
+        assert!(!htpasswd.verify("carol", "Secret").await);
+        // 缓存的大小有上限
+        {
+            let mut state = htpasswd.state.lock().unwrap();
+            state.verified.remove("alice");
+            for i in state.verified.len()..MAX_VERIFIED {
+                state.verified.insert(format!("user{}", i), Vec::new());
+            }
+        }
+        assert!(htpasswd.verify("alice", "password").await);
+        assert!(!htpasswd.state.lock().unwrap().verified.contains_key("alice"));
+
+        // 修改时间改变后重新载入，并清空缓存
+        fs::write(&path, "bob:{SHA}W6ph5Mm5Pz8GgiULbPgzG37mj9g=\n").unwrap();
+        let file = fs::File::options().write(true).open(&path).unwrap();
+        file.set_modified(SystemTime::now() + std::time::Duration::from_secs(10)).unwrap();
+        assert!(!htpasswd.verify("alice", "password").await);
+        assert!(!htpasswd.verify("carol", "secret").await);
+        assert!(htpasswd.verify("bob", "password").await);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_parse_authorization() {
+        assert_eq!(parse_authorization("Basic YWxpY2U6cGE6c3M="), Some(("alice".to_string(), "pa:ss".to_string())));
+        assert_eq!(parse_authorization("Bearer abc"), None);
+        assert_eq!(parse_authorization("Basic !!!"), None);
+    }
+}
@@ -0,0 +1,313 @@
+use std::{
+    future::poll_fn,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::Instant,
+};
+
+use bytes::{Bytes, BytesMut};
+use h2::{
+    server::{self, SendResponse},
+    Reason, RecvStream, SendStream,
+};
+use http::{HeaderName, HeaderValue, StatusCode};
+use log::{debug, warn};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::{
+    cache::FileCache,
+    config::Config,
+    param::{HttpRequestMethod, HttpVersion},
+    request::Request,
+    response::Response,
+    tls::TlsInfo,
+};
+
+/// HTTP/2连接前言的开头。它看起来像一个HTTP/1.x请求头，因此可以在读取请求头之后识别h2c（prior knowledge）连接
+pub const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\n";
+
+/// HTTP/2禁止的连接相关响应头，见RFC 9113 8.2.2
+const CONNECTION_HEADERS: [&str; 5] = ["connection", "keep-alive", "proxy-connection", "transfer-encoding", "upgrade"];
+
+/// 先返回已经读到的字节，再从底层连接读取的流
+///
+/// 识别h2c连接时，连接前言已经作为请求头被读入缓冲区，需要交还给HTTP/2重新读取
+pub struct Rewind<S> {
+    prefix: Bytes,
+    inner: S,
+}
+
+impl<S> Rewind<S> {
+    pub fn new(prefix: Vec<u8>, inner: S) -> Self {
+        Self { prefix: Bytes::from(prefix), inner }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Rewind<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        if !self.prefix.is_empty() {
+            let n = self.prefix.len().min(buf.remaining());
+            buf.put_slice(&self.prefix.split_to(n));
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Rewind<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// 在一个连接上提供HTTP/2服务，直到连接关闭
+///
+/// 每个流在单独的任务中处理，因此同一连接上的请求可以并发执行。HPACK、流量控制和SETTINGS由`h2`完成
+///
+/// ## 参数
+/// - `io`: 已经确定使用HTTP/2的连接，TLS连接或`Rewind`包装的h2c连接
+/// - `addr`: 客户端的地址
+/// - `id`: 当前TCP连接的ID，同一连接上的所有流共用
+/// - `tls`: TLS握手的结果，h2c连接为`None`
+/// - `config`: Web服务器配置类型
+/// - `cache`: 共享的文件缓存指针
+pub async fn serve<S>(io: S, addr: SocketAddr, id: u128, tls: Option<TlsInfo>, config: Arc<Config>, cache: Arc<Mutex<FileCache>>)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let settings = config.http2();
+    let mut connection = match server::Builder::new()
+        .max_concurrent_streams(settings.max_concurrent_streams())
+        .initial_window_size(settings.initial_window_size())
+        .max_frame_size(settings.max_frame_size())
+        .max_header_list_size(settings.max_header_list_size())
+        .handshake::<_, Bytes>(io)
+        .await
+    {
+        Ok(c) => c,
+        Err(e) => {
+            debug!("[ID{}]HTTP/2握手失败：{}", id, e);
+            return;
+        }
+    };
+    debug!("[ID{}]HTTP/2连接已建立", id);
+    while let Some(accepted) = connection.accept().await {
+        match accepted {
+            Ok((request, send)) => {
+                let tls = tls.clone();
+                let config = Arc::clone(&config);
+                let cache = Arc::clone(&cache);
+                tokio::spawn(async move {
+                    handle_stream(request, send, addr, id, tls, config, cache).await;
+                });
+            },
+            Err(e) => {
+                debug!("[ID{}]HTTP/2连接出错：{}", id, e);
+                break;
+            },
+        }
+    }
+    debug!("[ID{}]HTTP/2连接已关闭", id);
+}
+
+/// 处理一个流：读取请求体，生成响应并写回
+async fn handle_stream(
+    request: http::Request<RecvStream>,
+    mut send: SendResponse<Bytes>,
+    addr: SocketAddr,
+    id: u128,
+    tls: Option<TlsInfo>,
+    config: Arc<Config>,
+    cache: Arc<Mutex<FileCache>>,
+) {
+    let start_time = Instant::now();
+    let (parts, mut body) = request.into_parts();
+    let mut request = match to_request(&parts, HttpVersion::V2, id) {
+        Some(r) => r,
+        None => {
+            let head = http::Response::builder().status(StatusCode::NOT_IMPLEMENTED).body(()).unwrap();
+            let _ = send.send_response(head, true);
+            return;
+        }
+    };
+    crate::attach_connection(&mut request, addr, tls.as_ref(), &config);
+    debug!("[ID{}]成功解析HTTP/2请求，流{}", id, send.stream_id().as_u32());
+    if crate::should_close(&request, id, &config) {
+        send.send_reset(Reason::REFUSED_STREAM);
+        return;
+    }
+
+    // 读取请求体，并及时归还流量控制窗口
+    let max_body_size = config.max_body_size();
+    let declared = request.header("content-length").and_then(|l| l.parse::<usize>().ok()).unwrap_or(0);
+    let mut data = BytesMut::new();
+    let mut too_large = declared > max_body_size;
+    while !too_large {
+        let chunk = match body.data().await {
+            Some(Ok(chunk)) => chunk,
+            Some(Err(e)) => {
+                debug!("[ID{}]读取HTTP/2请求体时遇到错误：{}", id, e);
+                return;
+            },
+            None => break,
+        };
+        let _ = body.flow_control().release_capacity(chunk.len());
+        too_large = data.len() + chunk.len() > max_body_size;
+        data.extend_from_slice(&chunk);
+    }
+    let response = if too_large {
+        warn!("[ID{}]请求体长度超过了上限{}，返回413响应", id, max_body_size);
+        Response::response_with_code(413, &request, id, &config)
+    } else {
+        if !data.is_empty() {
+            request.set_body(data.freeze());
+        }
+        crate::respond(&mut request, id, start_time, &config, &cache).await
+    };
+
+    let headonly = request.method() == HttpRequestMethod::Head;
+    if let Err(e) = send_response(&response, headonly, &mut send).await {
+        debug!("[ID{}]写回HTTP/2响应时遇到错误：{}", id, e);
+        return;
+    }
+    debug!("[ID{}]HTTP/2响应已写回", id);
+}
+
+/// 将HTTP/2或HTTP/3请求转换为与版本无关的`Request`。`:authority`伪首部被当作`Host`请求头
+pub fn to_request(parts: &http::request::Parts, version: HttpVersion, id: u128) -> Option<Request> {
+    let mut headers: Vec<(String, String)> = Vec::new();
+    for (name, value) in &parts.headers {
+        let value = String::from_utf8_lossy(value.as_bytes()).into_owned();
+        // HTTP/2允许将Cookie拆分为多个字段，合并后才能交给CGI程序
+        match headers.iter_mut().find(|(n, _)| n == "cookie" && name == "cookie") {
+            Some((_, cookie)) => {
+                cookie.push_str("; ");
+                cookie.push_str(&value);
+            },
+            None => headers.push((name.as_str().to_string(), value)),
+        }
+    }
+    if let Some(authority) = parts.uri.authority() {
+        if !headers.iter().any(|(n, _)| n == "host") {
+            headers.push(("host".to_string(), authority.to_string()));
+        }
+    }
+    let target = parts.uri.path_and_query().map(|p| p.as_str()).unwrap_or("/");
+    Request::from_parts(parts.method.as_str(), target, version, headers, id).ok()
+}
+
+/// 生成HTTP/2或HTTP/3的响应头。连接相关的响应头会被去除
+pub fn response_head(response: &Response) -> http::Response<()> {
+    let mut head = http::Response::new(());
+    *head.status_mut() = StatusCode::from_u16(response.status_code()).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+    for (name, value) in response.header_fields() {
+        let name = match HeaderName::from_bytes(name.as_bytes()) {
+            Ok(n) if !CONNECTION_HEADERS.contains(&n.as_str()) => n,
+            _ => continue,
+        };
+        if let Ok(value) = HeaderValue::from_str(&value) {
+            head.headers_mut().append(name, value);
+        }
+    }
+    head
+}
+
+/// 发送响应头和响应体。HEAD请求的响应不发送响应体
+async fn send_response(response: &Response, headonly: bool, send: &mut SendResponse<Bytes>) -> Result<(), h2::Error> {
+    let head = response_head(response);
+    let body_stream = response.take_body_stream().filter(|_| !headonly);
+    let content = response.content().filter(|c| !headonly && !c.is_empty());
+    let mut stream = send.send_response(head, body_stream.is_none() && content.is_none())?;
+    if let Some(content) = content {
+        send_data(&mut stream, content.clone(), body_stream.is_none()).await?;
+    }
+    if let Some(mut receiver) = body_stream {
+        loop {
+            // 客户端重置了流时，停止发送，响应体的发送端随之得知
+            let chunk = tokio::select! {
+                chunk = receiver.recv() => chunk,
+                reason = poll_fn(|cx| stream.poll_reset(cx)) => return Err(reason?.into()),
+            };
+            match chunk {
+                Some(Ok(chunk)) => send_data(&mut stream, chunk, false).await?,
+                Some(Err(e)) => {
+                    warn!("生成HTTP/2流式响应体时遇到错误：{}", e);
+                    stream.send_reset(Reason::INTERNAL_ERROR);
+                    return Ok(());
+                },
+                None => break,
+            }
+        }
+        stream.send_data(Bytes::new(), true)?;
+    }
+    Ok(())
+}
+
+/// 在流量控制窗口允许的范围内分块发送数据
+async fn send_data(stream: &mut SendStream<Bytes>, mut data: Bytes, end_of_stream: bool) -> Result<(), h2::Error> {
+    while !data.is_empty() {
+        stream.reserve_capacity(data.len());
+        let capacity = match poll_fn(|cx| stream.poll_capacity(cx)).await {
+            Some(capacity) => capacity?,
+            None => return Err(Reason::STREAM_CLOSED.into()),
+        };
+        if capacity == 0 {
+            continue;
+        }
+        let chunk = data.split_to(capacity.min(data.len()));
+        stream.send_data(chunk, end_of_stream && data.is_empty())?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{duplex, AsyncReadExt};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_prior_knowledge() {
+        let (client_io, mut server_io) = duplex(64 * 1024);
+        let server = tokio::spawn(async move {
+            // 与handle_connection一样，先把连接前言当作请求头读入
+            let mut prefix = vec![0; PREFACE.len()];
+            server_io.read_exact(&mut prefix).await.unwrap();
+            assert_eq!(prefix, PREFACE);
+            let config = Arc::new(Config::new());
+            let cache = Arc::new(Mutex::new(FileCache::from_capacity(1)));
+            serve(Rewind::new(prefix, server_io), "127.0.0.1:1".parse().unwrap(), 0, None, config, cache).await;
+        });
+
+        let (client, connection) = h2::client::handshake(client_io).await.unwrap();
+        tokio::spawn(connection);
+        let mut client = client.ready().await.unwrap();
+        let request = http::Request::get("http://localhost/no/such/file").body(()).unwrap();
+        let (response, _) = client.send_request(request, true).unwrap();
+        let response = response.await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert!(response.headers().get("content-length").is_some());
+        assert!(response.headers().get("connection").is_none());
+        let mut body = response.into_body();
+        let mut received = 0;
+        while let Some(chunk) = body.data().await {
+            received += chunk.unwrap().len();
+        }
+        assert!(received > 0);
+
+        // 客户端关闭连接后，serve返回
+        drop((client, body));
+        server.await.unwrap();
+    }
+}
@@ -0,0 +1,406 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use log::debug;
+
+use crate::{
+    config::HttpCacheConfig,
+    param::{HttpEncoding, HttpRequestMethod},
+    request::Request,
+    response::Response,
+};
+
+/// 可以被缓存的状态码，见RFC 9110 15.1节中默认可缓存的状态码
+const CACHEABLE_STATUS: [u16; 8] = [200, 203, 204, 300, 301, 308, 404, 410];
+
+/// 响应的新鲜度
+///
+/// - `max_age`: 响应保持新鲜的时长
+/// - `stale_while_revalidate`: 过期之后，在该时长内可以先返回过期的响应，同时在后台更新
+/// - `stale_if_error`: 过期之后，在该时长内如果重新生成响应时出错，可以返回过期的响应
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Freshness {
+    max_age: Duration,
+    stale_while_revalidate: Duration,
+    stale_if_error: Duration,
+}
+
+/// 缓存的响应
+///
+/// - `status`、`reason`、`content_type`、`content_encoding`、`headers`、`body`: 响应本身
+/// - `path`: 请求路径，用于按路径清除缓存
+/// - `vary`: `Vary`头列出的请求头及其在原始请求中的值
+/// - `stored`: 存入缓存的时刻
+/// - `last_used`: 最近一次被使用的时刻，用于淘汰
+/// - `freshness`: 新鲜度
+/// - `revalidating`: 是否正在后台更新
+#[derive(Debug, Clone)]
+pub struct CacheEntry {
+    pub status: u16,
+    pub reason: String,
+    pub content_type: Option<String>,
+    pub content_encoding: Option<HttpEncoding>,
+    pub headers: Vec<(String, String)>,
+    pub body: Bytes,
+    path: String,
+    vary: Vec<(String, Option<String>)>,
+    stored: Instant,
+    last_used: Instant,
+    freshness: Freshness,
+    revalidating: bool,
+}
+
+impl CacheEntry {
+    /// 缓存项的年龄，即存入缓存以来经过的秒数
+    pub fn age(&self) -> u64 {
+        self.stored.elapsed().as_secs()
+    }
+
+    /// 缓存项占用的内存大小（近似值）
+    fn size(&self) -> usize {
+        self.body.len()
+            + self.headers.iter().map(|(k, v)| k.len() + v.len()).sum::<usize>()
+            + self.path.len()
+    }
+
+    /// 缓存项是否与请求匹配，即`Vary`列出的请求头的值都相同
+    fn matches(&self, request: &Request) -> bool {
+        self.vary.iter().all(|(name, value)| request.header(name) == value.as_deref())
+    }
+}
+
+/// 查找缓存的结果
+///
+/// - `Hit`: 可以直接使用的响应，包括新鲜的响应，以及正在后台更新的过期响应
+/// - `Revalidate`: 过期但仍在`stale-while-revalidate`时间内的响应。调用者应当先返回它，然后在后台重新生成响应
+/// - `Miss`: 没有可以直接使用的响应。如果有仍在`stale-if-error`时间内的过期响应，则一并返回，以便在出错时使用
+#[derive(Debug)]
+pub enum Lookup {
+    Hit(CacheEntry),
+    Revalidate(CacheEntry),
+    Miss(Option<CacheEntry>),
+}
+
+/// ### 动态响应缓存 HttpCache
+///
+/// 按照HTTP缓存语义缓存反向代理、CGI和FastCGI生成的响应。与只缓存静态文件的`FileCache`不同，
+/// 本缓存遵循响应的`Cache-Control`、`Expires`和`Vary`头。
+///
+/// - `config`: 缓存配置
+/// - `state`: 缓存项，键由`key`模板生成，每个键可以对应多个`Vary`变体；以及已用的内存大小
+#[derive(Debug)]
+pub struct HttpCache {
+    config: HttpCacheConfig,
+    state: Mutex<(HashMap<String, Vec<CacheEntry>>, usize)>,
+}
+
+impl HttpCache {
+    pub fn new(config: &HttpCacheConfig) -> Self {
+        Self {
+            config: config.clone(),
+            state: Mutex::new((HashMap::new(), 0)),
+        }
+    }
+
+    /// 按照`key`模板生成请求的缓存键
+    ///
+    /// 模板中可以使用`{scheme}`、`{host}`、`{path}`、`{query}`，以及`{header:名称}`
+    pub fn key(&self, request: &Request) -> String {
+        let mut key = String::new();
+        let mut rest = self.config.key();
+        while let Some(start) = rest.find('{') {
+            key.push_str(&rest[..start]);
+            let end = match rest[start..].find('}') {
+                Some(e) => start + e,
+                None => break,
+            };
+            let name = &rest[start + 1..end];
+            match name {
+                "scheme" => key.push_str(request.scheme()),
+                "host" => key.push_str(request.header("host").unwrap_or_default()),
+                "path" => key.push_str(request.path()),
+                "query" => key.push_str(request.query()),
+                _ => match name.strip_prefix("header:") {
+                    Some(header) => key.push_str(request.header(header).unwrap_or_default()),
+                    None => key.push_str(&rest[start..=end]),
+                },
+            }
+            rest = &rest[end + 1..];
+        }
+        key.push_str(rest);
+        key
+    }
+
+    /// 查找请求对应的缓存项
+    ///
+    /// 请求带有`Cache-Control: no-cache`或`no-store`时总是返回`Miss`
+    pub fn lookup(&self, request: &Request) -> Lookup {
+        let no_cache = request.header("cache-control").is_some_and(|c| {
+            directives(c).any(|(name, _)| name == "no-cache" || name == "no-store")
+        });
+        if no_cache {
+            return Lookup::Miss(None);
+        }
+        let key = self.key(request);
+        let mut state = self.state.lock().unwrap();
+        let entry = match state.0.get_mut(&key).and_then(|v| v.iter_mut().find(|e| e.matches(request))) {
+            Some(e) => e,
+            None => return Lookup::Miss(None),
+        };
+        entry.last_used = Instant::now();
+        let age = entry.stored.elapsed();
+        let freshness = entry.freshness;
+        if age < freshness.max_age {
+            return Lookup::Hit(entry.clone());
+        }
+        let stale_for = age - freshness.max_age;
+        if stale_for < freshness.stale_while_revalidate {
+            // 同一时刻只需要一个请求在后台更新，其他请求直接使用过期的响应
+            if entry.revalidating {
+                return Lookup::Hit(entry.clone());
+            }
+            entry.revalidating = true;
+            return Lookup::Revalidate(entry.clone());
+        }
+        match stale_for < freshness.stale_if_error {
+            true => Lookup::Miss(Some(entry.clone())),
+            false => Lookup::Miss(None),
+        }
+    }
+
+    /// 尝试将响应存入缓存
+    ///
+    /// 只有GET请求的动态响应，并且响应明确给出了新鲜度时才会被缓存。流式响应体会在发送的同时被收集，
+    /// 完整发送之后才存入缓存；超过`max_entry_size`的响应体不会被缓存。
+    ///
+    /// ## 参数
+    /// - `request`: 来自浏览器的`request`
+    /// - `response`: 刚生成的响应
+    pub fn store(self: &Arc<Self>, request: &Request, response: &mut Response) {
+        if request.method() != HttpRequestMethod::Get || !response.is_dynamic() {
+            return;
+        }
+        // 带有认证信息的请求，其响应通常是私有的
+        if request.header("authorization").is_some() {
+            return;
+        }
+        let freshness = match freshness(response.status_code(), response.headers()) {
+            Some(f) => f,
+            None => return,
+        };
+        let mut vary_names: Vec<String> = response.headers().iter()
+            .filter(|(name, _)| name.eq_ignore_ascii_case("vary"))
+            .flat_map(|(_, value)| value.split(',').map(|v| v.trim().to_lowercase()).collect::<Vec<_>>())
+            .filter(|v| !v.is_empty())
+            .collect();
+        if vary_names.iter().any(|v| v == "*") {
+            return;
+        }
+        // 压缩编码是根据请求的Accept-Encoding确定的
+        if response.content_encoding().is_some() && !vary_names.iter().any(|v| v == "accept-encoding") {
+            vary_names.push("accept-encoding".to_string());
+        }
+        let now = Instant::now();
+        let entry = CacheEntry {
+            status: response.status_code(),
+            reason: response.information().to_string(),
+            content_type: response.content_type().map(|t| t.to_string()),
+            content_encoding: response.content_encoding(),
+            headers: response.headers().to_vec(),
+            body: Bytes::new(),
+            path: request.path().to_string(),
+            vary: vary_names.into_iter()
+                .map(|name| {
+                    let value = request.header(&name).map(|v| v.to_string());
+                    (name, value)
+                })
+                .collect(),
+            stored: now,
+            last_used: now,
+            freshness,
+            revalidating: false,
+        };
+        let key = self.key(request);
+        let cache = Arc::clone(self);
+        response.tee_body(self.config.max_entry_size(), move |body| {
+            let mut entry = entry;
+            entry.body = body;
+            cache.insert(key, entry);
+        });
+    }
+
+    /// 插入缓存项，替换同一变体的旧缓存项，并在超出内存预算时淘汰最久未使用的缓存项
+    fn insert(&self, key: String, entry: CacheEntry) {
+        let size = entry.size();
+        if size > self.config.max_size() {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        let (entries, used) = &mut *state;
+        let variants = entries.entry(key.clone()).or_default();
+        if let Some(pos) = variants.iter().position(|e| e.vary == entry.vary) {
+            *used -= variants.remove(pos).size();
+        }
+        variants.push(entry);
+        *used += size;
+        debug!("响应已存入缓存：{}，当前缓存大小{}字节", key, used);
+        while *used > self.config.max_size() {
+            let oldest = entries.iter()
+                .flat_map(|(k, v)| v.iter().enumerate().map(move |(i, e)| (k, i, e.last_used)))
+                .min_by_key(|(_, _, last_used)| *last_used)
+                .map(|(k, i, _)| (k.clone(), i));
+            let (k, i) = match oldest {
+                Some(o) => o,
+                None => break,
+            };
+            let variants = entries.get_mut(&k).unwrap();
+            *used -= variants.remove(i).size();
+            if variants.is_empty() {
+                entries.remove(&k);
+            }
+        }
+    }
+
+    /// 后台更新失败时，允许其他请求再次尝试更新
+    pub fn revalidation_failed(&self, request: &Request) {
+        let key = self.key(request);
+        let mut state = self.state.lock().unwrap();
+        if let Some(entry) = state.0.get_mut(&key).and_then(|v| v.iter_mut().find(|e| e.matches(request))) {
+            entry.revalidating = false;
+        }
+    }
+
+    /// 清除请求对应的全部缓存项（包括所有变体），返回清除的数量
+    pub fn purge(&self, request: &Request) -> usize {
+        let key = self.key(request);
+        let mut state = self.state.lock().unwrap();
+        let (entries, used) = &mut *state;
+        match entries.remove(&key) {
+            Some(variants) => {
+                *used -= variants.iter().map(|e| e.size()).sum::<usize>();
+                variants.len()
+            },
+            None => 0,
+        }
+    }
+
+    /// 清除请求路径以`prefix`开头的全部缓存项，返回清除的数量
+    pub fn purge_prefix(&self, prefix: &str) -> usize {
+        let mut state = self.state.lock().unwrap();
+        let (entries, used) = &mut *state;
+        let mut count = 0;
+        entries.retain(|_, variants| {
+            variants.retain(|e| {
+                let keep = !e.path.starts_with(prefix);
+                if !keep {
+                    *used -= e.size();
+                    count += 1;
+                }
+                keep
+            });
+            !variants.is_empty()
+        });
+        count
+    }
+
+    /// 生成缓存的状态描述，用于控制台的`status`命令
+    pub fn status(&self) -> String {
+        let state = self.state.lock().unwrap();
+        let count: usize = state.0.values().map(|v| v.len()).sum();
+        format!("动态响应缓存：{}项，{}/{}字节", count, state.1, self.config.max_size())
+    }
+}
+
+/// 解析`Cache-Control`头中的指令，返回指令名（小写）和值
+fn directives(value: &str) -> impl Iterator<Item = (String, Option<String>)> + '_ {
+    value.split(',')
+        .map(|d| d.trim())
+        .filter(|d| !d.is_empty())
+        .map(|d| match d.split_once('=') {
+            Some((name, value)) => (name.trim().to_lowercase(), Some(value.trim().trim_matches('"').to_string())),
+            None => (d.to_lowercase(), None),
+        })
+}
+
+/// 根据响应头确定响应的新鲜度，响应不可缓存时返回`None`
+///
+/// - `Cache-Control`中的`no-store`、`no-cache`、`private`使响应不可缓存
+/// - 新鲜时长依次取自`s-maxage`、`max-age`和`Expires`，都没有时不缓存
+/// - 带有`Set-Cookie`的响应不会被缓存
+pub fn freshness(status: u16, headers: &[(String, String)]) -> Option<Freshness> {
+    if !CACHEABLE_STATUS.contains(&status) {
+        return None;
+    }
+    let header = |name: &str| headers.iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case(name))
+        .map(|(_, v)| v.as_str());
+    if header("set-cookie").is_some() {
+        return None;
+    }
+    let mut max_age = None;
+    let mut s_maxage = None;
+    let mut stale_while_revalidate = 0;
+    let mut stale_if_error = 0;
+    let seconds = |v: &Option<String>| v.as_deref().and_then(|v| v.parse::<u64>().ok());
+    for (name, value) in directives(header("cache-control").unwrap_or_default()) {
+        match name.as_str() {
+            "no-store" | "no-cache" | "private" => return None,
+            "max-age" => max_age = seconds(&value),
+            "s-maxage" => s_maxage = seconds(&value),
+            "stale-while-revalidate" => stale_while_revalidate = seconds(&value).unwrap_or(0),
+            "stale-if-error" => stale_if_error = seconds(&value).unwrap_or(0),
+            _ => {},
+        }
+    }
+    let max_age = match s_maxage.or(max_age) {
+        Some(a) => a,
+        None => {
+            let expires = DateTime::parse_from_rfc2822(header("expires")?).ok()?;
+            let date = header("date")
+                .and_then(|d| DateTime::parse_from_rfc2822(d).ok())
+                .map(|d| d.with_timezone(&Utc))
+                .unwrap_or_else(Utc::now);
+            (expires.with_timezone(&Utc) - date).num_seconds().max(0) as u64
+        },
+    };
+    Some(Freshness {
+        max_age: Duration::from_secs(max_age),
+        stale_while_revalidate: Duration::from_secs(stale_while_revalidate),
+        stale_if_error: Duration::from_secs(stale_if_error),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::freshness;
+
+    #[test]
+    fn test_freshness() {
+        let headers = |pairs: &[(&str, &str)]| pairs.iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect::<Vec<_>>();
+
+        let f = freshness(200, &headers(&[("Cache-Control", "public, max-age=60, s-maxage=120, stale-while-revalidate=30")])).unwrap();
+        assert_eq!(f.max_age, Duration::from_secs(120));
+        assert_eq!(f.stale_while_revalidate, Duration::from_secs(30));
+        assert_eq!(f.stale_if_error, Duration::ZERO);
+
+        let f = freshness(200, &headers(&[
+            ("Date", "Sun, 06 Nov 1994 08:49:37 GMT"),
+            ("Expires", "Sun, 06 Nov 1994 08:50:37 GMT"),
+        ])).unwrap();
+        assert_eq!(f.max_age, Duration::from_secs(60));
+
+        assert!(freshness(200, &headers(&[("Cache-Control", "private, max-age=60")])).is_none());
+        assert!(freshness(200, &headers(&[("Cache-Control", "max-age=60"), ("Set-Cookie", "a=b")])).is_none());
+        assert!(freshness(500, &headers(&[("Cache-Control", "max-age=60")])).is_none());
+        assert!(freshness(200, &[]).is_none());
+    }
+}
@@ -0,0 +1,332 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use regex::{Regex, RegexBuilder};
+
+use crate::{
+    access::AccessList,
+    config::{prefix_matches, ClientAuthMode, LocationConfig, ProxyConfig},
+    exception::Exception,
+    htpasswd::Htpasswd,
+    param::{HttpRequestMethod, STATUS_CODES},
+    ratelimit::RateLimiter,
+    util::join_under,
+};
+
+/// location的匹配方式，与nginx相同
+///
+/// - `Exact`: `= /path`，请求路径与之完全相同
+/// - `Prefix`: `/path`或`^~ /path`，按路径的层级匹配前缀。`^~`表示该前缀是最长匹配时不再检查正则表达式
+/// - `Regex`: `~ pattern`（区分大小写）或`~* pattern`（不区分大小写）
+#[derive(Debug)]
+enum Matcher {
+    Exact(String),
+    Prefix { prefix: String, stop_regex: bool },
+    Regex(Regex),
+}
+
+impl Matcher {
+    fn parse(path: &str) -> Result<Self, String> {
+        let (modifier, rest) = match path.split_once(char::is_whitespace) {
+            Some((m @ ("=" | "^~" | "~" | "~*"), rest)) => (m, rest.trim()),
+            _ => ("", path.trim()),
+        };
+        match modifier {
+            "=" => Ok(Self::Exact(rest.to_string())),
+            "~" | "~*" => RegexBuilder::new(rest)
+                .case_insensitive(modifier == "~*")
+                .build()
+                .map(Self::Regex)
+                .map_err(|e| format!("无效的正则表达式{}：{}", rest, e)),
+            _ if !rest.starts_with('/') => Err(format!("路径{}不是以/开头的", rest)),
+            _ => Ok(Self::Prefix { prefix: rest.to_string(), stop_regex: modifier == "^~" }),
+        }
+    }
+}
+
+/// ### 路径规则 Location
+///
+/// 由`[[location]]`构建的运行时对象。请求在解析完成后匹配一次location，之后的各个处理阶段通过`Request::location`读取
+#[derive(Debug)]
+pub struct Location {
+    matcher: Matcher,
+    config: LocationConfig,
+    proxy: Option<ProxyConfig>,
+    limiter: Option<RateLimiter>,
+    access: Option<AccessList>,
+    htpasswd: Option<Htpasswd>,
+}
+
+impl Location {
+    /// 通过配置构建location，配置无效时返回错误说明
+    pub fn new(config: &LocationConfig) -> Result<Self, String> {
+        let matcher = Matcher::parse(config.path())?;
+        if matches!(matcher, Matcher::Regex(_)) && config.alias().is_some() {
+            return Err("正则表达式location不支持alias".to_string());
+        }
+        if config.root().is_some() && config.alias().is_some() {
+            return Err("root和alias不能同时设置".to_string());
+        }
+        if let Some(fallback) = config.try_files().last().and_then(|f| f.strip_prefix('=')) {
+            // 不在STATUS_CODES中的状态码无法生成响应，必须在载入配置时拒绝
+            if !fallback.parse::<u16>().is_ok_and(|code| STATUS_CODES.contains_key(&code)) {
+                return Err(format!("try_files的回退状态码{}无效", fallback));
+            }
+        }
+        if let Some(rate) = config.rate_limit().map(|r| r.rate()) {
+            if !rate.is_finite() || rate <= 0.0 {
+                return Err(format!("限流的rate必须是正数，当前为{}", rate));
+            }
+        }
+        let access = match config.access().is_empty() {
+            true => None,
+            false => Some(AccessList::parse(config.access())?),
+        };
+        let proxy = config.proxy().map(|upstream| ProxyConfig::new(config.path(), upstream, config.proxy_timeout()));
+        Ok(Self {
+            matcher,
+            config: config.clone(),
+            proxy,
+            limiter: config.rate_limit().map(RateLimiter::new),
+            access,
+            htpasswd: config.htpasswd().map(Htpasswd::new).transpose()?,
+        })
+    }
+
+    /// 获取配置中的路径，用于日志
+    pub fn path(&self) -> &str {
+        self.config.path()
+    }
+
+    /// 获取文件系统上的根目录和它对应的URL前缀
+    ///
+    /// - 设置了`root`时，请求路径直接拼接在`root`之后，URL前缀为空
+    /// - 设置了`alias`时，请求路径中location的前缀被替换为`alias`
+    pub fn document_root(&self) -> Option<(&str, &str)> {
+        if let Some(root) = self.config.root() {
+            return Some((root, ""));
+        }
+        let base = match &self.matcher {
+            Matcher::Exact(path) => path.as_str(),
+            Matcher::Prefix { prefix, .. } => prefix.as_str(),
+            Matcher::Regex(_) => return None,
+        };
+        self.config.alias().map(|alias| (alias, base))
+    }
+
+    /// 是否允许列出目录，没有设置时为`None`
+    pub fn autoindex(&self) -> Option<bool> {
+        self.config.autoindex()
+    }
+
+    /// 获取允许的请求方法，为空时允许所有方法
+    pub fn methods(&self) -> &[HttpRequestMethod] {
+        self.config.methods()
+    }
+
+    /// 获取添加到响应中的响应头
+    pub fn headers(&self) -> &HashMap<String, String> {
+        self.config.headers()
+    }
+
+    /// 获取客户端证书的要求，没有设置时为`None`
+    pub fn client_auth(&self) -> Option<ClientAuthMode> {
+        self.config.client_auth()
+    }
+
+    /// 获取反向代理的配置
+    pub fn proxy(&self) -> Option<&ProxyConfig> {
+        self.proxy.as_ref()
+    }
+
+    /// 是否经过动态响应缓存
+    pub fn cache(&self) -> bool {
+        self.config.cache()
+    }
+
+    /// 获取限流器
+    pub fn limiter(&self) -> Option<&RateLimiter> {
+        self.limiter.as_ref()
+    }
+
+    /// 获取访问控制规则，没有设置时为`None`
+    pub fn access(&self) -> Option<&AccessList> {
+        self.access.as_ref()
+    }
+
+    /// 获取Basic认证使用的htpasswd文件，没有启用Basic认证时为`None`
+    pub fn htpasswd(&self) -> Option<&Htpasswd> {
+        self.htpasswd.as_ref()
+    }
+
+    /// 获取Basic认证的领域
+    pub fn realm(&self) -> &str {
+        self.config.realm()
+    }
+
+    /// 是否设置了`try_files`
+    pub fn has_try_files(&self) -> bool {
+        !self.config.try_files().is_empty()
+    }
+
+    /// 按`try_files`的顺序选择文件
+    ///
+    /// ## 参数
+    /// - `uri`: 以`/`开头的请求路径，用于替换`$uri`。使用`alias`时已经去掉了location的前缀
+    /// - `root`: 文件系统上的根目录
+    ///
+    /// ## 返回
+    /// - 第一个存在的候选文件或目录。都不存在时返回回退文件，回退是状态码时返回`Exception::StatusCode`
+    pub fn try_files(&self, uri: &str, root: &Path) -> Result<PathBuf, Exception> {
+        let (fallback, candidates) = match self.config.try_files().split_last() {
+            Some(split) => split,
+            None => return Err(Exception::FileNotFound),
+        };
+        // `$uri`来自请求，替换后的候选同样不能离开根目录
+        let resolve = |candidate: &str| join_under(root, &candidate.replace("$uri", uri));
+        for candidate in candidates {
+            let path = match resolve(candidate) {
+                Some(p) => p,
+                None => continue,
+            };
+            let exists = match candidate.ends_with('/') {
+                true => path.is_dir(),
+                false => path.is_file(),
+            };
+            if exists {
+                return Ok(path);
+            }
+        }
+        if let Some(code) = fallback.strip_prefix('=') {
+            return match code.parse() {
+                Ok(404) | Err(_) => Err(Exception::FileNotFound),
+                Ok(code) => Err(Exception::StatusCode(code)),
+            };
+        }
+        match resolve(fallback) {
+            Some(path) if path.exists() => Ok(path),
+            _ => Err(Exception::FileNotFound),
+        }
+    }
+}
+
+/// 按照nginx的优先级为请求路径选择location
+///
+/// 1. 完全匹配的`=`location
+/// 2. 记下前缀最长的前缀location。如果它带有`^~`，直接使用它
+/// 3. 按照配置中的顺序检查正则表达式location，使用第一个匹配的
+/// 4. 使用第2步记下的前缀location
+///
+/// ## 参数
+/// - `locations`: 虚拟主机的location列表
+/// - `path`: 经过百分号解码的请求路径
+pub fn resolve<'a>(locations: &'a [Arc<Location>], path: &str) -> Option<&'a Arc<Location>> {
+    let mut longest: Option<(&Arc<Location>, usize, bool)> = None;
+    for location in locations {
+        match &location.matcher {
+            Matcher::Exact(exact) if exact == path => return Some(location),
+            Matcher::Prefix { prefix, stop_regex }
+                if prefix_matches(prefix, path) && longest.is_none_or(|(_, len, _)| prefix.len() > len) => {
+                longest = Some((location, prefix.len(), *stop_regex));
+            },
+            _ => {},
+        }
+    }
+    if let Some((location, _, true)) = longest {
+        return Some(location);
+    }
+    locations.iter()
+        .find(|l| matches!(&l.matcher, Matcher::Regex(re) if re.is_match(path)))
+        .or(longest.map(|(location, _, _)| location))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn locations(paths: &[&str]) -> Vec<Arc<Location>> {
+        paths.iter().map(|path| {
+            let config: LocationConfig = toml::from_str(&format!("path = '{}'", path)).unwrap();
+            Arc::new(Location::new(&config).unwrap())
+        }).collect()
+    }
+
+    #[test]
+    fn test_precedence() {
+        let locations = locations(&["/", "= /", "/images/", "^~ /static/", "~* \\.(png|jpg)$", "~ ^/images/.*\\.svg$"]);
+        let path_of = |path| resolve(&locations, path).map(|l| l.path());
+
+        assert_eq!(path_of("/"), Some("= /"));
+        assert_eq!(path_of("/index.html"), Some("/"));
+        assert_eq!(path_of("/images/a.txt"), Some("/images/"));
+        // 正则表达式优先于普通的前缀
+        assert_eq!(path_of("/images/a.PNG"), Some("~* \\.(png|jpg)$"));
+        // 多个正则表达式匹配时使用第一个
+        assert_eq!(path_of("/images/a.svg"), Some("~ ^/images/.*\\.svg$"));
+        // ^~前缀是最长匹配时不检查正则表达式
+        assert_eq!(path_of("/static/a.png"), Some("^~ /static/"));
+        assert!(Location::new(&toml::from_str("path = '~ (' ").unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_try_files() {
+        let root = std::env::temp_dir().join(format!("webserver-try-files-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("docs")).unwrap();
+        std::fs::write(root.join("about.html"), "about").unwrap();
+        std::fs::write(root.join("index.html"), "index").unwrap();
+        std::fs::write(root.join("docs/index.html"), "docs").unwrap();
+        let build = |toml: &str| Location::new(&toml::from_str(toml).unwrap()).unwrap();
+
+        let spa = build("path = '/'\ntry_files = ['$uri', '$uri.html', '$uri/index.html', '/index.html']");
+        assert_eq!(spa.try_files("/about", &root).unwrap(), root.join("about.html"));
+        assert_eq!(spa.try_files("/docs", &root).unwrap(), root.join("docs/index.html"));
+        assert_eq!(spa.try_files("/users/42", &root).unwrap(), root.join("index.html"));
+        let assets = build("path = '/assets/'\ntry_files = ['$uri', '=404']");
+        assert!(matches!(assets.try_files("/assets/x.js", &root), Err(Exception::FileNotFound)));
+        let forbidden = build("path = '/'\ntry_files = ['$uri/', '=403']");
+        assert_eq!(forbidden.try_files("/docs", &root).unwrap(), root.join("docs"));
+        assert!(matches!(forbidden.try_files("/about.html", &root), Err(Exception::StatusCode(403))));
+        for fallback in ["=444", "=499", "=999", "=abc"] {
+            let toml = format!("path = '/'\ntry_files = ['$uri', '{}']", fallback);
+            assert!(Location::new(&toml::from_str(&toml).unwrap()).is_err());
+        }
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_document_root() {
+        let build = |toml: &str| Location::new(&toml::from_str(toml).unwrap());
+
+        let alias = build("path = '/static/'\nalias = '/srv/static'").unwrap();
+        assert_eq!(alias.document_root(), Some(("/srv/static", "/static/")));
+        let root = build("path = '~ \\.php$'\nroot = '/srv/php'").unwrap();
+        assert_eq!(root.document_root(), Some(("/srv/php", "")));
+        assert_eq!(build("path = '/'").unwrap().document_root(), None);
+        assert!(build("path = '~ \\.php$'\nalias = '/srv/php'").is_err());
+    }
+
+    #[test]
+    fn test_invalid_rate_limit() {
+        let build = |rate: &str| Location::new(&toml::from_str(&format!("path = '/'\nrate_limit = {{ rate = {} }}", rate)).unwrap());
+
+        assert!(build("2.5").is_ok());
+        assert!(build("0.0").is_err());
+        assert!(build("-1.0").is_err());
+        assert!(build("inf").is_err());
+        assert!(build("nan").is_err());
+    }
+
+    #[test]
+    fn test_invalid_access() {
+        let build = |rule: &str| Location::new(&toml::from_str(&format!("path = '/admin/'\naccess = ['{}', 'deny all']", rule)).unwrap());
+
+        assert!(build("allow 10.0.0.0/8").unwrap().access().is_some());
+        assert!(build("allow 10.0.0.0/33").is_err());
+        assert!(build("allow 10.0.0.256").is_err());
+        assert!(build("permit 10.0.0.0/8").is_err());
+    }
+}
@@ -1,12 +1,29 @@
 #![allow(clippy::unused_io_amount)]
 
+mod access;
 mod exception;
 mod param;
 mod config;
 mod request;
 mod response;
 mod cache;
+mod archive;
+mod cgi;
+mod fastcgi;
+mod htpasswd;
+mod http_cache;
+mod http2;
+mod http3;
+mod location;
+mod proxy;
+mod ratelimit;
+mod rewrite;
+mod upstream;
+mod mime;
+mod sse;
+mod tls;
 mod util;
+mod websocket;
 
 use request::Request;
 use config::Config;
@@ -16,27 +33,36 @@ use cache::FileCache;
 use tokio::{
     net::{TcpListener, TcpStream},
     io::{
-        AsyncWriteExt,
         AsyncBufReadExt,
+        AsyncRead,
+        AsyncReadExt,
+        AsyncWrite,
+        AsyncWriteExt,
         BufReader
     },
     runtime::Builder,
+    time::timeout,
 };
+use tokio_rustls::TlsAcceptor;
+use bytes::Bytes;
 use log::{error, warn, info, debug};
-use log4rs;
-use regex::Regex;
 
 use std::{
-    net::{Ipv4Addr, SocketAddrV4},
+    io,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
     path::{Path, PathBuf},
-    time::Instant,
+    time::{Duration, Instant},
     sync::{Arc, Mutex},
-    process::Command,
 };
 
 use crate::{
-    param::HTML_INDEX,
+    param::{HttpRequestMethod, HttpVersion, KEEP_ALIVE_TIMEOUT, MAX_HEADER_SIZE, CRLF},
+    config::{ClientAuthMode, DenyAction, VhostConfig},
     exception::Exception,
+    http_cache::{HttpCache, Lookup},
+    rewrite::Outcome,
+    tls::TlsInfo,
+    util::join_under,
 };
 
 #[tokio::main]
@@ -47,8 +73,10 @@ async fn main() {
     // 加载配置文件
     let config = Config::from_toml("files/config.toml");
     info!("配置文件已载入");
-    let root = config.www_root().to_string();
-    info!("www root: {}", &root);
+    info!("www root: {}", config.www_root());
+    if config.vhost_count() > 0 {
+        info!("已配置{}个虚拟主机", config.vhost_count());
+    }
 
     // 设置工作线程数量
     let worker_threads = config.worker_threads();
@@ -59,34 +87,37 @@ async fn main() {
 
     // 初始化文件缓存
     let cache_size = config.cache_size();
+    let config = Arc::new(config);
     let cache = Arc::new(
         Mutex::new(FileCache::from_capacity(cache_size))
     );
 
-    // 检测PHP环境
-    let php_result = Command::new("php")
-        .arg("-v")
-        .output();
-    match php_result {
-        Ok(o) => {
-            if o.status.success() {
-                let output = String::from_utf8_lossy(&o.stdout);
-                // 使用正则表达式捕获版本号
-                let re = Regex::new(r"PHP (\d+\.\d+\.\d+-\dubuntu\d+\.\d+)").unwrap();
-                if let Some(capture) = re.captures(&output) {
-                    if let Some(version) = capture.get(1) {
-                        info!("找到PHP解释器，版本：{}", version.as_str());
-                    }
-                }
-            } else {
-                // 执行php -v的status应该总是success的
-                panic!("在查找PHP解释器时遇到未知错误");
+    // 检测各扩展名的解释器
+    let mut interpreters: Vec<_> = config.interpreters().iter().collect();
+    interpreters.sort_by_key(|(extension, _)| extension.as_str());
+    for (extension, interpreter) in interpreters {
+        let program = match interpreter.program() {
+            Some(p) => p,
+            None => {
+                info!(".{}文件的解释器已被禁用，这类文件将无法访问", extension);
+                continue;
+            }
+        };
+        match cgi::find_program(program) {
+            Some(path) => match cgi::probe_version(&path, interpreter.version_args()) {
+                Some(version) => info!("找到.{}文件的解释器{}，版本：{}", extension, path.display(), version),
+                None => info!("找到.{}文件的解释器{}，但无法确定其版本", extension, path.display()),
+            },
+            None => {
+                warn!("无法找到.{}文件的解释器{}。服务器将继续运行，但将无法处理.{}文件的请求。", extension, program, extension);
             }
         }
-        Err(_) => {
-            warn!("无法找到PHP解释器。服务器将继续运行，但将无法处理PHP请求。");
-        }
-    };
+    }
+
+    info!("CGI程序最多同时运行{}个，最长运行{}秒",
+        config.cgi().max_processes(),
+        config.cgi().timeout().as_secs(),
+    );
 
     // 监听端口
     let port: u16 = config.port();
@@ -110,6 +141,59 @@ async fn main() {
     };
     info!("端口{}绑定完成", port);
 
+    // 配置了证书时，额外监听HTTPS端口
+    let mut quic = None;
+    let tls = match config.tls() {
+        Some(tls_config) => {
+            let (acceptor, store) = match tls::acceptor(tls_config, config.http2().enabled()) {
+                Ok(a) => a,
+                Err(e) => {
+                    error!("无法启用HTTPS：{}", e);
+                    panic!("无法启用HTTPS：{}", e);
+                }
+            };
+            let tls_socket = SocketAddrV4::new(address, tls_config.port());
+            let tls_listener = match TcpListener::bind(tls_socket).await {
+                Ok(listener) => listener,
+                Err(e) => {
+                    error!("无法绑定HTTPS端口：{}，错误：{}", tls_config.port(), e);
+                    panic!("无法绑定HTTPS端口：{}，错误：{}", tls_config.port(), e);
+                }
+            };
+            info!("HTTPS端口{}绑定完成，共{}个证书", tls_config.port(), tls_config.certificates().len());
+            if tls_config.redirect_http() {
+                info!("端口{}上的HTTP请求将被重定向到HTTPS", port);
+            }
+            // HTTP/3在同一端口号上监听UDP，与HTTPS共用证书
+            if let Some(http3_config) = config.http3() {
+                let endpoint = tls::quic_config(tls_config, &store)
+                    .and_then(|c| http3::endpoint(c, http3_config, tls_socket.into()).map_err(|e| e.to_string()));
+                match endpoint {
+                    Ok(endpoint) => {
+                        info!("HTTP/3（QUIC）UDP端口{}绑定完成", tls_config.port());
+                        quic = Some(endpoint);
+                    },
+                    Err(e) => {
+                        error!("无法启用HTTP/3：{}", e);
+                        panic!("无法启用HTTP/3：{}", e);
+                    }
+                }
+            }
+            // 收到SIGHUP时重新加载证书
+            #[cfg(unix)]
+            tokio::spawn(tls::reload_on_sighup(store));
+            #[cfg(not(unix))]
+            drop(store);
+            Some((tls_listener, acceptor))
+        },
+        None => None,
+    };
+
+    // 启动上游组的主动健康检查
+    for group in config.upstream_groups() {
+        tokio::spawn(Arc::clone(group).health_check());
+    }
+
     // 停机命令标志
     let shutdown_flag = Arc::new(Mutex::new(false));
     // 活跃连接计数
@@ -119,6 +203,7 @@ async fn main() {
     runtime.spawn({
         let shutdown_flag = Arc::clone(&shutdown_flag);
         let active_connection = Arc::clone(&active_connection);
+        let config = Arc::clone(&config);
         async move {
             let stdin = tokio::io::stdin();
             let mut reader = BufReader::new(stdin);
@@ -126,7 +211,7 @@ async fn main() {
             loop {
                 input.clear();
                 // 在这里处理命令，可以调用服务器的相关函数或执行其他操作
-                if let Ok(_) = reader.read_line(&mut input).await {
+                if reader.read_line(&mut input).await.is_ok() {
                     let cmd = input.trim();
                     match cmd {
                         "stop" => {
@@ -139,14 +224,46 @@ async fn main() {
                             println!("== Webserver Help ==");
                             println!("输入stop并再发出一次连接请求以停机");
                             println!("输入status以查看当前服务器状态");
+                            println!("输入purge [路径前缀]以清除动态响应缓存");
+                            println!("输入publish <频道> <数据>以向SSE频道发布事件");
                             println!("====================");
                         },
                         "status" => {
                             let active_count = *active_connection.lock().unwrap();
                             println!("== Webserver 状态 ===");
                             println!("当前连接数: {}", active_count);
+                            for group in config.upstream_groups() {
+                                for line in group.status() {
+                                    println!("{}", line);
+                                }
+                            }
+                            if let Some(http_cache) = config.http_cache() {
+                                println!("{}", http_cache.status());
+                            }
+                            for line in config.event_hub().status() {
+                                println!("{}", line);
+                            }
                             println!("====================");
                         },
+                        _ if cmd == "purge" || cmd.starts_with("purge ") => {
+                            let prefix = cmd.trim_start_matches("purge").trim();
+                            match config.http_cache() {
+                                Some(http_cache) => {
+                                    let count = http_cache.purge_prefix(prefix);
+                                    println!("清除了{}个缓存项", count);
+                                },
+                                None => println!("未启用动态响应缓存"),
+                            }
+                        },
+                        _ if cmd.starts_with("publish ") => {
+                            match cmd.trim_start_matches("publish ").trim().split_once(' ') {
+                                Some((channel, data)) => {
+                                    let event_id = config.event_hub().publish(channel, None, data.trim());
+                                    println!("已向频道{}发布事件{}", channel, event_id);
+                                },
+                                None => println!("用法：publish <频道> <数据>"),
+                            }
+                        },
                         _ => {
                             println!("无效的命令：{}", cmd);
                         }
@@ -165,11 +282,33 @@ async fn main() {
         if *shutdown_flag.lock().unwrap() {
             break;
         }
-        let (mut stream, addr) = listener.accept().await.unwrap();
+        let (mut stream, addr, acceptor) = tokio::select! {
+            accepted = listener.accept() => {
+                let (stream, addr) = accepted.unwrap();
+                (stream, addr, None)
+            },
+            accepted = accept_tls(&tls) => {
+                let (stream, addr) = accepted.unwrap();
+                (stream, addr, tls.as_ref().map(|(_, acceptor)| acceptor.clone()))
+            },
+            Some(incoming) = accept_quic(&quic) => {
+                debug!("新的QUIC连接：{}", incoming.remote_address());
+                let active_connection_arc = Arc::clone(&active_connection);
+                let config_arc = Arc::clone(&config);
+                let cache_arc = Arc::clone(&cache);
+                tokio::spawn(async move {
+                    *active_connection_arc.lock().unwrap() += 1;
+                    http3::serve(incoming, id, config_arc, cache_arc).await;
+                    *active_connection_arc.lock().unwrap() -= 1;
+                });
+                id += 1;
+                continue;
+            },
+        };
         debug!("新的连接：{}", addr);
 
         let active_connection_arc = Arc::clone(&active_connection);
-        let root_clone = root.clone();
+        let config_arc = Arc::clone(&config);
         let cache_arc = Arc::clone(&cache);
         debug!("[ID{}]TCP连接已建立", id);
         tokio::spawn(async move {
@@ -177,7 +316,23 @@ async fn main() {
                 let mut lock = active_connection_arc.lock().unwrap();
                 *lock += 1;
             }
-            handle_connection(&mut stream, id, &root_clone, cache_arc).await;
+            match acceptor {
+                Some(acceptor) => match timeout(tls::HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                    Ok(Ok(mut tls_stream)) => {
+                        let info = TlsInfo::from_connection(tls_stream.get_ref().1);
+                        debug!("[ID{}]TLS握手完成，客户端证书：{:?}", id, info.client_cert.as_ref().map(|c| &c.subject));
+                        match info.http2 {
+                            true => http2::serve(&mut tls_stream, addr, id, Some(info), config_arc, cache_arc).await,
+                            false => handle_connection(&mut tls_stream, addr, id, Some(info), config_arc, cache_arc).await,
+                        }
+                        // 发送close_notify，告知客户端响应已经完整
+                        let _ = tls_stream.shutdown().await;
+                    },
+                    Ok(Err(e)) => debug!("[ID{}]TLS握手失败：{}", id, e),
+                    Err(_) => debug!("[ID{}]TLS握手超时", id),
+                },
+                None => handle_connection(&mut stream, addr, id, None, config_arc, cache_arc).await,
+            }
             {
                 let mut lock = active_connection_arc.lock().unwrap();
                 *lock -= 1;
@@ -187,108 +342,556 @@ async fn main() {
     }
 }
 
+/// 在HTTPS监听器上接受连接。没有启用HTTPS时永远等待
+async fn accept_tls(tls: &Option<(TcpListener, TlsAcceptor)>) -> io::Result<(TcpStream, SocketAddr)> {
+    match tls {
+        Some((listener, _)) => listener.accept().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// 在QUIC监听器上接受连接。没有启用HTTP/3时永远等待
+async fn accept_quic(quic: &Option<quinn::Endpoint>) -> Option<quinn::Incoming> {
+    match quic {
+        Some(endpoint) => endpoint.accept().await,
+        None => std::future::pending().await,
+    }
+}
+
 /// 处理TCP连接
 /// 
 /// 参数：
-/// - `stream`: 建立好的`TcpStream`
-/// - `config`: Web服务器配置类型，在当前子线程建立时使用`Arc<T>`共享
+/// - `stream`: 建立好的连接，可以是`TcpStream`，也可以是TLS握手完成后的连接
+/// - `addr`: 客户端的地址
 /// - `id`: 当前TCP连接的ID
-async fn handle_connection(stream: &mut TcpStream, id: u128, root: &str, cache: Arc<Mutex<FileCache>>) {
-    let mut buffer = vec![0; 1024];
+/// - `tls`: TLS握手的结果，普通HTTP连接为`None`
+/// - `config`: Web服务器配置类型，在当前子线程建立时使用`Arc<T>`共享
+/// - `cache`: 共享的文件缓存指针
+async fn handle_connection<S>(stream: &mut S, addr: SocketAddr, id: u128, tls: Option<TlsInfo>, config: Arc<Config>, cache: Arc<Mutex<FileCache>>)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    let mut buffer = Vec::with_capacity(1024);
+    let mut chunk = vec![0; 1024];
+    // HTTP/1.0的keep-alive连接上可以有多个请求，等待后续请求时有超时限制
+    let mut idle_timeout = None;
+    loop {
+        // 读取请求头，直到遇到空行。缓冲区中可能还包含一部分请求体
+        let head_end = loop {
+            if let Some(pos) = buffer.windows(4).position(|w| w == b"\r\n\r\n") {
+                break pos + 4;
+            }
+            if buffer.len() > MAX_HEADER_SIZE {
+                warn!("[ID{}]请求头超过了{}字节，关闭连接", id, MAX_HEADER_SIZE);
+                return;
+            }
+            let read = match idle_timeout {
+                Some(t) => match timeout(t, stream.read(&mut chunk)).await {
+                    Ok(r) => r,
+                    Err(_) => {
+                        debug!("[ID{}]keep-alive连接空闲超时，关闭连接", id);
+                        return;
+                    },
+                },
+                None => stream.read(&mut chunk).await,
+            };
+            match read {
+                Ok(0) => return,
+                Ok(n) => buffer.extend_from_slice(&chunk[..n]),
+                Err(e) => {
+                    error!("[ID{}]读取TCPStream时遇到错误: {}", id, e);
+                    return;
+                },
+            }
+        };
+        debug!("[ID{}]HTTP请求头接收完毕", id);
 
-    // 等待tcpstream变得可读
-    stream.readable().await.unwrap();
+        // 启动timer
+        let start_time = Instant::now();
 
-    match stream.try_read(&mut buffer) {
-        Ok(0) => return,
-        Err(e) => {
-            error!("[ID{}]读取TCPStream时遇到错误: {}", id, e);
-            panic!();
-        },
-        _ => {},
-    }
-    debug!("[ID{}]HTTP请求接收完毕", id);
+        // 以HTTP/2连接前言开头的是h2c（prior knowledge）连接，已经读到的字节交给HTTP/2重新读取
+        if idle_timeout.is_none() && config.http2().enabled() && buffer.starts_with(http2::PREFACE) {
+            debug!("[ID{}]收到HTTP/2连接前言", id);
+            http2::serve(http2::Rewind::new(buffer, stream), addr, id, tls, config, cache).await;
+            return;
+        }
 
-    // 启动timer
-    let start_time = Instant::now();
+        let mut request = match Request::try_from(&buffer[..head_end], id) {
+            Ok(r) => r,
+            Err(e) => {
+                warn!("[ID{}]无法解析HTTP请求：{}", id, e);
+                let _ = Response::from_parse_error(e, id).write_to(stream).await;
+                return;
+            },
+        };
+        attach_connection(&mut request, addr, tls.as_ref(), &config);
+        debug!("[ID{}]成功解析HTTP请求", id);
+        if should_close(&request, id, &config) {
+            return;
+        }
+        let version = *request.version();
 
-    let request = Request::try_from(&buffer, id).unwrap();
-    debug!("[ID{}]成功解析HTTP请求", id);
+        // 根据Content-Length读取请求体。请求体之后多读到的字节属于下一个请求
+        let mut rest = buffer.split_off(head_end);
+        let content_length = request.header("content-length")
+            .and_then(|l| l.parse::<usize>().ok())
+            .unwrap_or(0);
+        if content_length > config.max_body_size() {
+            warn!("[ID{}]请求体长度{}超过了上限{}，返回413响应", id, content_length, config.max_body_size());
+            let mut response = Response::response_with_code(413, &request, id, &config);
+            let _ = response.set_http_version(version).write_to(stream).await;
+            return;
+        }
+        if content_length > 0 {
+            // 客户端在发送请求体之前等待服务器的确认。HTTP/1.0客户端不理解100响应
+            let expect_continue = request.header("expect").is_some_and(|e| e.eq_ignore_ascii_case("100-continue"));
+            if expect_continue && version == HttpVersion::V1_1 {
+                let continue_line = ["HTTP/1.1 100 Continue", CRLF, CRLF].concat();
+                if stream.write_all(continue_line.as_bytes()).await.is_err() {
+                    return;
+                }
+            }
+            if rest.len() < content_length {
+                let received = rest.len();
+                rest.resize(content_length, 0);
+                if let Err(e) = stream.read_exact(&mut rest[received..]).await {
+                    error!("[ID{}]读取请求体时遇到错误: {}", id, e);
+                    return;
+                }
+            }
+            let next = rest.split_off(content_length);
+            request.set_body(Bytes::from(std::mem::replace(&mut rest, next)));
+            debug!("[ID{}]请求体接收完毕，共{}字节", id, content_length);
+        }
 
-    let result = route(&request.path(), id, root).await;
-    debug!("[ID{}]HTTP路由解析完毕", id);
+        let mut response = respond(&mut request, id, start_time, &config, &cache).await;
 
-    // 如果path不存在，就返回404。使用Response::response_404
-    let response = match result {
-        Ok(path) => {
-            let path_str = match path.to_str() {
-                Some(s) => s,
-                None => {
-                    let path_str = path.to_str().unwrap();
-                    error!("[ID{}]无法将路径{}转换为str", id, path_str);
-                    return;
-                },
-            };
-            Response::from(path_str, &request, id, &cache)
+        // HTTP/1.0默认在响应之后关闭连接，客户端通过`Connection: keep-alive`要求保持连接。
+        // 流式响应体以关闭连接表示结束，因此不能保持连接
+        let keep_alive = version == HttpVersion::V1_0
+            && !response.is_streaming()
+            && request.header("connection").is_some_and(|c| c.split(',').any(|t| t.trim().eq_ignore_ascii_case("keep-alive")));
+        response.set_http_version(version);
+        if keep_alive {
+            response.add_header("Connection", "keep-alive")
+                .add_header("Keep-Alive", &format!("timeout={}", KEEP_ALIVE_TIMEOUT));
+        }
+
+        if let Err(e) = response.write_to(stream).await {
+            warn!("[ID{}]写回HTTP响应时遇到错误：{}", id, e);
+            return;
+        }
+        debug!("[ID{}]HTTP响应已写回", id);
+
+        // 握手完成，连接交给WebSocket处理器或到上游的隧道。请求头之后多读到的字节属于WebSocket数据
+        if let Some(upgrade) = response.take_upgrade() {
+            info!("[ID{}]连接已升级为WebSocket", id);
+            websocket::serve(upgrade, stream, rest, &request, config.websocket().max_message_size(), id).await;
+            return;
+        }
+
+        if !keep_alive {
+            return;
+        }
+        buffer = rest;
+        idle_timeout = Some(Duration::from_secs(KEEP_ALIVE_TIMEOUT));
+    }
+}
+
+/// 为解析好的请求设置连接相关的信息：客户端地址、是否经过TLS、匹配的location，以及客户端证书
+/// 
+/// 先按顺序应用URL重写规则，再为重写后的路径选择一次location，之后的各个处理阶段都使用同一个location。
+/// 双向TLS：只有匹配`client_auth`的路径才使用客户端证书
+fn attach_connection(request: &mut Request, addr: SocketAddr, tls: Option<&TlsInfo>, config: &Config) {
+    let original = request.target().to_string();
+    let outcome = rewrite::apply(config.rewrite_rules(), request);
+    if request.target() != original {
+        debug!("请求目标{}被重写为{}", original, request.target());
+    }
+    request.set_rewrite(outcome);
+    let location = config.location_for(request);
+    request.set_remote_addr(addr)
+        .set_secure(tls.is_some())
+        .set_location(location);
+    if config.client_auth_mode_for(request).is_some() {
+        request.set_client_cert(tls.and_then(|t| t.client_cert.clone()));
+    }
+}
+
+/// 检查是否应当不发送响应而直接关闭连接，即客户端地址被拒绝访问，并且`deny_action`为`close`
+pub fn should_close(request: &Request, id: u128, config: &Config) -> bool {
+    let close = config.deny_action() == DenyAction::Close && !config.access_allowed(request);
+    if close {
+        warn!("[ID{}]拒绝来自{:?}的请求，关闭连接", id, config.client_ip(request));
+    }
+    close
+}
+
+/// 为一个完整的请求（包括请求体）选择生成响应的方式，并记录访问日志。该函数与HTTP版本无关
+/// 
+/// 依次检查：客户端地址的访问控制、HTTPS重定向、重写规则的重定向、客户端证书、Basic认证、location允许的请求方法和限流、PURGE请求、动态响应缓存，最后通过`generate_response`生成响应。
+/// 前面的检查都通过之后才验证Basic认证，被拒绝的客户端不会触发bcrypt的计算。通过认证的用户名记录在`request`中
+/// 
+/// ## 参数：
+/// - `request`: 来自浏览器的`request`
+/// - `id`: 当前TCP连接的ID
+/// - `start_time`: 开始处理请求的时刻，用于记录服务端用时
+/// - `config`：Web服务器配置类型
+/// - `cache`: 共享的文件缓存指针
+async fn respond(request: &mut Request, id: u128, start_time: Instant, config: &Arc<Config>, cache: &Arc<Mutex<FileCache>>) -> Response {
+    // RFC 9112 3.2: HTTP/1.1请求必须带有Host头
+    let host_missing = *request.version() == HttpVersion::V1_1 && request.header("host").is_none();
+    let allowed = config.access_allowed(request);
+    let redirect = !request.is_secure() && config.tls().is_some_and(|t| t.redirect_http());
+    let client_auth = config.client_auth_mode_for(request);
+    let cert_missing = client_auth == Some(ClientAuthMode::Require) && request.client_cert().is_none();
+    if !host_missing && allowed && !redirect && request.rewrite().is_none() && !cert_missing {
+        let user = authenticate(request).await;
+        request.set_remote_user(user);
+    }
+    let request = &*request;
+    let method = request.method();
+    let upgrade = websocket::is_upgrade(request);
+    let vhost = config.vhost_for(request.header("host"));
+    let location = request.location();
+    let methods = location.map(|l| l.methods()).unwrap_or_default();
+    let throttled = match (location.and_then(|l| l.limiter()), config.client_ip(request)) {
+        (Some(limiter), Some(ip)) => limiter.acquire(ip).err(),
+        _ => None,
+    };
+    // location关闭了缓存时，请求直接生成响应
+    let http_cache = config.http_cache().filter(|_| location.is_none_or(|l| l.cache()));
+    let mut response = match http_cache {
+        _ if host_missing => {
+            warn!("[ID{}]HTTP/1.1请求缺少Host头，返回400", id);
+            Response::response_with_code(400, request, id, config)
         },
-        Err(Exception::FileNotFound) => {
-            warn!("[ID{}]请求的路径：{} 不存在，返回404响应", id, &request.path());
-            Response::response_404(&request, id)
+        _ if !allowed => {
+            warn!("[ID{}]拒绝来自{:?}的请求，返回403", id, config.client_ip(request));
+            Response::response_with_code(403, request, id, config)
         },
-        Err(e) => {
-            panic!("非法的错误类型：{}", e);
-        }
+        _ if redirect => Response::https_redirect(request, id, config),
+        _ if let Some(outcome) = request.rewrite() => rewrite_response(outcome, request, id, config),
+        _ if cert_missing => {
+            warn!("[ID{}]路径{}要求客户端证书，但客户端没有提供，返回403", id, request.path());
+            Response::response_with_code(403, request, id, config)
+        },
+        _ if location.is_some_and(|l| l.htpasswd().is_some()) && request.remote_user().is_none() => {
+            let realm = location.map(|l| l.realm()).unwrap_or_default();
+            if request.header("authorization").is_some() {
+                warn!("[ID{}]来自{:?}的Basic认证失败，返回401", id, config.client_ip(request));
+            }
+            let mut response = Response::response_with_code(401, request, id, config);
+            response.add_header("WWW-Authenticate", &format!("Basic realm=\"{}\", charset=\"UTF-8\"", realm.replace('"', "")));
+            response
+        },
+        _ if !methods.is_empty() && !methods.contains(&method) => {
+            warn!("[ID{}]location不允许{}方法，返回405", id, method);
+            let allow: Vec<_> = methods.iter().map(|m| m.to_string()).collect();
+            Response::response_with_code(405, request, id, config)
+                .add_header("Allow", &allow.join(", "))
+                .to_owned()
+        },
+        _ if throttled.is_some() => {
+            let wait = throttled.unwrap_or_default();
+            warn!("[ID{}]来自{:?}的请求超过了location的速率限制，返回429", id, config.client_ip(request));
+            Response::response_with_code(429, request, id, config)
+                .add_header("Retry-After", &wait.as_secs_f64().ceil().to_string())
+                .to_owned()
+        },
+        _ if method == HttpRequestMethod::Purge => purge_response(request, id, config),
+        // WebSocket升级请求不经过缓存
+        Some(http_cache) if !upgrade && (method == HttpRequestMethod::Get || method == HttpRequestMethod::Head) => {
+            cached_response(http_cache, request, id, config, cache).await
+        },
+        _ => generate_response(request, id, config, cache).await,
     };
+    // location的响应头覆盖虚拟主机的同名响应头
+    let location_headers = location.map(|l| l.headers());
+    for (name, value) in vhost.headers() {
+        if !location_headers.is_some_and(|h| h.keys().any(|n| n.eq_ignore_ascii_case(name))) {
+            response.add_header(name, value);
+        }
+    }
+    for (name, value) in location_headers.into_iter().flatten() {
+        response.add_header(name, value);
+    }
+    // 通过HTTPS到达的请求，告知浏览器可以改用HTTP/3
+    if let (Some(tls), Some(http3)) = (config.tls(), config.http3()) {
+        if request.is_secure() && *request.version() != HttpVersion::V3 {
+            response.add_header("Alt-Svc", &format!("h3=\":{}\"; ma={}", tls.port(), http3.alt_svc_max_age()));
+        }
+    }
 
     debug!("[ID{}]HTTP响应构建完成，服务端用时{}ms。",
         id,
         start_time.elapsed().as_millis()
     );
 
-    info!("[ID{}] {}, {}, {}, {}, {}, {}, ", id,
+    info!("[ID{}] {}, {}, {}, {}, {}, {}, {}, {}", id,
         request.version(),
         request.path(),
         request.method(),
         response.status_code(),
         response.information(),
         request.user_agent(),
+        // 客户端证书的主体，没有时为空
+        request.client_cert().map(|c| c.subject.as_str()).unwrap_or_default(),
+        // 通过Basic认证的用户名，没有时为空
+        request.remote_user().unwrap_or_default(),
     );
+    response
+}
 
-    stream.write(&response.as_bytes()).await.unwrap();
-    stream.flush().await.unwrap();
-    debug!("[ID{}]HTTP响应已写回", id);
+/// location启用了Basic认证时，验证`Authorization`请求头中的用户名和密码，返回通过认证的用户名
+async fn authenticate(request: &Request) -> Option<String> {
+    let htpasswd = request.location()?.htpasswd()?;
+    let (user, password) = htpasswd::parse_authorization(request.header("authorization")?)?;
+    htpasswd.verify(&user, &password).await.then_some(user)
 }
 
-/// 路由解析函数
+/// 生成响应：匹配WebSocket处理器的升级请求完成握手，SSE路径的请求订阅或发布事件，匹配反向代理的请求转发给上游服务器，其他请求通过本地文件系统处理
+/// 
+/// ## 参数：
+/// - `request`: 来自浏览器的`request`
+/// - `id`: 当前TCP连接的ID
+/// - `config`：Web服务器配置类型
+/// - `cache`: 共享的文件缓存指针
+async fn generate_response(request: &Request, id: u128, config: &Config, cache: &Arc<Mutex<FileCache>>) -> Response {
+    if websocket::is_upgrade(request) {
+        if let Some(handler) = config.websocket_handler(request.path()) {
+            debug!("[ID{}]请求路径匹配WebSocket处理器{:?}", id, handler);
+            return Response::from_websocket(handler, request, id, config);
+        }
+    }
+    if let Some(channel) = config.sse_channel(request.path()) {
+        debug!("[ID{}]请求路径匹配SSE频道{}", id, channel);
+        return Response::from_sse(channel, request, id, config);
+    }
+    match request.location().and_then(|l| l.proxy()).or_else(|| config.proxy_for(request.path())) {
+        Some(location) => {
+            debug!("[ID{}]请求路径匹配反向代理{}，转发给{}", id, location.prefix(), location.upstream());
+            Response::from_proxy(location, request, id, config).await
+        },
+        None => local_response(request, id, config, cache).await,
+    }
+}
+
+/// 通过动态响应缓存生成响应
+/// 
+/// - 缓存命中时直接返回缓存的响应
+/// - 缓存过期但仍在`stale-while-revalidate`时间内时，返回过期的响应，并在后台重新生成响应
+/// - 否则重新生成响应并尝试存入缓存。生成的响应是5xx错误，并且有仍在`stale-if-error`时间内的过期响应时，返回过期的响应
+/// 
+/// ## 参数：
+/// - `http_cache`: 动态响应缓存
+/// - `request`: 来自浏览器的`request`，只能是GET或HEAD
+/// - `id`: 当前TCP连接的ID
+/// - `config`：Web服务器配置类型
+/// - `cache`: 共享的文件缓存指针
+async fn cached_response(http_cache: &Arc<HttpCache>, request: &Request, id: u128, config: &Arc<Config>, cache: &Arc<Mutex<FileCache>>) -> Response {
+    let headonly = request.method() == HttpRequestMethod::Head;
+    let stale = match http_cache.lookup(request) {
+        Lookup::Hit(entry) => {
+            debug!("[ID{}]命中动态响应缓存", id);
+            return Response::from_cached(&entry, headonly, "HIT");
+        },
+        Lookup::Revalidate(entry) => {
+            debug!("[ID{}]缓存的响应已过期，先返回过期的响应，并在后台更新", id);
+            let http_cache = Arc::clone(http_cache);
+            let request = request.clone();
+            let config = Arc::clone(config);
+            let cache = Arc::clone(cache);
+            tokio::spawn(async move {
+                let mut response = generate_response(&request, id, &config, &cache).await;
+                if response.status_code() < 500 {
+                    http_cache.store(&request, &mut response);
+                    // 没有浏览器接收这个响应，读完响应体以便将其存入缓存
+                    let _ = response.write_to(&mut tokio::io::sink()).await;
+                }
+                // 响应没有被存入缓存时，旧的缓存项仍处于更新状态，需要将其恢复
+                http_cache.revalidation_failed(&request);
+            });
+            return Response::from_cached(&entry, headonly, "STALE");
+        },
+        Lookup::Miss(stale) => stale,
+    };
+    let mut response = generate_response(request, id, config, cache).await;
+    if response.status_code() >= 500 {
+        if let Some(entry) = stale {
+            warn!("[ID{}]生成响应时出错，返回过期的缓存响应", id);
+            return Response::from_cached(&entry, headonly, "STALE");
+        }
+    }
+    if response.is_dynamic() {
+        http_cache.store(request, &mut response);
+        response.add_header("X-Cache", "MISS");
+    }
+    response
+}
+
+/// 生成重写规则要求的响应：重定向时返回带有`Location`头的重定向响应，检测到重写循环时返回500，重写结果无效时返回400
+fn rewrite_response(outcome: &Outcome, request: &Request, id: u128, config: &Config) -> Response {
+    match outcome {
+        Outcome::Redirect { status, location } => {
+            debug!("[ID{}]重写规则要求重定向到{}", id, location);
+            let mut response = Response::response_with_code(*status, request, id, config);
+            response.add_header("Location", location);
+            response
+        },
+        Outcome::Loop => {
+            error!("[ID{}]请求{}的内部重写超过了{}次，可能存在循环，返回500", id, request.target(), rewrite::MAX_REWRITES);
+            Response::response_500(request, id, config)
+        },
+        Outcome::Invalid => {
+            warn!("[ID{}]请求{}重写后的目标含有空格或控制字符，返回400", id, request.target());
+            Response::response_with_code(400, request, id, config)
+        },
+    }
+}
+
+/// 处理PURGE请求，清除请求路径对应的动态响应缓存
+/// 
+/// 只接受来自本机的请求。清除了缓存时返回200，没有对应的缓存时返回404，未启用缓存时返回405
+fn purge_response(request: &Request, id: u128, config: &Config) -> Response {
+    let http_cache = match config.http_cache() {
+        Some(c) => c,
+        None => return Response::response_with_code(405, request, id, config),
+    };
+    if !request.remote_addr().is_some_and(|a| a.ip().is_loopback()) {
+        warn!("[ID{}]拒绝来自{:?}的PURGE请求", id, request.remote_addr());
+        return Response::response_with_code(403, request, id, config);
+    }
+    let count = http_cache.purge(request);
+    info!("[ID{}]清除了{}的{}个缓存项", id, request.path(), count);
+    match count {
+        0 => Response::response_404(request, id, config),
+        _ => Response::response_with_code(200, request, id, config),
+    }
+}
+
+/// 通过本地文件系统生成响应
 /// 
 /// ## 参数：
-/// - `path`：请求路径
+/// - `request`: 来自浏览器的`request`
+/// - `id`: 当前TCP连接的ID
 /// - `config`：Web服务器配置类型
+/// - `cache`: 共享的文件缓存指针
+async fn local_response(request: &Request, id: u128, config: &Config, cache: &Arc<Mutex<FileCache>>) -> Response {
+    let vhost = config.vhost_for(request.header("host"));
+    debug!("[ID{}]请求属于虚拟主机{}", id, vhost.name());
+    if let Some(location) = request.location() {
+        debug!("[ID{}]请求匹配location {}", id, location.path());
+    }
+    let result = route(request, vhost, id, config).await;
+    debug!("[ID{}]HTTP路由解析完毕", id);
+
+    // 如果path不存在，就返回404。使用Response::response_404
+    match result {
+        Ok(path) => {
+            let path_str = match path.to_str() {
+                Some(s) => s,
+                None => {
+                    error!("[ID{}]无法将路径{}转换为str", id, path.to_string_lossy());
+                    return Response::response_500(request, id, config);
+                },
+            };
+            Response::from(path_str, request, id, cache, config).await
+        },
+        Err(Exception::FileNotFound) => {
+            warn!("[ID{}]请求的路径：{} 不存在，返回404响应", id, request.path());
+            Response::response_404(request, id, config)
+        },
+        Err(Exception::StatusCode(code)) => Response::response_with_code(code, request, id, config),
+        Err(e) => {
+            panic!("非法的错误类型：{}", e);
+        }
+    }
+}
+
+/// 路由解析函数
+/// 
+/// 文件系统上的根目录由请求匹配的location决定，见[Config::document_root]
+/// 
+/// ## 参数：
+/// - `request`：来自浏览器的`request`
+/// - `vhost`: 请求所属的虚拟主机
 /// - `id`: 当前TCP连接的ID
+/// - `config`：Web服务器配置类型
 /// 
 /// ## 返回：
 /// - `u8`: 状态码。0为正常，1为404
 /// - `PathBuf`: 文件的完整路径
 /// - `String`: MIME类型
-async fn route(path: &str, id: u128, root: &str) -> Result<PathBuf, Exception> {
+async fn route(request: &Request, vhost: &VhostConfig, id: u128, config: &Config) -> Result<PathBuf, Exception> {
+    let (root, base) = config.document_root(request);
+    // 使用alias时，去掉请求路径中location的前缀
+    let path = match request.path().strip_prefix(base.trim_end_matches('/')) {
+        Some("") => "/",
+        Some(rest) => rest,
+        None => request.path(),
+    };
     if path == "/" {
         debug!("[ID{}]请求路径为根目录，返回index", id);
-        let path = PathBuf::from(HTML_INDEX);
+        let path = Path::new(root).join(vhost.index());
         return Ok(path);
     } else if path == "*" {     // 常见于OPTIONS方法
         debug!("[ID{}]请求路径为*", id);
         let path = PathBuf::from("*");
         return Ok(path);
     }
-    let mut path_str = path.to_string();
-    path_str.remove(0);
-    let path = Path::new(&path_str);
-    // 将路径和config.wwwroot拼接
+    // 路径已经过百分号解码，不能让其通过`..`或`//etc`形式的绝对路径离开根目录
     let root = Path::new(root);
-    let path = root.join(path);
-    debug!("[ID{}]请求文件路径：{}", id, path.to_str().unwrap());
-    match path.exists() {
-        true => Ok(path),
-        false => Err(Exception::FileNotFound),
+    let full_path = match join_under(root, path) {
+        Some(p) => p,
+        None => {
+            warn!("[ID{}]请求路径{}试图访问根目录之外的文件", id, path);
+            return Err(Exception::FileNotFound);
+        },
+    };
+    // location设置了try_files时，只使用其中的候选文件
+    if let Some(location) = request.location().filter(|l| l.has_try_files()) {
+        let result = location.try_files(path, root);
+        debug!("[ID{}]try_files的结果：{:?}", id, result);
+        return result;
+    }
+    debug!("[ID{}]请求文件路径：{}", id, full_path.to_str().unwrap());
+    if full_path.exists() {
+        return Ok(full_path);
+    }
+    // 路径不存在时，检查路径的某一级是否是CGI脚本，如`/cgi-bin/script.sh/extra`。此时剩余部分作为PATH_INFO
+    for ancestor in full_path.ancestors().skip(1) {
+        if !ancestor.starts_with(root) || ancestor == root {
+            break;
+        }
+        if ancestor.is_file() {
+            if cgi::program_for(ancestor, request.path(), config).is_some() || cgi::is_fastcgi(ancestor, request.path(), config) {
+                debug!("[ID{}]找到CGI脚本：{}", id, ancestor.display());
+                return Ok(ancestor.to_path_buf());
+            }
+            break;
+        }
+    }
+    Err(Exception::FileNotFound)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn route_path(target: &str, config: &Config) -> Result<PathBuf, Exception> {
+        let headers = vec![("Host".to_string(), "localhost".to_string())];
+        let request = Request::from_parts("GET", target, HttpVersion::V1_1, headers, 0).unwrap();
+        route(&request, config.vhost_for(request.header("host")), 0, config).await
+    }
+
+    #[tokio::test]
+    async fn test_route_traversal() {
+        let config = Config::new();
+
+        assert_eq!(route_path("/Cargo.toml", &config).await.unwrap(), Path::new(".").join("Cargo.toml"));
+        for target in ["//etc/passwd", "/%2Fetc/passwd", "/src/../../etc/passwd", "/%2E%2E/etc/passwd"] {
+            assert!(matches!(route_path(target, &config).await, Err(Exception::FileNotFound)), "{}", target);
+        }
     }
 }
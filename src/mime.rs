@@ -0,0 +1,200 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::Read,
+    path::Path,
+};
+
+use log::{info, warn};
+
+use crate::{
+    config::MimeConfig,
+    param::MIME_TYPES,
+};
+
+/// 用于内容嗅探的最大字节数
+const SNIFF_LEN: usize = 512;
+
+/// ### MIME 表 MimeTable
+/// 
+/// 由内置的`MIME_TYPES`、可选的系统`mime.types`文件和配置文件中的覆盖项合并而成，
+/// 后者的优先级更高。
+#[derive(Debug, Clone, Default)]
+pub struct MimeTable {
+    types: HashMap<String, String>,
+    default_type: String,
+    default_charset: Option<String>,
+    sniff: bool,
+}
+
+impl MimeTable {
+    /// 通过MIME配置构建MIME表
+    pub fn from_config(config: &MimeConfig) -> Self {
+        let mut types: HashMap<String, String> = MIME_TYPES.iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        if let Some(file) = config.types_file() {
+            match fs::read_to_string(file) {
+                Ok(content) => {
+                    let entries = parse_mime_types(&content);
+                    info!("已从{}载入{}条MIME类型", file, entries.len());
+                    types.extend(entries);
+                },
+                Err(e) => warn!("无法读取MIME类型文件{}：{}", file, e),
+            }
+        }
+        for (ext, mime) in config.types() {
+            types.insert(ext.to_lowercase(), mime.to_string());
+        }
+        Self {
+            types,
+            default_type: config.default_type().to_string(),
+            default_charset: config.default_charset().map(|c| c.to_string()),
+            sniff: config.sniff(),
+        }
+    }
+
+    /// 确定文件的MIME类型
+    /// 
+    /// 1. 如果文件有扩展名且在表中，则使用表中的类型
+    /// 2. 否则，如果启用了内容嗅探，则根据文件开头的字节判断
+    /// 3. 再否则，使用默认类型
+    /// 
+    /// 对于`text/*`类型，如果没有指定charset，则附加默认的charset。
+    pub fn lookup(&self, path: &Path) -> String {
+        let by_extension = path.extension()
+            .and_then(|e| e.to_str())
+            .and_then(|e| self.types.get(&e.to_lowercase()))
+            .cloned();
+        let mime = match by_extension {
+            Some(m) => m,
+            None if self.sniff => match sniff_file(path) {
+                Some(m) => m.to_string(),
+                None => self.default_type.clone(),
+            },
+            None => self.default_type.clone(),
+        };
+        self.with_charset(mime)
+    }
+
+    /// 为文本类型附加默认的charset
+    fn with_charset(&self, mime: String) -> String {
+        match &self.default_charset {
+            Some(charset) if mime.starts_with("text/") && !mime.contains("charset") => {
+                format!("{};charset={}", mime, charset)
+            },
+            _ => mime,
+        }
+    }
+}
+
+/// 解析`mime.types`格式的文件，每一行的格式为`类型 扩展名1 扩展名2 ...`，`#`开头的行为注释
+fn parse_mime_types(content: &str) -> Vec<(String, String)> {
+    let mut entries = Vec::new();
+    for line in content.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut fields = line.split_whitespace();
+        let mime = match fields.next() {
+            Some(m) => m,
+            None => continue,
+        };
+        for ext in fields {
+            entries.push((ext.to_lowercase(), mime.to_string()));
+        }
+    }
+    entries
+}
+
+/// 读取文件的开头部分并进行内容嗅探
+fn sniff_file(path: &Path) -> Option<&'static str> {
+    let file = File::open(path).ok()?;
+    let mut head = Vec::with_capacity(SNIFF_LEN);
+    file.take(SNIFF_LEN as u64).read_to_end(&mut head).ok()?;
+    sniff(&head)
+}
+
+/// 通过魔数判断内容的MIME类型。无法判断时返回`None`
+/// 
+/// 如果内容不匹配任何已知的魔数，但是合法的UTF-8且不含控制字符，则认为是纯文本。
+pub fn sniff(bytes: &[u8]) -> Option<&'static str> {
+    const MAGIC: &[(&[u8], &str)] = &[
+        (b"\x89PNG\r\n\x1a\n", "image/png"),
+        (b"\xff\xd8\xff", "image/jpeg"),
+        (b"GIF87a", "image/gif"),
+        (b"GIF89a", "image/gif"),
+        (b"%PDF-", "application/pdf"),
+        (b"PK\x03\x04", "application/zip"),
+        (b"\x1f\x8b", "application/gzip"),
+        (b"BZh", "application/x-bzip2"),
+        (b"7z\xbc\xaf\x27\x1c", "application/x-7z-compressed"),
+        (b"\x7fELF", "application/x-executable"),
+        (b"OggS", "application/ogg"),
+        (b"ID3", "audio/mpeg"),
+        (b"wOFF", "font/woff"),
+        (b"wOF2", "font/woff2"),
+        (b"\x00asm", "application/wasm"),
+    ];
+    if bytes.is_empty() {
+        return None;
+    }
+    for (magic, mime) in MAGIC {
+        if bytes.starts_with(magic) {
+            return Some(mime);
+        }
+    }
+    if bytes.len() >= 12 && &bytes[..4] == b"RIFF" && &bytes[8..12] == b"WEBP" {
+        return Some("image/webp");
+    }
+    let lower = String::from_utf8_lossy(&bytes[..bytes.len().min(64)]).to_lowercase();
+    let trimmed = lower.trim_start();
+    if trimmed.starts_with("<!doctype html") || trimmed.starts_with("<html") {
+        return Some("text/html");
+    }
+    if trimmed.starts_with("<?xml") {
+        return Some("text/xml");
+    }
+    if is_text(bytes) {
+        return Some("text/plain");
+    }
+    None
+}
+
+/// 判断字节流是否为文本：合法的UTF-8（允许末尾被截断的字符），并且除常见空白外不含控制字符
+fn is_text(bytes: &[u8]) -> bool {
+    let valid = match std::str::from_utf8(bytes) {
+        Ok(s) => s,
+        // 嗅探只读取了文件开头，末尾可能截断在多字节字符中间
+        Err(e) if e.error_len().is_none() => match std::str::from_utf8(&bytes[..e.valid_up_to()]) {
+            Ok(s) => s,
+            Err(_) => return false,
+        },
+        Err(_) => return false,
+    };
+    !valid.chars().any(|c| c.is_control() && !matches!(c, '\n' | '\r' | '\t' | '\x0c'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_mime_types, sniff};
+
+    #[test]
+    fn test_sniff() {
+        assert_eq!(sniff(b"\x89PNG\r\n\x1a\n\x00\x00"), Some("image/png"));
+        assert_eq!(sniff(b"%PDF-1.7\n"), Some("application/pdf"));
+        assert_eq!(sniff(b"MIT License\n\nCopyright"), Some("text/plain"));
+        assert_eq!(sniff(b"  <!DOCTYPE html><html>"), Some("text/html"));
+        assert_eq!(sniff(b"\x00\x01\x02\x03"), None);
+    }
+
+    #[test]
+    fn test_parse_mime_types() {
+        let content = "# comment\ntext/markdown\tmd markdown\n\napplication/x-empty\n";
+        assert_eq!(parse_mime_types(content), vec![
+            ("md".to_string(), "text/markdown".to_string()),
+            ("markdown".to_string(), "text/markdown".to_string()),
+        ]);
+    }
+}
@@ -1,13 +1,21 @@
 use std::collections::HashMap;
 
 use lazy_static::lazy_static;
-
-pub const HTML_INDEX: &str = r"files/html/index.html";
+use serde_derive::{Deserialize, Serialize};
 
 pub const SERVER_NAME: &str = "eslzzyl-webserver";
 
 pub const CRLF: &str = "\r\n";
 
+/// 即使没有配置解释器，也不能以源码形式发送的脚本扩展名。这类文件往往包含数据库密码等敏感信息
+pub const SCRIPT_EXTENSIONS: [&str; 2] = ["php", "phtml"];
+
+/// 请求头的最大长度，超过该长度的请求会被直接关闭
+pub const MAX_HEADER_SIZE: usize = 16 * 1024;
+
+/// HTTP/1.0的keep-alive连接上等待下一个请求的最长时间，以秒为单位
+pub const KEEP_ALIVE_TIMEOUT: u64 = 5;
+
 lazy_static! {
     pub static ref ALLOWED_METHODS: Vec<HttpRequestMethod> = {
         vec![
@@ -64,6 +72,7 @@ lazy_static! {
         map.insert(421, "Misdirected Request");
         map.insert(422, "Unprocessable Content");
         map.insert(426, "Upgrade Required");
+        map.insert(429, "Too Many Requests");
         // 5xx: Server Error
         map.insert(500, "Internal Server Error");
         map.insert(501, "Not Implemented");
@@ -92,7 +101,7 @@ lazy_static! {
         map.insert("cab", "application/vnd.ms-cab-compressed");
         map.insert("cda", "application/x-cdf");
         map.insert("csh", "application/x-csh");
-        map.insert("css", "text/css;charset=utf-8");
+        map.insert("css", "text/css");
         map.insert("csv", "text/csv");
         map.insert("crx", "application/x-chrome-extension");
         map.insert("deb", "application/x-deb");
@@ -103,14 +112,14 @@ lazy_static! {
         map.insert("exe", "application/x-msdownload");
         map.insert("gif", "image/gif");
         map.insert("gz", "application/gzip");
-        map.insert("htm", "text/html;charset=utf-8");
-        map.insert("html", "text/html;charset=utf-8");
+        map.insert("htm", "text/html");
+        map.insert("html", "text/html");
         map.insert("img", "application/x-iso9660-image");
         map.insert("ico", "image/x-icon");
         map.insert("ics", "text/calendar");
         map.insert("iso", "application/x-iso9660-image");
         map.insert("jar", "application/java-archive");
-        map.insert("js", "text/javascript;charset=utf-8");
+        map.insert("js", "text/javascript");
         map.insert("json", "application/json");
         map.insert("jsonld", "application/ld+json");
         map.insert("jpg", "image/jpeg");
@@ -170,17 +179,25 @@ lazy_static! {
     };
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum HttpVersion {
+    V1_0,
     V1_1,
+    V2,
+    V3,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
 pub enum HttpRequestMethod {
     Get,
     Head,
     Options,
     Post,
+    Put,
+    Delete,
+    Patch,
+    Purge,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -195,7 +212,10 @@ use std::fmt;
 impl fmt::Display for HttpVersion {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match *self {
+            HttpVersion::V1_0 => write!(f, "1.0"),
             HttpVersion::V1_1 => write!(f, "1.1"),
+            HttpVersion::V2 => write!(f, "2"),
+            HttpVersion::V3 => write!(f, "3"),
         }
     }
 }
@@ -207,6 +227,10 @@ impl fmt::Display for HttpRequestMethod {
             HttpRequestMethod::Head => write!(f, "HEAD"),
             HttpRequestMethod::Options => write!(f, "OPTIONS"),
             HttpRequestMethod::Post => write!(f, "POST"),
+            HttpRequestMethod::Put => write!(f, "PUT"),
+            HttpRequestMethod::Delete => write!(f, "DELETE"),
+            HttpRequestMethod::Patch => write!(f, "PATCH"),
+            HttpRequestMethod::Purge => write!(f, "PURGE"),
         }
     }
 }
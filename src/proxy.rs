@@ -0,0 +1,364 @@
+use std::{io, time::Duration};
+
+use bytes::Bytes;
+use log::{debug, warn};
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+    sync::mpsc::Sender,
+    time::timeout,
+};
+
+use crate::{
+    exception::Exception,
+    param::{HttpRequestMethod, CRLF, MAX_HEADER_SIZE, SERVER_NAME},
+    request::Request,
+    upstream::BackendGuard,
+    websocket::{self, Upgrade},
+};
+
+/// 逐跳（hop-by-hop）头，只对单个连接有意义，不能被代理转发
+const HOP_BY_HOP: [&str; 8] = [
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// 读取响应体时的缓冲区大小
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// 上游服务器的响应头
+///
+/// - `status`: 状态码
+/// - `reason`: 状态说明
+/// - `headers`: 响应头，逐跳头已被移除
+#[derive(Debug, Clone)]
+pub struct UpstreamResponse {
+    pub status: u16,
+    pub reason: String,
+    pub headers: Vec<(String, String)>,
+}
+
+/// 响应体的长度
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum BodyLength {
+    /// 没有响应体
+    Empty,
+    /// 由`Content-Length`指定长度
+    Fixed(u64),
+    /// 分块传输编码
+    Chunked,
+    /// 读取到连接关闭为止
+    UntilClose,
+}
+
+/// 判断一个头是否是逐跳头
+///
+/// ## 参数
+/// - `name`: 头的名称
+/// - `connection`: `Connection`头的值，其中列出的头同样是逐跳头
+pub fn is_hop_by_hop(name: &str, connection: Option<&str>) -> bool {
+    HOP_BY_HOP.iter().any(|h| name.eq_ignore_ascii_case(h))
+        || connection.is_some_and(|c| c.split(',').any(|t| t.trim().eq_ignore_ascii_case(name)))
+}
+
+/// 将请求转发给上游服务器
+///
+/// ## 参数
+/// - `upstream`: 上游服务器的地址
+/// - `limit`: 连接上游和等待响应头的超时时间
+/// - `request`: 来自浏览器的`request`
+/// - `sender`: 响应体的发送端。响应体在后台任务中逐块发送到这里
+/// - `backend`: 从上游组中选中的后端，在响应体读取完毕之前保持占用。请求的结果会被记录到后端的失败计数中
+/// - `id`: 用于日志的TCP连接编号
+///
+/// ## 返回
+/// - 上游服务器的响应头。WebSocket升级请求被上游接受（101）时，同时返回到上游的连接，用于建立隧道
+/// - 无法连接或响应不合法时返回`Exception::BadGateway`，超时返回`Exception::GatewayTimeout`
+pub async fn forward(
+    upstream: &str,
+    limit: Duration,
+    request: &Request,
+    sender: Sender<io::Result<Bytes>>,
+    backend: Option<BackendGuard>,
+    id: u128,
+) -> Result<(UpstreamResponse, Option<Upgrade>), Exception> {
+    let result = exchange(upstream, limit, request, id).await;
+    if let Some(backend) = &backend {
+        match result {
+            Ok(_) => backend.mark_success(),
+            Err(_) => backend.mark_failure(),
+        }
+    }
+    let (mut stream, response, length) = result?;
+    if response.status == 101 {
+        return Ok((response, Some(Upgrade::Tunnel(stream, backend))));
+    }
+    if length != BodyLength::Empty {
+        tokio::spawn(async move {
+            if let Err(e) = read_body(&mut stream, length, &sender).await {
+                warn!("[ID{}]读取上游响应体时出错：{}", id, e);
+                let _ = sender.send(Err(e)).await;
+            }
+            drop(backend);
+        });
+    }
+    Ok((response, None))
+}
+
+/// 连接上游服务器，发送请求并读取响应头
+async fn exchange(upstream: &str, limit: Duration, request: &Request, id: u128) -> Result<(BufReader<TcpStream>, UpstreamResponse, BodyLength), Exception> {
+    let stream = match timeout(limit, TcpStream::connect(upstream)).await {
+        Ok(Ok(s)) => s,
+        Ok(Err(e)) => {
+            warn!("[ID{}]无法连接上游服务器{}：{}", id, upstream, e);
+            return Err(Exception::BadGateway);
+        },
+        Err(_) => {
+            warn!("[ID{}]连接上游服务器{}超时", id, upstream);
+            return Err(Exception::GatewayTimeout);
+        },
+    };
+    let mut stream = BufReader::new(stream);
+    let head = build_request_head(request, upstream);
+    let sent = async {
+        stream.write_all(head.as_bytes()).await?;
+        stream.write_all(request.body()).await?;
+        stream.flush().await
+    };
+    if let Err(e) = sent.await {
+        warn!("[ID{}]向上游服务器{}发送请求时出错：{}", id, upstream, e);
+        return Err(Exception::BadGateway);
+    }
+
+    let (response, length) = match timeout(limit, read_response_head(&mut stream, request.method())).await {
+        Ok(Ok(r)) => r,
+        Ok(Err(e)) => {
+            warn!("[ID{}]上游服务器{}的响应不合法：{}", id, upstream, e);
+            return Err(Exception::BadGateway);
+        },
+        Err(_) => {
+            warn!("[ID{}]等待上游服务器{}的响应超时", id, upstream);
+            return Err(Exception::GatewayTimeout);
+        },
+    };
+    debug!("[ID{}]上游服务器{}返回{}", id, upstream, response.status);
+    Ok((stream, response, length))
+}
+
+/// 构建发往上游服务器的请求头
+///
+/// - 逐跳头被移除，请求体的长度由`Content-Length`重新给出
+/// - `Host`被替换为上游服务器的地址，原始的`Host`放在`X-Forwarded-Host`中
+/// - 客户端的地址被追加到`X-Forwarded-For`的末尾
+/// - WebSocket升级请求保留`Upgrade`，其他请求使用`Connection: close`
+fn build_request_head(request: &Request, upstream: &str) -> String {
+    let connection = request.header("connection");
+    let mut head = format!("{} {} HTTP/1.1{}", request.method(), request.target(), CRLF);
+    head.push_str(&format!("Host: {}{}", upstream, CRLF));
+    let mut forwarded_for = None;
+    for (name, value) in request.headers() {
+        if is_hop_by_hop(name, connection) {
+            continue;
+        }
+        match name.to_lowercase().as_str() {
+            // 请求体已经完整接收，100 Continue也已经由本服务器处理
+            "host" | "content-length" | "expect" | "x-forwarded-proto" | "x-forwarded-host" => {},
+            "x-forwarded-for" => forwarded_for = Some(value.as_str()),
+            _ => head.push_str(&format!("{}: {}{}", name, value, CRLF)),
+        }
+    }
+    if let Some(addr) = request.remote_addr() {
+        let value = match forwarded_for {
+            Some(previous) => format!("{}, {}", previous, addr.ip()),
+            None => addr.ip().to_string(),
+        };
+        head.push_str(&format!("X-Forwarded-For: {}{}", value, CRLF));
+    }
+    head.push_str(&format!("X-Forwarded-Proto: {}{}", request.scheme(), CRLF));
+    if let Some(host) = request.header("host") {
+        head.push_str(&format!("X-Forwarded-Host: {}{}", host, CRLF));
+    }
+    if !request.body().is_empty() {
+        head.push_str(&format!("Content-Length: {}{}", request.body().len(), CRLF));
+    }
+    head.push_str(&format!("Via: 1.1 {}{}", SERVER_NAME, CRLF));
+    if websocket::is_upgrade(request) {
+        head.push_str(&format!("Upgrade: websocket{crlf}Connection: Upgrade{crlf}", crlf = CRLF));
+    } else {
+        // 每个请求使用一条新的连接，由上游关闭连接
+        head.push_str(&format!("Connection: close{}", CRLF));
+    }
+    head.push_str(CRLF);
+    head
+}
+
+/// 读取上游服务器的响应头，并确定响应体的长度。除`101`以外的`1xx`中间响应会被跳过
+async fn read_response_head<R: AsyncBufRead + Unpin>(reader: &mut R, method: HttpRequestMethod) -> io::Result<(UpstreamResponse, BodyLength)> {
+    loop {
+        let mut lines = Vec::new();
+        let mut total = 0;
+        loop {
+            let mut line = Vec::new();
+            if reader.read_until(b'\n', &mut line).await? == 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "上游服务器提前关闭了连接"));
+            }
+            total += line.len();
+            if total > MAX_HEADER_SIZE {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "响应头过长"));
+            }
+            let line = String::from_utf8_lossy(&line).trim_end().to_string();
+            if line.is_empty() {
+                break;
+            }
+            lines.push(line);
+        }
+        let status_line = lines.first()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "缺少状态行"))?;
+        let mut parts = status_line.splitn(3, ' ');
+        let version = parts.next().unwrap_or_default();
+        let status = parts.next().and_then(|s| s.parse::<u16>().ok());
+        let status = match status {
+            Some(s) if version.starts_with("HTTP/1.") && (100..600).contains(&s) => s,
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("状态行不合法：{}", status_line))),
+        };
+        if (100..200).contains(&status) && status != 101 {
+            continue;
+        }
+        let reason = parts.next().unwrap_or_default().to_string();
+        let headers: Vec<(String, String)> = lines[1..].iter()
+            .filter_map(|l| l.split_once(':'))
+            .map(|(n, v)| (n.trim().to_string(), v.trim().to_string()))
+            .collect();
+        let find = |name: &str| headers.iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.clone());
+        let length = if method == HttpRequestMethod::Head || status == 101 || status == 204 || status == 304 {
+            BodyLength::Empty
+        } else if find("transfer-encoding").is_some_and(|t| t.to_lowercase().contains("chunked")) {
+            BodyLength::Chunked
+        } else if let Some(len) = find("content-length") {
+            match len.parse::<u64>() {
+                Ok(0) => BodyLength::Empty,
+                Ok(n) => BodyLength::Fixed(n),
+                Err(_) => return Err(io::Error::new(io::ErrorKind::InvalidData, "Content-Length不合法")),
+            }
+        } else {
+            BodyLength::UntilClose
+        };
+        let connection = find("connection");
+        let headers = headers.into_iter()
+            .filter(|(n, _)| !is_hop_by_hop(n, connection.as_deref()))
+            .collect();
+        return Ok((UpstreamResponse { status, reason, headers }, length));
+    }
+}
+
+/// 按照响应体的长度读取响应体，逐块发送到`sender`。接收端关闭时停止读取
+async fn read_body<R: AsyncBufRead + Unpin>(reader: &mut R, length: BodyLength, sender: &Sender<io::Result<Bytes>>) -> io::Result<()> {
+    let closed = || io::Error::new(io::ErrorKind::BrokenPipe, "接收端已关闭");
+    match length {
+        BodyLength::Empty => Ok(()),
+        BodyLength::Fixed(n) => copy_exact(reader, n, sender).await,
+        BodyLength::UntilClose => {
+            let mut buffer = vec![0; CHUNK_SIZE];
+            loop {
+                let n = reader.read(&mut buffer).await?;
+                if n == 0 {
+                    return Ok(());
+                }
+                sender.send(Ok(Bytes::copy_from_slice(&buffer[..n]))).await.map_err(|_| closed())?;
+            }
+        },
+        BodyLength::Chunked => {
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).await?;
+                let size = line.split(';').next().unwrap_or_default().trim();
+                let size = u64::from_str_radix(size, 16)
+                    .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "分块长度不合法"))?;
+                if size == 0 {
+                    // 跳过尾部的头（trailer）
+                    loop {
+                        line.clear();
+                        if reader.read_line(&mut line).await? == 0 || line.trim_end().is_empty() {
+                            return Ok(());
+                        }
+                    }
+                }
+                copy_exact(reader, size, sender).await?;
+                line.clear();
+                reader.read_line(&mut line).await?;
+            }
+        },
+    }
+}
+
+/// 读取恰好`n`个字节，逐块发送到`sender`
+async fn copy_exact<R: AsyncBufRead + Unpin>(reader: &mut R, n: u64, sender: &Sender<io::Result<Bytes>>) -> io::Result<()> {
+    let mut remaining = n;
+    let mut buffer = vec![0; CHUNK_SIZE];
+    while remaining > 0 {
+        let want = remaining.min(CHUNK_SIZE as u64) as usize;
+        let read = reader.read(&mut buffer[..want]).await?;
+        if read == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "上游服务器提前关闭了连接"));
+        }
+        remaining -= read as u64;
+        sender.send(Ok(Bytes::copy_from_slice(&buffer[..read]))).await
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "接收端已关闭"))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::{param::HttpVersion, rewrite::{self, RewriteRule}};
+
+    #[tokio::test]
+    async fn test_read_chunked_response() {
+        let raw = b"HTTP/1.1 100 Continue\r\n\r\n\
+            HTTP/1.1 201 Created\r\nContent-Type: text/plain\r\nConnection: close, X-Internal\r\n\
+            X-Internal: 1\r\nTransfer-Encoding: chunked\r\n\r\n\
+            5\r\nhello\r\n7;ext=1\r\n, world\r\n0\r\nTrailer: x\r\n\r\n";
+        let mut reader = BufReader::new(&raw[..]);
+        let (response, length) = read_response_head(&mut reader, HttpRequestMethod::Get).await.unwrap();
+        assert_eq!(response.status, 201);
+        assert_eq!(response.reason, "Created");
+        assert_eq!(response.headers, vec![("Content-Type".to_string(), "text/plain".to_string())]);
+        assert_eq!(length, BodyLength::Chunked);
+
+        let (sender, mut receiver) = mpsc::channel(8);
+        read_body(&mut reader, length, &sender).await.unwrap();
+        drop(sender);
+        let mut body = Vec::new();
+        while let Some(chunk) = receiver.recv().await {
+            body.extend_from_slice(&chunk.unwrap());
+        }
+        assert_eq!(body, b"hello, world");
+    }
+
+    #[test]
+    fn test_request_head_after_rewrite() {
+        let config = toml::from_str("pattern = '(?s)^/app/(.*)$'\nreplacement = '/backend/$1'").unwrap();
+        let rules = [RewriteRule::new(&config).unwrap()];
+        let headers = vec![("Host".to_string(), "example.com".to_string())];
+        let target = "/app/x%20HTTP/1.1%0D%0AHost:%20evil%0D%0A%0D%0AGET%20/admin";
+        let mut request = Request::from_parts("GET", target, HttpVersion::V1_1, headers, 0).unwrap();
+        assert_eq!(rewrite::apply(&rules, &mut request), None);
+
+        // 编码过的CRLF不能在发往上游的请求行中变成真正的CRLF
+        let head = build_request_head(&request, "127.0.0.1:9000");
+        let request_line = head.split(CRLF).next().unwrap();
+        assert_eq!(request_line, "GET /backend/x%20HTTP/1.1%0D%0AHost%3A%20evil%0D%0A%0D%0AGET%20/admin HTTP/1.1");
+        assert!(!head.contains("Host: evil"));
+    }
+}
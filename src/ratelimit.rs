@@ -0,0 +1,129 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv6Addr},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::config::RateLimitConfig;
+
+/// 清理已经装满的令牌桶的间隔。装满的令牌桶与新建的令牌桶没有区别。
+/// 清理按时间而不是按令牌桶的数量进行，否则令牌桶很多时每个请求都要遍历全部令牌桶
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// 一个客户端的令牌桶
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// 全部客户端的令牌桶，以及上次清理的时刻
+#[derive(Debug)]
+struct Buckets {
+    clients: HashMap<IpAddr, Bucket>,
+    cleaned: Instant,
+}
+
+/// ### 限流器 RateLimiter
+///
+/// 按客户端IP地址限流的令牌桶。每个IP地址有一个容量为`burst`的令牌桶，令牌以每秒`rate`个的速度补充，
+/// 每个请求消耗一个令牌，没有令牌时请求被拒绝。IPv6客户端按/64网段共享令牌桶，
+/// 因为一个客户端通常拥有整个/64网段，可以随意更换其中的地址
+#[derive(Debug)]
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    buckets: Mutex<Buckets>,
+}
+
+impl RateLimiter {
+    pub fn new(config: &RateLimitConfig) -> Self {
+        Self {
+            rate: config.rate(),
+            burst: config.burst() as f64,
+            buckets: Mutex::new(Buckets { clients: HashMap::new(), cleaned: Instant::now() }),
+        }
+    }
+
+    /// 尝试为一个请求取得令牌
+    ///
+    /// ## 参数
+    /// - `ip`: 客户端的IP地址
+    ///
+    /// ## 返回
+    /// - 成功时返回`Ok`，被限流时返回还需要等待多长时间才会有可用的令牌
+    pub fn acquire(&self, ip: IpAddr) -> Result<(), Duration> {
+        self.acquire_at(ip, Instant::now())
+    }
+
+    fn acquire_at(&self, ip: IpAddr, now: Instant) -> Result<(), Duration> {
+        let mut buckets = self.buckets.lock().unwrap();
+        if now.saturating_duration_since(buckets.cleaned) >= CLEANUP_INTERVAL {
+            let (rate, burst) = (self.rate, self.burst);
+            buckets.clients.retain(|_, b| b.tokens + now.duration_since(b.updated).as_secs_f64() * rate < burst);
+            buckets.cleaned = now;
+        }
+        let bucket = buckets.clients.entry(client_key(ip)).or_insert(Bucket { tokens: self.burst, updated: now });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.rate))
+        }
+    }
+}
+
+/// 确定客户端使用的令牌桶。IPv4地址使用它本身，IPv6地址使用它所在的/64网段
+fn client_key(ip: IpAddr) -> IpAddr {
+    match ip.to_canonical() {
+        IpAddr::V6(v6) => IpAddr::V6(Ipv6Addr::from(u128::from(v6) & !(u64::MAX as u128))),
+        v4 => v4,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_bucket() {
+        let config: RateLimitConfig = toml::from_str("rate = 2.0\nburst = 3").unwrap();
+        let limiter = RateLimiter::new(&config);
+        let a: IpAddr = "192.0.2.1".parse().unwrap();
+        let b: IpAddr = "192.0.2.2".parse().unwrap();
+        let start = Instant::now();
+
+        for _ in 0..3 {
+            assert!(limiter.acquire_at(a, start).is_ok());
+        }
+        let wait = limiter.acquire_at(a, start).unwrap_err();
+        assert_eq!(wait, Duration::from_millis(500));
+        // 不同的IP地址互不影响
+        assert!(limiter.acquire_at(b, start).is_ok());
+        // 0.5秒后补充了一个令牌
+        assert!(limiter.acquire_at(a, start + Duration::from_millis(500)).is_ok());
+        assert!(limiter.acquire_at(a, start + Duration::from_millis(500)).is_err());
+    }
+
+    #[test]
+    fn test_ipv6_prefix() {
+        let config: RateLimitConfig = toml::from_str("rate = 1.0\nburst = 1").unwrap();
+        let limiter = RateLimiter::new(&config);
+        let start = Instant::now();
+
+        // 同一个/64网段中的地址共享令牌桶
+        assert!(limiter.acquire_at("2001:db8::1".parse().unwrap(), start).is_ok());
+        assert!(limiter.acquire_at("2001:db8::ffff:2".parse().unwrap(), start).is_err());
+        assert!(limiter.acquire_at("2001:db8:0:1::1".parse().unwrap(), start).is_ok());
+        assert_eq!(client_key("::ffff:192.0.2.1".parse().unwrap()), "192.0.2.1".parse::<IpAddr>().unwrap());
+
+        // 定时清理装满的令牌桶
+        let later = start + CLEANUP_INTERVAL;
+        assert!(limiter.acquire_at("192.0.2.1".parse().unwrap(), later).is_ok());
+        assert_eq!(limiter.buckets.lock().unwrap().clients.len(), 1);
+    }
+}
@@ -1,25 +1,42 @@
 use crate::{
     exception::Exception,
+    location::Location,
+    rewrite::Outcome,
     param::*,
+    tls::ClientCert,
+    util::percent_decode,
 };
 
+use bytes::Bytes;
 use log::error;
 
+use std::{net::SocketAddr, sync::Arc};
+
 #[derive(Debug, Clone)]
 pub struct Request {
     method: HttpRequestMethod,
     path: String,
+    query: String,
+    target: String,                      // 原始的请求目标，未经解码，用于反向代理
     version: HttpVersion,
     user_agent: String,
     accept_encoding: Vec<HttpEncoding>,  // 压缩编码，可以支持多种编码，如果该vec为空说明不支持压缩
+    headers: Vec<(String, String)>,      // 全部请求头，保留原始的大小写和顺序
+    body: Bytes,                         // 请求体，没有请求体时为空
+    remote_addr: Option<SocketAddr>,     // 客户端的地址
+    secure: bool,                        // 是否通过HTTPS连接到达
+    client_cert: Option<Arc<ClientCert>>, // 经过验证的客户端证书，只在匹配`client_auth`的路径上设置
+    location: Option<Arc<Location>>,     // 匹配的location，在解析完成后设置
+    rewrite: Option<Outcome>,            // 重写规则要求的重定向，或者检测到的重写循环和无效的重写结果
+    remote_user: Option<String>,         // 通过Basic认证的用户名
 }
 
 impl Request {
     /// 尝试通过字节流解析Request
     /// 
     /// ## 参数：
-    /// - `buffer`: 来自客户浏览器的请求报文，用字节流表示
-    pub fn try_from(buffer: &Vec<u8>, id: u128) -> Result<Self, Exception> {
+    /// - `buffer`: 来自客户浏览器的请求报文头部，用字节流表示。请求体需要另外通过`set_body`设置
+    pub fn try_from(buffer: &[u8], id: u128) -> Result<Self, Exception> {
         let request_string = match String::from_utf8(buffer.to_vec()) {
             Ok(string) => string,
             Err(_) => {
@@ -33,64 +50,157 @@ impl Request {
 
         // 然后再以空格分割首行
         let first_line: Vec<&str> = request_lines[0].split(" ").collect();
-        let method_str = first_line[0].to_uppercase();
+        if first_line.len() != 3 {
+            error!("[ID{}]无法解析请求行：{}", id, request_lines[0]);
+            return Err(Exception::MalformedRequest);
+        }
+        let version_str = first_line[2].to_uppercase();
+        let version = match version_str.as_str() {
+            r"HTTP/1.0" => HttpVersion::V1_0,
+            r"HTTP/1.1" => HttpVersion::V1_1,
+            _ => {
+                error!("[ID{}]不支持的HTTP协议版本：{}", id, &version_str);
+                return Err(Exception::UnsupportedHttpVersion);
+            }
+        };
+
+        // 收集全部请求头，请求头在第一个空行处结束
+        let mut headers = Vec::new();
+        for line in request_lines.iter().skip(1) {
+            if line.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                headers.push((name.trim().to_string(), value.trim().to_string()));
+            }
+        }
+
+        Self::from_parts(first_line[0], first_line[1], version, headers, id)
+    }
+
+    /// 通过已经解析的请求行和请求头构建Request，与HTTP版本无关
+    /// 
+    /// ## 参数：
+    /// - `method`: 请求方法
+    /// - `target`: 请求目标，即路径和查询字符串，未经解码
+    /// - `version`: HTTP版本
+    /// - `headers`: 全部请求头
+    /// - `id`: 用于日志的连接编号
+    pub fn from_parts(method: &str, target: &str, version: HttpVersion, headers: Vec<(String, String)>, id: u128) -> Result<Self, Exception> {
+        let method_str = method.to_uppercase();
         let method = match method_str.as_str() {
             "GET" => HttpRequestMethod::Get,
             "HEAD" => HttpRequestMethod::Head,
             "OPTIONS" => HttpRequestMethod::Options,
             "POST" => HttpRequestMethod::Post,
+            // 以下方法只能用于反向代理，见`Response::from`
+            "PUT" => HttpRequestMethod::Put,
+            "DELETE" => HttpRequestMethod::Delete,
+            "PATCH" => HttpRequestMethod::Patch,
+            // 用于清除动态响应缓存
+            "PURGE" => HttpRequestMethod::Purge,
             _ => {
                 error!("[ID{}]不支持的HTTP请求方法：{}", id, &method_str);
                 return Err(Exception::UnSupportedRequestMethod);
             }
         };
-        let path = first_line[1].to_string();
-        let version_str = first_line[2].to_uppercase();
-        let version = match version_str.as_str() {
-            // 当前只支持1.1
-            r"HTTP/1.1" => HttpVersion::V1_1,
-            _ => {
-                error!("[ID{}]不支持的HTTP协议版本：{}", id, &version_str);
-                return Err(Exception::UnsupportedHttpVersion);
-            }
+        // 请求目标可能带有查询字符串，路径部分需要进行百分号解码
+        let (path, query) = match target.split_once('?') {
+            Some((p, q)) => (percent_decode(p), q.to_string()),
+            None => (percent_decode(target), String::new()),
         };
 
-        // 确定剩余字段
-        let mut user_agent = "".to_string();
+        // 确定剩余字段，注意 HTTP请求头大小写不敏感
+        let header = |name: &str| headers.iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str());
+        let user_agent = header("user-agent").unwrap_or_default().to_string();
+        // 确定accept-encoding，即浏览器能接受的压缩编码
         let mut accept_encoding = vec!();
-        for line in &request_lines {
-            // 确定user-agent，注意 HTTP请求头大小写不敏感
-            if line.starts_with("user-agent") || line.starts_with("User-Agent") {
-                user_agent = line.split(": ").collect::<Vec<&str>>()[1].to_string();
-                break;
+        if let Some(encoding) = header("accept-encoding") {
+            if encoding.contains("gzip") {
+                accept_encoding.push(HttpEncoding::Gzip);
             }
-        }
-
-        for line in &request_lines {
-            // 确定accept-encoding，即浏览器能接受的压缩编码
-            if line.starts_with("accept-encoding") || line.starts_with("Accept-Encoding") {
-                let encoding = line.split(": ").collect::<Vec<&str>>()[1];
-                if encoding.contains("gzip") {
-                    accept_encoding.push(HttpEncoding::Gzip);
-                }
-                if encoding.contains("deflate") {
-                    accept_encoding.push(HttpEncoding::Deflate);
-                }
-                if encoding.contains("br") {
-                    accept_encoding.push(HttpEncoding::Br);
-                }
-                break;
+            if encoding.contains("deflate") {
+                accept_encoding.push(HttpEncoding::Deflate);
+            }
+            if encoding.contains("br") {
+                accept_encoding.push(HttpEncoding::Br);
             }
         }
 
         Ok(Self {
             method,
             path,
+            query,
+            target: target.to_string(),
             version,
             user_agent,
             accept_encoding,
+            headers,
+            body: Bytes::new(),
+            remote_addr: None,
+            secure: false,
+            client_cert: None,
+            location: None,
+            rewrite: None,
+            remote_user: None,
         })
     }
+
+    /// 设置请求体
+    pub fn set_body(&mut self, body: Bytes) -> &mut Self {
+        self.body = body;
+        self
+    }
+
+    /// 设置客户端的地址
+    pub fn set_remote_addr(&mut self, addr: SocketAddr) -> &mut Self {
+        self.remote_addr = Some(addr);
+        self
+    }
+
+    /// 设置请求是否通过HTTPS连接到达
+    pub fn set_secure(&mut self, secure: bool) -> &mut Self {
+        self.secure = secure;
+        self
+    }
+
+    /// 设置经过验证的客户端证书
+    pub fn set_client_cert(&mut self, cert: Option<Arc<ClientCert>>) -> &mut Self {
+        self.client_cert = cert;
+        self
+    }
+
+    /// 设置请求目标，用于URL重写。路径和查询字符串按照新的请求目标重新解析
+    pub fn set_target(&mut self, target: &str) -> &mut Self {
+        let (path, query) = match target.split_once('?') {
+            Some((p, q)) => (percent_decode(p), q.to_string()),
+            None => (percent_decode(target), String::new()),
+        };
+        self.path = path;
+        self.query = query;
+        self.target = target.to_string();
+        self
+    }
+
+    /// 设置重写规则的结果
+    pub fn set_rewrite(&mut self, outcome: Option<Outcome>) -> &mut Self {
+        self.rewrite = outcome;
+        self
+    }
+
+    /// 设置通过Basic认证的用户名
+    pub fn set_remote_user(&mut self, user: Option<String>) -> &mut Self {
+        self.remote_user = user;
+        self
+    }
+
+    /// 设置匹配的location
+    pub fn set_location(&mut self, location: Option<Arc<Location>>) -> &mut Self {
+        self.location = location;
+        self
+    }
 }
 
 impl Request {
@@ -104,6 +214,28 @@ impl Request {
         &self.path
     }
 
+    /// 返回请求的原始查询字符串（不含`?`）
+    pub fn query(&self) -> &str {
+        &self.query
+    }
+
+    /// 返回原始的请求目标（路径和查询字符串），未经百分号解码
+    pub fn target(&self) -> &str {
+        &self.target
+    }
+
+    /// 查找查询字符串中的参数，返回解码后的值。参数不存在时返回`None`
+    pub fn query_param(&self, name: &str) -> Option<String> {
+        self.query.split('&')
+            .filter_map(|pair| match pair.split_once('=') {
+                Some((k, v)) => Some((k, v)),
+                None if !pair.is_empty() => Some((pair, "")),
+                None => None,
+            })
+            .find(|(k, _)| percent_decode(k) == name)
+            .map(|(_, v)| percent_decode(&v.replace('+', " ")))
+    }
+
     /// 返回请求的方法
     pub fn method(&self) -> HttpRequestMethod {
         self.method
@@ -114,8 +246,80 @@ impl Request {
         &self.user_agent
     }
 
+    /// 查找请求头，名称大小写不敏感。请求头不存在时返回`None`
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// 返回全部请求头
+    pub fn headers(&self) -> &[(String, String)] {
+        &self.headers
+    }
+
+    /// 返回请求体
+    pub fn body(&self) -> &Bytes {
+        &self.body
+    }
+
+    /// 返回客户端的地址
+    pub fn remote_addr(&self) -> Option<SocketAddr> {
+        self.remote_addr
+    }
+
+    /// 请求是否通过HTTPS连接到达
+    pub fn is_secure(&self) -> bool {
+        self.secure
+    }
+
+    /// 返回请求的协议，`http`或`https`
+    pub fn scheme(&self) -> &'static str {
+        match self.secure {
+            true => "https",
+            false => "http",
+        }
+    }
+
+    /// 返回经过验证的客户端证书
+    pub fn client_cert(&self) -> Option<&ClientCert> {
+        self.client_cert.as_deref()
+    }
+
+    /// 返回重写规则要求的重定向，或者检测到的重写循环
+    pub fn rewrite(&self) -> Option<&Outcome> {
+        self.rewrite.as_ref()
+    }
+
+    /// 返回通过Basic认证的用户名，没有经过认证时为`None`
+    pub fn remote_user(&self) -> Option<&str> {
+        self.remote_user.as_deref()
+    }
+
+    /// 返回匹配的location
+    pub fn location(&self) -> Option<&Location> {
+        self.location.as_deref()
+    }
+
     /// 返回当前浏览器接受的压缩编码
     pub fn accept_encoding(&self) -> &Vec<HttpEncoding> {
         &self.accept_encoding
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_http_versions() {
+        let request = Request::try_from(b"GET /a%20b?x=1 HTTP/1.0\r\nConnection: keep-alive\r\n\r\n", 0).unwrap();
+        assert_eq!(*request.version(), HttpVersion::V1_0);
+        assert_eq!(request.path(), "/a b");
+        assert_eq!(request.header("connection"), Some("keep-alive"));
+
+        assert!(matches!(Request::try_from(b"GET / HTTP/2.0\r\n\r\n", 0), Err(Exception::UnsupportedHttpVersion)));
+        assert!(matches!(Request::try_from(b"BREW / HTTP/1.1\r\n\r\n", 0), Err(Exception::UnSupportedRequestMethod)));
+        // HTTP/0.9风格的请求行没有版本
+        assert!(matches!(Request::try_from(b"GET /\r\n\r\n", 0), Err(Exception::MalformedRequest)));
+    }
+}
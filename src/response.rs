@@ -1,8 +1,20 @@
 use crate::{
     param::*,
+    archive::{self, ArchiveFormat, ChannelWriter},
+    cgi::{self, CgiResponse, Invocation, ScriptLocation},
+    config::{Config, ProxyConfig},
+    exception::Exception,
+    fastcgi::FastCgiClient,
+    http_cache::CacheEntry,
+    proxy,
     request::Request,
+    sse,
+    websocket::{self, PendingUpgrade, Upgrade, WebSocketHandler, WEBSOCKET_VERSION},
     cache::FileCache,
-    util::{HtmlBuilder, handle_php},
+    util::{
+        HtmlBuilder, ListingFormat, ListingOptions,
+        fill_error_page, read_dir_entries, sort_dir_entries, dir_to_json, percent_encode,
+    },
 };
 
 use chrono::prelude::*;
@@ -13,12 +25,18 @@ use flate2::{
 };
 use brotli::enc::{self, backward_references::BrotliEncoderParams};
 use log::{error, warn, debug};
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt},
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        oneshot,
+    },
+};
 
 use std::{
     io::{self, Read, Write},
     sync::{Arc, Mutex},
     fs::{self, File, metadata},
-    ffi::OsStr,
     path::{Path, PathBuf},
     str,
 };
@@ -35,6 +53,10 @@ use std::{
 /// - `server_name`: 服务器名
 /// - `allow`: 服务器允许的HTTP请求方法
 /// - `content`: 响应体本身
+/// - `headers`: 其他响应头
+/// - `body_stream`: 流式响应体。存在时以分块传输编码发送，`content`应当为`None`
+/// - `dynamic`: 是否是由CGI程序、FastCGI服务器或上游服务器生成的动态响应，只有动态响应会被`HttpCache`缓存
+/// - `upgrade`: 101响应写回之后接管连接的操作，见[PendingUpgrade]
 #[derive(Debug, Clone)]
 pub struct Response {
     version: HttpVersion,
@@ -47,6 +69,35 @@ pub struct Response {
     server_name: String,
     allow: Option<Vec<HttpRequestMethod>>,
     content: Option<Bytes>,
+    headers: Vec<(String, String)>,
+    body_stream: Option<BodyStream>,
+    dynamic: bool,
+    upgrade: Option<PendingUpgrade>,
+}
+
+/// 流式响应体
+/// 
+/// 响应体由其他任务通过通道逐块发送，以分块传输编码写回浏览器，从而不必在内存中保存完整的响应体。
+/// 通道中的`Err`表示响应体生成失败，此时连接会被直接关闭，浏览器能够据此发现响应不完整。
+/// 
+/// `Response`需要能够被克隆，因此接收端被包装在`Arc<Mutex<Option<_>>>`中，只能被取出一次。
+#[derive(Debug, Clone)]
+pub struct BodyStream(Arc<Mutex<Option<Receiver<io::Result<Bytes>>>>>);
+
+impl BodyStream {
+    /// 创建一个流式响应体，以及向其发送数据的发送端
+    /// 
+    /// ## 参数
+    /// - `buffer`: 通道中最多缓存的数据块数量，发送端会在通道满时等待
+    pub fn channel(buffer: usize) -> (Sender<io::Result<Bytes>>, Self) {
+        let (sender, receiver) = mpsc::channel(buffer);
+        (sender, Self(Arc::new(Mutex::new(Some(receiver)))))
+    }
+
+    /// 取出接收端。只有第一次调用会返回`Some`
+    fn take(&self) -> Option<Receiver<io::Result<Bytes>>> {
+        self.0.lock().unwrap().take()
+    }
 }
 
 impl Response {
@@ -62,6 +113,9 @@ impl Response {
     /// - Server: `SERVER_NAME`
     /// - Allow: GET、HEAD、OPTIONS
     /// - Content：None
+    /// - 其他响应头：无
+    /// - 流式响应体：None
+    /// - 动态响应：否
     pub fn new() -> Self {
         Self {
             version: HttpVersion::V1_1,
@@ -74,6 +128,10 @@ impl Response {
             server_name: SERVER_NAME.to_string(),
             allow: Some(ALLOWED_METHODS.to_vec()),
             content: None,
+            headers: Vec::new(),
+            body_stream: None,
+            dynamic: false,
+            upgrade: None,
         }
     }
 
@@ -207,19 +265,66 @@ impl Response {
         response
     }
 
+    /// 通过配置的自定义错误页面创建response对象。
+    /// 
+    /// 如果没有为该状态码配置错误页面，或者页面文件无法读取，则退回到`from_status_code`生成的内置页面。
+    /// 
+    /// ## 参数
+    /// - `code`: 状态码
+    /// - `request`: 来自浏览器的`request`，用于确定压缩编码和填充请求路径
+    /// - `id`: 用于日志的TCP连接编号
+    /// - `config`: Web服务器配置
+    /// 
+    /// ## 返回
+    /// - 一个新的 Response 对象，不完整，还需要进一步处理才能发回浏览器
+    fn from_error_page(code: u16, request: &Request, id: u128, config: &Config) -> Self {
+        let accept_encoding = request.accept_encoding().to_vec();
+        let vhost = config.vhost_for(request.header("host"));
+        let page = match vhost.error_page(code) {
+            Some(p) => Path::new(vhost.root()).join(p),
+            None => return Self::from_status_code(code, accept_encoding, id),
+        };
+        let template = match fs::read_to_string(&page) {
+            Ok(t) => t,
+            Err(e) => {
+                warn!("[ID{}]无法读取错误页面{}：{}，使用内置页面", id, page.display(), e);
+                return Self::from_status_code(code, accept_encoding, id);
+            }
+        };
+        debug!("[ID{}]使用自定义错误页面{}", id, page.display());
+        let reason = STATUS_CODES.get(&code).copied().unwrap_or("");
+        let content = fill_error_page(&template, code, reason, request.path());
+
+        let mut response = Self::new();
+        response.allow = None;
+        response.content_encoding = decide_encoding(&accept_encoding);
+        let content_compressed = compress(content.into_bytes(), response.content_encoding).unwrap();
+        response.content_length = content_compressed.len() as u64;
+        response.content = Some(Bytes::from(content_compressed));
+        response.content_type = Some("text/html;charset=utf-8".to_string());
+        response.set_code(code);
+        response
+    }
+
     /// 通过目录来生成一个 `Response`，该 `Response` 应当列出目录的所有文件。
     /// 
     /// ## 参数
     /// - `path`: 文件的完整路径
-    /// - `accept_encoding`: 浏览器能够接受的压缩编码，需要根据该参数确定压缩编码
+    /// - `request`: 来自浏览器的`request`，用于确定压缩编码、URL路径和排序方式
     /// - `id`: 用于日志的TCP连接编号
-    /// - `cache`: 共享的文件缓存指针
+    /// - `headonly`: 是否为HEAD请求
+    /// - `config`: Web服务器配置，用于在JSON列表中确定文件的MIME类型
     /// 
     /// ## 返回
     /// - 一个新的 Response 对象，不完整，还需要进一步处理才能发回浏览器
-    fn from_dir(path: &str, accept_encoding: Vec<HttpEncoding>, id: u128, cache: &Arc<Mutex<FileCache>>, headonly: bool) -> Self {
+    /// 
+    /// 根据请求的`Accept`头或`format`查询参数，列表可以是HTML页面，也可以是JSON。
+    fn from_dir(path: &str, request: &Request, id: u128, headonly: bool, config: &Config) -> Self {
+        let accept_encoding = request.accept_encoding().to_vec();
+        let options = ListingOptions::from_request(request);
         let mut response = Self::new();
         response.allow = None;
+        response.set_code(200);
         response.content_encoding = match headonly {
             true => None,
             false => decide_encoding(&accept_encoding),
@@ -233,93 +338,524 @@ impl Response {
 
         // 仅在有响应体时才设置content-type
         if !headonly {
-            response.content_type = Some("text/html;charset=utf-8".to_string());
+            response.content_type = Some(match options.format() {
+                ListingFormat::Html => "text/html;charset=utf-8".to_string(),
+                ListingFormat::Json => "application/json".to_string(),
+            });
         } else {
             response.content_type = None;
         }
 
-        // 查找缓存
-        let mut cache_lock = cache.lock().unwrap();
-        match cache_lock.find(path) {
-            Some(bytes) => {
-                debug!("[ID{}]缓存命中", id);
-                // 这里其实是有个潜在问题的。理论上不同客户端要求的encoding可能会不同，但是缓存却是共享的，导致encoding是相同的。
-                // 但是单客户端情况下可以忽略。而且目前所有主流浏览器也都支持gzip了。
-                response.content = match headonly {
-                    // headonly时，填入一个空字符串，否则填入找到的bytes
-                    true => None,
-                    false => Some(bytes.clone()),
-                };
-                response.content_length = bytes.len() as u64;
-            },
-            None => {   // 缓存未命中，生成目录列表
-                debug!("[ID{}]缓存未命中", id);
-                let mut dir_vec = Vec::<PathBuf>::new();
-                let entries = fs::read_dir(path).unwrap();
-                for entry in entries.into_iter() {
-                    dir_vec.push(entry.unwrap().path());
+        // 目录列表不写入缓存：列表取决于请求路径、排序方式、location的设置和目录当前的内容，缓存无法及时失效
+        let mut entries = match read_dir_entries(path, config.show_hidden()) {
+            Ok(e) => e,
+            Err(e) => {
+                error!("[ID{}]无法读取目录{}：{}", id, path, e);
+                return Self::response_500(request, id, config);
+            }
+        };
+        sort_dir_entries(&mut entries, &options);
+        let content = match options.format() {
+            ListingFormat::Html => HtmlBuilder::from_dir(request.path(), &entries, &options, config.archive().enabled()).build(),
+            ListingFormat::Json => dir_to_json(request.path(), path, &entries, config.mime()),
+        };
+        let content_compressed = compress(content.into_bytes(), response.content_encoding).unwrap();
+        response.content_length = content_compressed.len() as u64;
+        // headonly时不填入响应体
+        if !headonly {
+            response.content = Some(Bytes::from(content_compressed));
+        }
+        response
+    }
+
+    /// 将目录打包为压缩包，生成一个流式的`Response`
+    /// 
+    /// ## 参数
+    /// - `path`: 目录的完整路径
+    /// - `request`: 来自浏览器的`request`
+    /// - `id`: 用于日志的TCP连接编号
+    /// - `config`: Web服务器配置，用于确定隐藏文件规则和大小上限
+    /// - `format`: 压缩包格式
+    /// - `headonly`: 是否为HEAD请求
+    /// 
+    /// ## 返回
+    /// - 一个新的 Response 对象，不完整，还需要进一步处理才能发回浏览器
+    /// 
+    /// 遍历目录树和生成压缩包都在同一个阻塞线程中进行，压缩包通过`BodyStream`边生成边发送，不会在内存中保存完整的压缩包。
+    /// 遍历完成后，阻塞线程通过oneshot通道告知目录的项数和总大小，超过上限时返回403
+    async fn from_archive(path: &str, request: &Request, id: u128, config: &Config, format: ArchiveFormat, headonly: bool) -> Self {
+        let dir = PathBuf::from(path);
+        let root = PathBuf::from(config.document_root(request).0);
+        let show_hidden = config.show_hidden();
+        let max_size = config.archive().max_size();
+        let (checked_sender, checked) = oneshot::channel();
+        let (sender, body) = BodyStream::channel(4);
+        let walk_dir = dir.clone();
+        tokio::task::spawn_blocking(move || {
+            let entries = match archive::collect_entries(&walk_dir, &root, show_hidden) {
+                Ok(e) => e,
+                Err(e) => {
+                    let _ = checked_sender.send(Err(e));
+                    return;
                 }
-                let content = HtmlBuilder::from_dir(path, &mut dir_vec).build();
-                let content_compressed = compress(content.into_bytes(), response.content_encoding).unwrap();
-                response.content_length = content_compressed.len() as u64;
-                // headonly时，填入一个空字符串，否则填入压缩好的content
-                response.content = match headonly {
-                    true => None,
-                    false => Some(Bytes::from(content_compressed.clone())),
-                };
-                // 无论是否是HEAD请求，都要写缓存
-                cache_lock.push(path, Bytes::from(content_compressed));
+            };
+            let total = archive::total_size(&entries);
+            // 浏览器已经断开，或者不需要生成压缩包
+            if checked_sender.send(Ok((entries.len(), total))).is_err() || headonly || total > max_size {
+                return;
             }
+            let writer = ChannelWriter::new(sender.clone());
+            if let Err(e) = archive::write_archive(format, &entries, writer, max_size) {
+                warn!("[ID{}]打包中断：{}", id, e);
+                let _ = sender.blocking_send(Err(e));
+            }
+        });
+        match checked.await {
+            Ok(Ok((count, total))) if total > max_size => {
+                warn!("[ID{}]目录{}的总大小{}超过了打包上限{}，拒绝打包，共{}项", id, path, total, max_size, count);
+                return Self::from_error_page(403, request, id, config);
+            },
+            Ok(Ok((count, total))) => debug!("[ID{}]打包目录{}，共{}项，{}字节", id, path, count, total),
+            Ok(Err(e)) => {
+                error!("[ID{}]无法读取目录{}：{}", id, path, e);
+                return Self::from_error_page(500, request, id, config);
+            },
+            Err(_) => {
+                error!("[ID{}]遍历目录{}的线程意外退出", id, path);
+                return Self::from_error_page(500, request, id, config);
+            },
         }
+
+        let name = dir.file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_else(|| "archive".to_string());
+        let filename = format!("{}.{}", name, format.extension());
+        let mut response = Self::new();
+        response.allow = None;
+        response.set_code(200);
+        response.headers.push((
+            "Content-Disposition".to_string(),
+            format!(
+                "attachment; filename=\"{}\"; filename*=UTF-8''{}",
+                filename.replace(['"', '\\'], "_"),
+                percent_encode(&filename),
+            ),
+        ));
+        if headonly {
+            return response;
+        }
+        response.content_type = Some(format.mime().to_string());
+        response.body_stream = Some(body);
         response
     }
 
-    /// 通过HTML代码生成一个`Response`
+    /// 通过CGI程序的响应生成一个`Response`
     /// 
     /// ## 参数
-    /// - `html`: HTML代码
+    /// - `cgi`: CGI程序的响应
     /// - `accept_encoding`: 浏览器能够接受的压缩编码，需要根据该参数确定压缩编码
     /// - `id`: 用于日志的TCP连接编号
+    /// - `headonly`: 是否为HEAD请求
+    /// - `body_stream`: 流式响应体。为`Some`时忽略`cgi.body`，响应体以分块传输编码发送，不进行压缩
     /// 
     /// ## 返回
     /// - 一个新的 Response 对象，不完整，还需要进一步处理才能发回浏览器
     /// 
-    /// 本函数不涉及对文件缓存的访问，因为本函数被设计用来进行PHP的处理，而PHP往往是动态页面。
-    fn from_html(html: &str, accept_encoding: Vec<HttpEncoding>, id: u128, headonly: bool) -> Response {
+    /// 本函数不涉及对文件缓存的访问，因为CGI程序的输出往往是动态的。
+    /// 如果CGI程序自己设置了`Content-Encoding`，则不再进行压缩。
+    fn from_cgi(cgi: CgiResponse, accept_encoding: Vec<HttpEncoding>, id: u128, headonly: bool, body_stream: Option<BodyStream>) -> Response {
         let mut response = Self::new();
         response.allow = None;
-        if headonly {
-            response.content_encoding = None;
-            response.content_type = None;
-            response.content = None;
+        response.dynamic = true;
+        response.set_status(cgi.status, cgi.reason.as_deref());
+        let mut content_type = None;
+        let mut encoded = false;
+        for (name, value) in cgi.headers {
+            let lower = name.to_lowercase();
+            match lower.as_str() {
+                "content-type" => content_type = Some(value),
+                // 以下响应头由服务器自己生成
+                "content-length" | "transfer-encoding" | "connection" | "date" | "server" => {},
+                _ => {
+                    if lower == "content-encoding" {
+                        encoded = true;
+                    }
+                    response.headers.push((name, value));
+                },
+            }
+        }
+        if let Some(body) = body_stream {
+            if !headonly {
+                response.content_type = Some(content_type.unwrap_or_else(|| "text/html;charset=utf-8".to_string()));
+                response.body_stream = Some(body);
+            }
             return response;
         }
-        response.content_encoding = decide_encoding(&accept_encoding);
+        if cgi.body.is_empty() || headonly {
+            response.content_length = cgi.body.len() as u64;
+            return response;
+        }
+        response.content_encoding = match encoded {
+            true => None,
+            false => decide_encoding(&accept_encoding),
+        };
         match response.content_encoding {
             Some(HttpEncoding::Gzip) => debug!("[ID{}]使用Gzip压缩编码", id),
             Some(HttpEncoding::Br) => debug!("[ID{}]使用Brotli压缩编码", id),
             Some(HttpEncoding::Deflate) => debug!("[ID{}]使用Deflate压缩编码", id),
             None => debug!("[ID{}]不进行压缩", id),
         };
-        let content_compressed = compress(Vec::from(html), response.content_encoding).unwrap();
+        let content_compressed = compress(cgi.body, response.content_encoding).unwrap();
         response.content_length = content_compressed.len() as u64;
-        response.content_type = Some("text/html;charset=utf-8".to_string());
+        // CGI规范要求有响应体时必须给出Content-Type，这里对不规范的程序做一些宽容
+        response.content_type = Some(content_type.unwrap_or_else(|| "text/html;charset=utf-8".to_string()));
         response.content = Some(Bytes::from(content_compressed));
         response
     }
 
+    /// 执行CGI脚本，生成一个`Response`
+    /// 
+    /// ## 参数
+    /// - `invocation`: 执行脚本所用的命令
+    /// - `path`: 脚本的完整路径
+    /// - `request`: 来自浏览器的`request`
+    /// - `id`: 用于日志的TCP连接编号
+    /// - `config`: Web服务器配置
+    /// 
+    /// ## 返回
+    /// - HTTP响应。CGI程序无法执行或输出不合法时返回500，超时返回504，同时运行的CGI程序达到上限时返回503
+    async fn from_script(invocation: &Invocation, path: &str, request: &Request, id: u128, config: &Config) -> Self {
+        let _permit = match Arc::clone(config.cgi_permits()).try_acquire_owned() {
+            Ok(p) => p,
+            Err(_) => {
+                warn!("[ID{}]同时运行的CGI程序已达到上限{}，返回503", id, config.cgi().max_processes());
+                return Self::response_with_code(503, request, id, config);
+            }
+        };
+        let (root, base) = config.document_root(request);
+        let location = ScriptLocation::new(Path::new(path), root, base, request.path());
+        let script = match fs::canonicalize(path) {
+            Ok(s) => s,
+            Err(e) => {
+                error!("[ID{}]无法确定CGI脚本{}的绝对路径：{}", id, path, e);
+                return Self::response_500(request, id, config);
+            }
+        };
+        let env = cgi::build_env(request, config, &script, &location);
+        let result = cgi::execute(invocation, &script, &env, request.body().clone(), config.cgi().timeout(), id).await
+            .and_then(|output| cgi::parse_response(&output));
+        match result {
+            Ok(cgi) => {
+                let headonly = request.method() == HttpRequestMethod::Head;
+                Self::from_cgi(cgi, request.accept_encoding().to_vec(), id, headonly, None)
+                    .set_date()
+                    .set_version()
+                    .set_server_name()
+                    .to_owned()
+            },
+            Err(Exception::GatewayTimeout) => Self::response_with_code(504, request, id, config),
+            Err(e) => {
+                error!("[ID{}]执行CGI脚本{}时出错：{}", id, path, e);
+                Self::response_500(request, id, config)
+            }
+        }
+    }
+
+    /// 将PHP脚本转发给FastCGI服务器执行，生成一个`Response`
+    /// 
+    /// ## 参数
+    /// - `client`: FastCGI客户端
+    /// - `path`: 脚本的完整路径
+    /// - `request`: 来自浏览器的`request`
+    /// - `id`: 用于日志的TCP连接编号
+    /// - `config`: Web服务器配置
+    /// 
+    /// ## 返回
+    /// - HTTP响应。响应体从FastCGI服务器流式转发。无法连接或响应不合法时返回502，超时返回504
+    async fn from_fastcgi(client: &Arc<FastCgiClient>, path: &str, request: &Request, id: u128, config: &Config) -> Self {
+        let (root, base) = config.document_root(request);
+        let location = ScriptLocation::new(Path::new(path), root, base, request.path());
+        let script = match fs::canonicalize(path) {
+            Ok(s) => s,
+            Err(e) => {
+                error!("[ID{}]无法确定脚本{}的绝对路径：{}", id, path, e);
+                return Self::response_500(request, id, config);
+            }
+        };
+        let env = cgi::build_env(request, config, &script, &location);
+        let (sender, body) = BodyStream::channel(16);
+        match client.request(&env, request.body(), sender, id).await {
+            Ok(cgi) => {
+                let headonly = request.method() == HttpRequestMethod::Head;
+                Self::from_cgi(cgi, Vec::new(), id, headonly, Some(body))
+                    .set_date()
+                    .set_version()
+                    .set_server_name()
+                    .to_owned()
+            },
+            Err(e) => {
+                error!("[ID{}]FastCGI服务器{}处理脚本{}时出错：{}", id, client.address(), path, e);
+                let code = match e {
+                    Exception::GatewayTimeout => 504,
+                    _ => 502,
+                };
+                Self::response_with_code(code, request, id, config)
+            }
+        }
+    }
+
+    /// 将请求转发给上游服务器，生成一个`Response`
+    /// 
+    /// ## 参数
+    /// - `location`: 反向代理的配置
+    /// - `request`: 来自浏览器的`request`
+    /// - `id`: 用于日志的TCP连接编号
+    /// - `config`: Web服务器配置
+    /// 
+    /// ## 返回
+    /// - HTTP响应。响应体从上游服务器流式转发，不进行压缩。无法连接或响应不合法时返回502，超时返回504
+    pub async fn from_proxy(location: &ProxyConfig, request: &Request, id: u128, config: &Config) -> Self {
+        // `upstream`可以是上游组的名称，此时按照负载均衡策略选择后端
+        let backend = match config.upstream_group(location.upstream()) {
+            Some(group) => match group.select(request.remote_addr().map(|a| a.ip())) {
+                Some(b) => Some(b),
+                None => {
+                    error!("[ID{}]上游组{}中没有可用的后端", id, group.name());
+                    return Self::response_with_code(502, request, id, config);
+                }
+            },
+            None => None,
+        };
+        let address = match &backend {
+            Some(b) => b.address().to_string(),
+            None => location.upstream().to_string(),
+        };
+        debug!("[ID{}]转发给上游服务器{}", id, address);
+        let (sender, body) = BodyStream::channel(16);
+        let (upstream, upgrade) = match proxy::forward(&address, location.timeout(), request, sender, backend, id).await {
+            Ok(u) => u,
+            Err(e) => {
+                error!("[ID{}]反向代理到{}时出错：{}", id, address, e);
+                let code = match e {
+                    Exception::GatewayTimeout => 504,
+                    _ => 502,
+                };
+                return Self::response_with_code(code, request, id, config);
+            }
+        };
+        let mut response = Self::new();
+        response.allow = None;
+        response.dynamic = upgrade.is_none();
+        response.set_status(upstream.status, Some(&upstream.reason));
+        // HEAD请求和304响应没有响应体，但上游的Content-Length仍然表示资源的长度，需要原样转发
+        let bodiless = request.method() == HttpRequestMethod::Head || upstream.status == 304;
+        let mut has_body = !bodiless && upstream.status != 204;
+        if let Some(upgrade) = upgrade {
+            debug!("[ID{}]上游服务器{}接受了WebSocket升级", id, address);
+            has_body = false;
+            response.add_header("Upgrade", "websocket")
+                .add_header("Connection", "Upgrade");
+            response.upgrade = Some(PendingUpgrade::new(upgrade));
+        }
+        for (name, value) in upstream.headers {
+            match name.to_lowercase().as_str() {
+                "content-type" => response.content_type = Some(value),
+                // 上游明确给出了空的响应体
+                "content-length" if value == "0" => has_body = false,
+                "content-length" if bodiless => response.content_length = value.trim().parse().unwrap_or(0),
+                // 以下响应头由服务器自己生成
+                "content-length" | "date" | "server" => {},
+                _ => response.headers.push((name, value)),
+            }
+        }
+        if has_body {
+            response.body_stream = Some(body);
+        } else {
+            // 没有响应体时不能带有Content-Type，见`as_bytes`
+            if let Some(content_type) = response.content_type.take() {
+                response.headers.push(("Content-Type".to_string(), content_type));
+            }
+        }
+        response.set_date()
+            .set_version()
+            .set_server_name()
+            .to_owned()
+    }
+
+    /// 完成WebSocket握手，生成101响应。响应写回之后，连接交给`handler`处理
+    /// 
+    /// ## 参数
+    /// - `handler`: 处理该路径的WebSocket处理器
+    /// - `request`: 升级请求
+    /// - `id`: 用于日志的TCP连接编号
+    /// - `config`: Web服务器配置类型
+    /// 
+    /// ## 返回
+    /// - 101响应；请求不合法时返回400，协议版本不受支持时返回426并给出支持的版本
+    pub fn from_websocket(handler: &Arc<dyn WebSocketHandler>, request: &Request, id: u128, config: &Config) -> Self {
+        if request.method() != HttpRequestMethod::Get {
+            return Self::response_with_code(405, request, id, config);
+        }
+        if request.header("sec-websocket-version") != Some(WEBSOCKET_VERSION) {
+            warn!("[ID{}]不支持的WebSocket版本{:?}", id, request.header("sec-websocket-version"));
+            let mut response = Self::response_with_code(426, request, id, config);
+            response.add_header("Sec-WebSocket-Version", WEBSOCKET_VERSION);
+            return response;
+        }
+        let key = match request.header("sec-websocket-key") {
+            Some(k) if websocket::is_valid_key(k) => k,
+            _ => {
+                warn!("[ID{}]WebSocket握手缺少合法的Sec-WebSocket-Key", id);
+                return Self::response_with_code(400, request, id, config);
+            }
+        };
+        let mut response = Self::new();
+        response.allow = None;
+        response.set_status(101, None);
+        response.add_header("Upgrade", "websocket")
+            .add_header("Connection", "Upgrade")
+            .add_header("Sec-WebSocket-Accept", &websocket::accept_key(key));
+        response.upgrade = Some(PendingUpgrade::new(Upgrade::Handler(Arc::clone(handler))));
+        response.set_date()
+            .set_version()
+            .set_server_name()
+            .to_owned()
+    }
+
+    /// 处理SSE路径的请求
+    /// 
+    /// - GET：订阅频道，返回保持打开的`text/event-stream`响应。`Last-Event-ID`头之后的历史事件会被补发
+    /// - POST：只接受来自本机的请求，将请求体作为事件数据发布到频道，`?event=`参数指定事件类型
+    /// 
+    /// ## 参数
+    /// - `channel`: 频道名称
+    /// - `request`: 来自浏览器的`request`
+    /// - `id`: 用于日志的TCP连接编号
+    /// - `config`: Web服务器配置类型
+    pub fn from_sse(channel: &str, request: &Request, id: u128, config: &Config) -> Self {
+        let hub = config.event_hub();
+        match request.method() {
+            HttpRequestMethod::Get => {},
+            HttpRequestMethod::Post => {
+                if !request.remote_addr().is_some_and(|a| a.ip().is_loopback()) {
+                    warn!("[ID{}]拒绝来自{:?}的SSE发布请求", id, request.remote_addr());
+                    return Self::response_with_code(403, request, id, config);
+                }
+                let data = match str::from_utf8(request.body()) {
+                    Ok(d) => d,
+                    Err(_) => return Self::response_with_code(400, request, id, config),
+                };
+                let event = request.query_param("event");
+                let event_id = hub.publish(channel, event.as_deref(), data);
+                debug!("[ID{}]向SSE频道{}发布了事件{}", id, channel, event_id);
+                return Self::response_with_code(200, request, id, config);
+            },
+            _ => return Self::response_with_code(405, request, id, config),
+        }
+        let last_event_id = request.header("last-event-id").and_then(|i| i.trim().parse().ok());
+        let subscription = hub.subscribe(channel, last_event_id);
+        debug!("[ID{}]订阅SSE频道{}，Last-Event-ID：{:?}", id, channel, last_event_id);
+        let (sender, body) = BodyStream::channel(16);
+        tokio::spawn(sse::pump(subscription, config.sse().keep_alive(), config.sse().retry(), sender, id));
+
+        let mut response = Self::new();
+        response.allow = None;
+        response.content_type = Some("text/event-stream; charset=utf-8".to_string());
+        response.body_stream = Some(body);
+        // 禁止缓存，并提示中间的代理服务器不要缓冲响应
+        response.add_header("Cache-Control", "no-cache")
+            .add_header("X-Accel-Buffering", "no");
+        response.set_date()
+            .set_version()
+            .set_server_name()
+            .to_owned()
+    }
+
+    /// 通过缓存的响应生成一个`Response`
+    /// 
+    /// ## 参数
+    /// - `entry`: 缓存项
+    /// - `headonly`: 是否为HEAD请求
+    /// - `cache_status`: `X-Cache`头的值，如`HIT`、`STALE`
+    pub fn from_cached(entry: &CacheEntry, headonly: bool, cache_status: &str) -> Self {
+        let mut response = Self::new();
+        response.allow = None;
+        response.set_status(entry.status, Some(&entry.reason));
+        response.headers = entry.headers.clone();
+        response.add_header("Age", &entry.age().to_string())
+            .add_header("X-Cache", cache_status);
+        response.content_length = entry.body.len() as u64;
+        if !headonly && !entry.body.is_empty() {
+            response.content_type = entry.content_type.clone();
+            response.content_encoding = entry.content_encoding;
+            response.content = Some(entry.body.clone());
+        }
+        response.set_date()
+            .set_version()
+            .set_server_name()
+            .to_owned()
+    }
+
+    /// 添加一个响应头
+    pub fn add_header(&mut self, name: &str, value: &str) -> &mut Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// 在发送响应体的同时收集响应体，完整发送之后以完整的响应体调用`on_complete`
+    /// 
+    /// 响应体超过`limit`字节、生成响应体时出错，或者浏览器提前断开时，不会调用`on_complete`
+    pub fn tee_body<F: FnOnce(Bytes) + Send + 'static>(&mut self, limit: usize, on_complete: F) {
+        if let Some(content) = &self.content {
+            if content.len() <= limit {
+                on_complete(content.clone());
+            }
+            return;
+        }
+        let mut receiver = match self.body_stream.as_ref().and_then(|s| s.take()) {
+            Some(r) => r,
+            None => {
+                on_complete(Bytes::new());
+                return;
+            }
+        };
+        let (sender, body) = BodyStream::channel(16);
+        tokio::spawn(async move {
+            let mut collected = Some(Vec::new());
+            while let Some(chunk) = receiver.recv().await {
+                match (&chunk, collected.as_mut()) {
+                    (Ok(data), Some(buffer)) if buffer.len() + data.len() <= limit => buffer.extend_from_slice(data),
+                    _ => collected = None,
+                }
+                if sender.send(chunk).await.is_err() {
+                    return;
+                }
+            }
+            if let Some(buffer) = collected {
+                on_complete(Bytes::from(buffer));
+            }
+        });
+        self.body_stream = Some(body);
+    }
+
     /// 设定时间为当前时刻
     fn set_date(&mut self) -> &mut Self {
         self.date = Utc::now();
         self
     }
 
-    /// 设置响应协议版本，当前固定为HTTP1.1
+    /// 设置响应协议版本为HTTP/1.1。HTTP/1.0请求的响应在写回之前通过`set_http_version`修改
     fn set_version(&mut self) -> &mut Self {
         self.version = HttpVersion::V1_1;
         self
     }
 
+    /// 设置响应协议版本，决定状态行和流式响应体的传输方式
+    pub fn set_http_version(&mut self, version: HttpVersion) -> &mut Self {
+        self.version = version;
+        self
+    }
+
     /// 设置服务器名
     fn set_server_name(&mut self) -> &mut Self {
         self.server_name = SERVER_NAME.to_string();
@@ -344,10 +880,72 @@ impl Response {
         self
     }
 
+    /// 设置状态码和状态说明。与`set_code`不同，本函数接受`STATUS_CODES`之外的状态码，用于处理来自CGI程序等外部来源的状态码
+    /// 
+    /// ## 参数：
+    /// - `code`: 状态码
+    /// - `reason`: 状态说明。为`None`时从`STATUS_CODES`中查找
+    fn set_status(&mut self, code: u16, reason: Option<&str>) -> &mut Self {
+        self.status_code = code;
+        self.information = match reason {
+            Some(r) => r.to_string(),
+            None => STATUS_CODES.get(&code).copied().unwrap_or("Unknown").to_string(),
+        };
+        self
+    }
+
+    /// 通过状态码生成一个完整的`Response`，优先使用配置的自定义错误页面
+    pub fn response_with_code(code: u16, request: &Request, id: u128, config: &Config) -> Self {
+        Self::from_error_page(code, request, id, config)
+            .set_date()
+            .set_code(code)
+            .set_version()
+            .set_server_name()
+            .to_owned()
+    }
+
+    /// 将普通HTTP请求重定向到HTTPS。GET和HEAD使用301，其他方法使用308，以保留请求方法和请求体
+    pub fn https_redirect(request: &Request, id: u128, config: &Config) -> Self {
+        let host = request.header("host").unwrap_or("localhost");
+        // Host头中的端口是HTTP端口，需要替换为HTTPS端口
+        let host = match host.rsplit_once(':') {
+            Some((name, port)) if port.chars().all(|c| c.is_ascii_digit()) => name,
+            _ => host,
+        };
+        let location = match config.tls().map(|t| t.port()) {
+            Some(443) | None => format!("https://{}{}", host, request.target()),
+            Some(port) => format!("https://{}:{}{}", host, port, request.target()),
+        };
+        debug!("[ID{}]重定向到{}", id, location);
+        let code = match request.method() {
+            HttpRequestMethod::Get | HttpRequestMethod::Head => 301,
+            _ => 308,
+        };
+        let mut response = Self::response_with_code(code, request, id, config);
+        response.add_header("Location", &location);
+        response
+    }
+
+    /// 无法解析的请求的Response。此时没有`Request`可用，只能使用内置的错误页面
+    /// 
+    /// 不支持的HTTP版本返回505，不支持的请求方法返回501，其他错误返回400
+    pub fn from_parse_error(exception: Exception, id: u128) -> Self {
+        let code = match exception {
+            Exception::UnsupportedHttpVersion => 505,
+            Exception::UnSupportedRequestMethod => 501,
+            _ => 400,
+        };
+        Self::from_status_code(code, Vec::new(), id)
+            .set_date()
+            .set_code(code)
+            .set_version()
+            .set_server_name()
+            .to_owned()
+    }
+
     /// 预设的404 Response
-    pub fn response_404(request: &Request, id: u128) -> Self {
-        let accept_encoding = request.accept_encoding().to_vec();
-        Self::from_status_code(404, accept_encoding, id)
+    pub fn response_404(request: &Request, id: u128, config: &Config) -> Self {
+        Self::from_error_page(404, request, id, config)
             .set_date()
             .set_code(404)
             .set_version()
@@ -355,9 +953,8 @@ impl Response {
     }
 
     /// 预设的500 Response
-    pub fn response_500(request: &Request, id: u128) -> Self {
-        let accept_encoding = request.accept_encoding().to_vec();
-        Self::from_status_code(500, accept_encoding, id)
+    pub fn response_500(request: &Request, id: u128, config: &Config) -> Self {
+        Self::from_error_page(500, request, id, config)
             .set_date()
             .set_code(500)
             .set_version()
@@ -371,19 +968,21 @@ impl Response {
     /// - `request`: 来自浏览器的`request`
     /// - `id`: 用于日志的TCP连接编号
     /// - `cache`: 共享的文件缓存指针
+    /// - `config`: Web服务器配置
     /// 
     /// ## 返回
     /// - HTTP响应
-    pub fn from(path: &str, request: &Request, id: u128, cache: &Arc<Mutex<FileCache>>) -> Response {
+    pub async fn from(path: &str, request: &Request, id: u128, cache: &Arc<Mutex<FileCache>>, config: &Config) -> Response {
         let accept_encoding = request.accept_encoding().to_vec();
         let method = request.method();
         let metadata_result = fs::metadata(path);
 
-        // 仅有下列方法得到支持，其他方法一律返回405
+        // 仅有下列方法得到支持，其他方法一律返回405。POST只能用于CGI脚本，在下面单独检查
         if method != HttpRequestMethod::Get
             && method != HttpRequestMethod::Head
-            && method != HttpRequestMethod::Options {
-            return Self::from_status_code(405, accept_encoding, id)
+            && method != HttpRequestMethod::Options
+            && method != HttpRequestMethod::Post {
+            return Self::from_error_page(405, request, id, config)
                 .set_date()
                 .set_version()
                 .set_server_name()
@@ -410,46 +1009,66 @@ impl Response {
             _ => false,
         };
 
+        // CGI脚本可以处理所有支持的请求方法
+        if metadata_result.as_ref().is_ok_and(|m| m.is_file()) {
+            if let Some(client) = config.fastcgi().filter(|_| cgi::is_fastcgi(Path::new(path), request.path(), config)) {
+                debug!("[ID{}]请求的文件是PHP脚本，转发给FastCGI服务器{}", id, client.address());
+                return Self::from_fastcgi(client, path, request, id, config).await;
+            }
+            if let Some(invocation) = cgi::program_for(Path::new(path), request.path(), config) {
+                debug!("[ID{}]请求的文件是CGI脚本，使用{}执行", id, invocation.program);
+                return Self::from_script(&invocation, path, request, id, config).await;
+            }
+        }
+        if method == HttpRequestMethod::Post {
+            return Self::from_error_page(405, request, id, config)
+                .set_date()
+                .set_version()
+                .set_server_name()
+                .to_owned();
+        }
+
         match metadata_result {
             Ok(metadata) => {
                 if metadata.is_dir() {  // path是目录
                     debug!("[ID{}]请求的路径是目录", id);
-                    Self::from_dir(path, accept_encoding, id, cache, headonly)
+                    let listing = request.location()
+                        .and_then(|l| l.autoindex())
+                        .unwrap_or_else(|| config.vhost_for(request.header("host")).listing());
+                    if !listing {
+                        debug!("[ID{}]虚拟主机或location不允许列出目录，返回403", id);
+                        return Self::from_error_page(403, request, id, config)
+                            .set_date()
+                            .set_version()
+                            .set_server_name()
+                            .to_owned();
+                    }
+                    let archive_format = request.query_param("archive")
+                        .and_then(|f| ArchiveFormat::from_query(&f))
+                        .filter(|_| config.archive().enabled());
+                    if let Some(format) = archive_format {
+                        debug!("[ID{}]请求将目录打包为{}", id, format.extension());
+                        return Self::from_archive(path, request, id, config, format, headonly).await
+                            .set_date()
+                            .set_version()
+                            .set_server_name()
+                            .to_owned();
+                    }
+                    Self::from_dir(path, request, id, headonly, config)
                         .set_date()
-                        .set_code(200)
                         .set_version()
                         .set_server_name()
                         .to_owned()
                 } else {    // path是文件
                     debug!("[ID{}]请求的路径是文件", id);
-                    let extention = match Path::new(path).extension() {
-                        Some(e) => e,
-                        None => {
-                            error!("[ID{}]无法确定请求路径{}的文件扩展名", id, path);
-                            return Self::response_404(request, id);
-                        }
-                    };
-                    debug!("[ID{}]文件扩展名: {}", id, extention.to_str().unwrap());
-                    // 特殊情况：文件扩展名是PHP
-                    if extention == "php" {
-                        debug!("[ID{}]请求的文件是PHP，启用PHP处理", id);
-                        let html = match handle_php(path, id) {
-                            Ok(html) => html,
-                            Err(e) => {
-                                error!("[ID{}]解析PHP文件{}时出错：{}", id, path, e);
-                                return Self::response_500(request, id);
-                            }
-                        };
-                        return Self::from_html(&html, accept_encoding, id, headonly)
-                            .set_date()
-                            .set_code(200)
-                            .set_version()
-                            .set_server_name()
-                            .to_owned();
+                    // 特殊情况：文件是脚本，但是没有可用的解释器。此时不能把脚本源码发给浏览器
+                    if cgi::is_disabled_script(Path::new(path), config) {
+                        warn!("[ID{}]请求了脚本{}，但是没有配置可用的解释器，返回403", id, path);
+                        return Self::response_with_code(403, request, id, config);
                     }
-                    let mime = get_mime(extention);
+                    let mime = config.mime().lookup(Path::new(path));
                     debug!("[ID{}]MIME类型: {}", id, mime);
-                    Self::from_file(path, accept_encoding, id, cache, headonly, mime)
+                    Self::from_file(path, accept_encoding, id, cache, headonly, &mime)
                         .set_date()
                         .set_code(200)
                         .set_version()
@@ -459,79 +1078,101 @@ impl Response {
             }
             Err(_) => {
                 warn!("[ID{}]无法获取{}的元数据，产生500 response", id, path);
-                Self::response_500(request, id)
+                Self::response_500(request, id, config)
             }
         }
     }
 
-    /// 将一个 `Response` 对象转换为字节流
+    /// 将一个 `Response` 写回浏览器。如果有流式响应体，则在写出响应头后逐块发送响应体
+    /// 
+    /// HTTP/1.0不支持分块传输编码，流式响应体直接写出，以关闭连接表示结束
+    pub async fn write_to<W: AsyncWrite + Unpin>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(&self.as_bytes()).await?;
+        if let Some(mut receiver) = self.body_stream.as_ref().and_then(|s| s.take()) {
+            let chunked = self.version != HttpVersion::V1_0;
+            while let Some(chunk) = receiver.recv().await {
+                let chunk = chunk?;
+                if !chunked {
+                    writer.write_all(&chunk).await?;
+                    writer.flush().await?;
+                    continue;
+                }
+                // 空的数据块会被浏览器当作响应体的结尾，因此跳过
+                if chunk.is_empty() {
+                    continue;
+                }
+                writer.write_all(format!("{:X}{}", chunk.len(), CRLF).as_bytes()).await?;
+                writer.write_all(&chunk).await?;
+                writer.write_all(CRLF.as_bytes()).await?;
+                writer.flush().await?;
+            }
+            if chunked {
+                writer.write_all(["0", CRLF, CRLF].concat().as_bytes()).await?;
+            }
+        }
+        writer.flush().await
+    }
+
+    /// 生成与HTTP版本无关的响应头列表，不包含HTTP/1.1专有的`Transfer-Encoding`
+    ///
+    /// 流式响应体的长度是未知的，不带有`Content-Length`。1xx响应同样不能带有`Content-Length`
+    pub fn header_fields(&self) -> Vec<(String, String)> {
+        let mut fields = Vec::new();
+        // 选择性地填入content_type
+        if let Some(t) = &self.content_type {
+            fields.push(("Content-Type".to_string(), t.clone()));
+        }
+        // 选择性地填入content_encoding
+        if let Some(e) = self.content_encoding {
+            let encoding = match e {
+                HttpEncoding::Gzip => "gzip",
+                HttpEncoding::Deflate => "deflate",
+                HttpEncoding::Br => "br",
+            };
+            fields.push(("Content-encoding".to_string(), encoding.to_string()));
+        }
+        if self.body_stream.is_none() && self.status_code >= 200 {
+            fields.push(("Content-Length".to_string(), self.content_length.to_string()));
+        }
+        fields.push(("Date".to_string(), format_date(&self.date)));
+        fields.push(("Server".to_string(), self.server_name.clone()));
+        // 选择性地填入allow
+        if let Some(a) = &self.allow {
+            let allow_str = a.iter().map(|m| m.to_string()).collect::<Vec<_>>().join(", ");
+            fields.push(("Allow".to_string(), allow_str));
+        }
+        // 其他响应头
+        fields.extend(self.headers.iter().cloned());
+        fields
+    }
+
+    /// 将一个 `Response` 对象转换为HTTP/1.1字节流。流式响应体不包含在内，见`write_to`
     pub fn as_bytes(&self) -> Vec<u8> {
         // 如果content字段是None，那么content-type和content-encoding也必须是None
-        if self.content == None {
+        if self.content.is_none() && self.body_stream.is_none() {
             assert_eq!(self.content_encoding, None);
             assert_eq!(self.content_type, None);
         }
-        // 获取各字段的&str
-        let version: &str = match self.version {
-            HttpVersion::V1_1 => "HTTP/1.1",
+        // HTTP/2和HTTP/3的响应不经过这里
+        let version = match self.version {
+            HttpVersion::V1_0 => "HTTP/1.0",
+            HttpVersion::V1_1 | HttpVersion::V2 | HttpVersion::V3 => "HTTP/1.1",
         };
-        let status_code: &str = &self.status_code.to_string();
-        let information: &str = &self.information;
-        let content_length: &str = &self.content_length.to_string();
-        let date: &str = &format_date(&self.date);
-        let server: &str = &self.server_name;
-
-        // 拼接响应
-        let header = [
-            version, " ", status_code, " ", information, CRLF,
-            // 选择性地填入content_type
-            match &self.content_type {
-                Some(t) => {
-                    ["Content-Type: ", &t, CRLF].concat()
-                },
-                None => "".to_string(),
-            }.as_str(),
-            // 选择性地填入content_encoding
-            match self.content_encoding {
-                Some(e) => {
-                    [
-                        "Content-encoding: ",
-                        match e {
-                            HttpEncoding::Gzip => "gzip",
-                            HttpEncoding::Deflate => "deflate",
-                            HttpEncoding::Br => "br",
-                        },
-                        CRLF,
-                    ].concat().to_string()
-                },
-                None => "".to_string(),
-            }.as_str(),
-            "Content-Length: ", content_length, CRLF,
-            "Date: ", date, CRLF,
-            "Server: ", server, CRLF,
-            // 选择性地填入allow
-            match &self.allow {
-                Some(a) => {
-                    let mut allow_str = String::new();
-                    for (index, method) in a.iter().enumerate() {
-                        allow_str.push_str(&format!("{}", method));
-                        // 如果后面还有，就加一个逗号分隔
-                        if index < a.len() - 1 {
-                            allow_str.push_str(", ");
-                        }
-                    }
-                    ["Allow: ", &allow_str, CRLF].concat()
-                },
-                None => "".to_string(),
-            }.as_str(),
-            CRLF,   // 分隔响应头和响应体的空行
-        ].concat();
+        let mut header = [version, " ", &self.status_code.to_string(), " ", &self.information, CRLF].concat();
+        // HTTP/1.1的流式响应体使用分块传输编码
+        if self.body_stream.is_some() && self.version != HttpVersion::V1_0 {
+            header.push_str(&["Transfer-Encoding: chunked", CRLF].concat());
+        }
+        for (name, value) in self.header_fields() {
+            header.push_str(&[name.as_str(), ": ", value.as_str(), CRLF].concat());
+        }
+        header.push_str(CRLF);   // 分隔响应头和响应体的空行
         // 然后拼接响应体，注意响应体可能有压缩，因此需要以Vec<u8>格式拼接，而不是上面的String。
         [
             header.as_bytes(),
             // 选择性地填入响应体
             match &self.content {
-                Some(c) => &c,
+                Some(c) => c,
                 None => b"",
             },
         ].concat()
@@ -543,9 +1184,49 @@ impl Response {
         self.status_code
     }
 
+    /// 是否是动态响应
+    pub fn is_dynamic(&self) -> bool {
+        self.dynamic
+    }
+
+    /// 返回其他响应头
+    pub fn headers(&self) -> &[(String, String)] {
+        &self.headers
+    }
+
+    /// 返回响应体的MIME类型
+    pub fn content_type(&self) -> Option<&str> {
+        self.content_type.as_deref()
+    }
+
+    /// 返回响应体的压缩编码
+    pub fn content_encoding(&self) -> Option<HttpEncoding> {
+        self.content_encoding
+    }
+
     pub fn information(&self) -> &str {
         &self.information
     }
+
+    /// 是否有流式响应体
+    pub fn is_streaming(&self) -> bool {
+        self.body_stream.is_some()
+    }
+
+    /// 返回非流式的响应体
+    pub fn content(&self) -> Option<&Bytes> {
+        self.content.as_ref()
+    }
+
+    /// 取出流式响应体的接收端，只有第一次调用会返回`Some`
+    pub fn take_body_stream(&self) -> Option<Receiver<io::Result<Bytes>>> {
+        self.body_stream.as_ref().and_then(|s| s.take())
+    }
+
+    /// 取出101响应写回之后接管连接的操作，只有第一次调用会返回`Some`
+    pub fn take_upgrade(&self) -> Option<Upgrade> {
+        self.upgrade.as_ref().and_then(|u| u.take())
+    }
 }
 
 /// 格式化时间，使用`chrono` crate自带的`to_rfc2822`方法
@@ -593,7 +1274,7 @@ fn compress(data: Vec<u8>, mode: Option<HttpEncoding>) -> io::Result<Vec<u8>> {
 /// 4. 再否则，就只好不压缩了。
 /// 
 /// 实测Brotli太慢，因此优先用Gzip。考虑后期换一个brotli库。
-fn decide_encoding(accept_encoding: &Vec<HttpEncoding>) -> Option<HttpEncoding> {
+fn decide_encoding(accept_encoding: &[HttpEncoding]) -> Option<HttpEncoding> {
     if accept_encoding.contains(&HttpEncoding::Gzip) {
         Some(HttpEncoding::Gzip)
     } else if accept_encoding.contains(&HttpEncoding::Deflate) {
@@ -603,21 +1284,43 @@ fn decide_encoding(accept_encoding: &Vec<HttpEncoding>) -> Option<HttpEncoding>
     }
 }
 
-/// MIME
-/// 
-/// 保存了常见文件类型的映射关系
-/// 
-/// <https://developer.mozilla.org/en-US/docs/Web/HTTP/Basics_of_HTTP/MIME_types/Common_types>
-fn get_mime(extension: &OsStr) -> &str {
-    let extension = match extension.to_str() {
-        Some(e) => e,
-        None => {
-            error!("无法将&OsStr转换为&str类型");
-            return "application/octet-stream";
-        }
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
     };
-    match MIME_TYPES.get(extension) {
-        Some(v) => v,
-        None => "application/octet-stream",
+
+    use super::*;
+
+    /// 对每个连接返回固定响应头的上游服务器
+    async fn stub_upstream(listener: TcpListener, head: &'static str) {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let mut buffer = [0; 1024];
+            let _ = stream.read(&mut buffer).await;
+            let _ = stream.write_all(head.as_bytes()).await;
+        }
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn test_proxy_bodiless_content_length() {
+        let config = Config::new();
+        let cases = [
+            ("HEAD", "HTTP/1.1 200 OK\r\nContent-Length: 1234\r\nContent-Type: text/plain\r\n\r\n"),
+            ("GET", "HTTP/1.1 304 Not Modified\r\nContent-Length: 1234\r\nETag: \"a\"\r\n\r\n"),
+        ];
+        for (method, head) in cases {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let address = listener.local_addr().unwrap().to_string();
+            tokio::spawn(stub_upstream(listener, head));
+
+            let location = ProxyConfig::new("/", &address, 5);
+            let headers = vec![("Host".to_string(), "localhost".to_string())];
+            let request = Request::from_parts(method, "/file", HttpVersion::V1_1, headers, 0).unwrap();
+            let response = Response::from_proxy(&location, &request, 0, &config).await;
+            assert!(response.body_stream.is_none());
+            let fields = response.header_fields();
+            assert!(fields.contains(&("Content-Length".to_string(), "1234".to_string())), "{}", method);
+        }
+    }
+}
@@ -0,0 +1,250 @@
+use regex::{Captures, Regex};
+
+use crate::{
+    config::RewriteConfig,
+    request::Request,
+    util::{percent_encode_path, strip_port, wildcard_matches},
+};
+
+/// 一个请求最多经过的内部重写次数，超过时认为规则之间形成了循环
+pub const MAX_REWRITES: usize = 10;
+
+/// 重写规则匹配后的结果
+///
+/// - `Redirect`: 返回重定向响应，`location`是`Location`响应头的值
+/// - `Loop`: 内部重写的次数超过了`MAX_REWRITES`
+/// - `Invalid`: 替换结果含有空格或控制字符，不能作为请求目标
+#[derive(Debug, Clone, PartialEq)]
+pub enum Outcome {
+    Redirect { status: u16, location: String },
+    Loop,
+    Invalid,
+}
+
+/// ### 重写规则 RewriteRule
+///
+/// 由`[[rewrite]]`构建的运行时对象，保存编译好的正则表达式
+#[derive(Debug, Clone)]
+pub struct RewriteRule {
+    pattern: Regex,
+    query: Option<Regex>,
+    config: RewriteConfig,
+}
+
+impl RewriteRule {
+    /// 通过配置构建重写规则，配置无效时返回错误说明
+    pub fn new(config: &RewriteConfig) -> Result<Self, String> {
+        let pattern = Regex::new(config.pattern())
+            .map_err(|e| format!("无效的正则表达式{}：{}", config.pattern(), e))?;
+        let query = match config.query() {
+            Some(q) => Some(Regex::new(q).map_err(|e| format!("无效的正则表达式{}：{}", q, e))?),
+            None => None,
+        };
+        if !is_valid_target(config.replacement()) {
+            return Err(format!("替换结果{}含有空格或控制字符", config.replacement()));
+        }
+        if !matches!(config.status(), 0 | 301 | 302 | 307 | 308) {
+            return Err(format!("不支持的重定向状态码{}，只能是301、302、307或308", config.status()));
+        }
+        Ok(Self { pattern, query, config: config.clone() })
+    }
+
+    /// 检查请求是否满足规则的条件，满足时返回替换后的请求目标
+    fn apply(&self, request: &Request) -> Option<String> {
+        let host = request.header("host").map(|h| strip_port(h).trim_end_matches('.').to_lowercase());
+        let hosts = self.config.hosts();
+        if !hosts.is_empty() && !host.is_some_and(|h| hosts.iter().any(|n| n.eq_ignore_ascii_case(&h) || wildcard_matches(&n.to_lowercase(), &h))) {
+            return None;
+        }
+        let methods = self.config.methods();
+        if !methods.is_empty() && !methods.contains(&request.method()) {
+            return None;
+        }
+        if self.query.as_ref().is_some_and(|q| !q.is_match(request.query())) {
+            return None;
+        }
+        let captures = self.pattern.captures(request.path())?;
+        let mut target = expand(&captures, self.config.replacement());
+        // 替换结果中没有查询字符串时，保留原来的查询字符串
+        if !target.contains('?') && !request.query().is_empty() {
+            target.push('?');
+            target.push_str(request.query());
+        }
+        Some(target)
+    }
+}
+
+/// 展开替换结果中的`$1`、`${name}`等引用，`$$`代表`$`
+///
+/// 捕获组取自已经百分号解码的请求路径，因此在填入时重新进行百分号编码（保留`/`），
+/// 使替换结果仍然是编码后的请求目标，其中的`%0D%0A`等不会变成真正的控制字符
+fn expand(captures: &Captures, replacement: &str) -> String {
+    let mut target = String::with_capacity(replacement.len());
+    let mut rest = replacement;
+    while let Some(position) = rest.find('$') {
+        target.push_str(&rest[..position]);
+        rest = &rest[position + 1..];
+        let (name, after) = match rest.strip_prefix('{').and_then(|r| r.split_once('}')) {
+            Some((name, after)) => (name, after),
+            None => {
+                let end = rest.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).unwrap_or(rest.len());
+                (&rest[..end], &rest[end..])
+            },
+        };
+        if name.is_empty() {
+            // `$$`或者后面不是引用的`$`，原样保留一个`$`
+            target.push('$');
+            rest = rest.strip_prefix('$').unwrap_or(rest);
+            continue;
+        }
+        let group = match name.parse::<usize>() {
+            Ok(index) => captures.get(index),
+            Err(_) => captures.name(name),
+        };
+        if let Some(group) = group {
+            target.push_str(&percent_encode_path(group.as_str()));
+        }
+        rest = after;
+    }
+    target.push_str(rest);
+    target
+}
+
+/// 检查请求目标中是否没有空格和控制字符
+fn is_valid_target(target: &str) -> bool {
+    !target.chars().any(|c| c == ' ' || c.is_control())
+}
+
+/// 按顺序对请求应用重写规则
+///
+/// 内部重写修改请求的路径和查询字符串，然后从第一条规则重新开始匹配。重定向规则匹配时立即停止。
+/// 没有规则匹配时，请求以当前的路径继续处理
+///
+/// ## 参数
+/// - `rules`: 重写规则
+/// - `request`: 来自浏览器的`request`
+///
+/// ## 返回
+/// - 需要返回重定向或检测到循环时返回`Some`，否则返回`None`
+pub fn apply(rules: &[RewriteRule], request: &mut Request) -> Option<Outcome> {
+    let mut rewrites = 0;
+    'restart: loop {
+        for rule in rules {
+            let target = match rule.apply(request) {
+                Some(t) => t,
+                None => continue,
+            };
+            // 原样保留的查询字符串来自客户端，同样不能含有控制字符
+            if !is_valid_target(&target) {
+                return Some(Outcome::Invalid);
+            }
+            if rule.config.status() != 0 {
+                return Some(Outcome::Redirect { status: rule.config.status(), location: target });
+            }
+            rewrites += 1;
+            if rewrites > MAX_REWRITES {
+                return Some(Outcome::Loop);
+            }
+            request.set_target(&target);
+            continue 'restart;
+        }
+        return None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::param::HttpVersion;
+
+    fn rules(toml: &str) -> Vec<RewriteRule> {
+        #[derive(serde_derive::Deserialize)]
+        struct Rules {
+            rewrite: Vec<RewriteConfig>,
+        }
+        let rules: Rules = toml::from_str(toml).unwrap();
+        rules.rewrite.iter().map(|r| RewriteRule::new(r).unwrap()).collect()
+    }
+
+    fn request(method: &str, target: &str, host: &str) -> Request {
+        let headers = vec![("Host".to_string(), host.to_string())];
+        Request::from_parts(method, target, HttpVersion::V1_1, headers, 0).unwrap()
+    }
+
+    #[test]
+    fn test_apply() {
+        let rules = rules(r#"
+            [[rewrite]]
+            pattern = '^/old/(\d+)$'
+            replacement = '/posts/$1'
+            status = 301
+            hosts = ["*.example.com"]
+
+            [[rewrite]]
+            pattern = '^/blog/(?P<slug>[^/]+)$'
+            replacement = '/index.php?post=${slug}'
+
+            [[rewrite]]
+            pattern = '^/index\.php$'
+            replacement = '/v2/index.php'
+            methods = ["POST"]
+            query = '^post='
+
+            [[rewrite]]
+            pattern = '^/loop/a$'
+            replacement = '/loop/b'
+
+            [[rewrite]]
+            pattern = '^/loop/b$'
+            replacement = '/loop/a'
+        "#);
+
+        let mut r = request("GET", "/old/42?ref=x", "www.example.com");
+        assert_eq!(apply(&rules, &mut r), Some(Outcome::Redirect { status: 301, location: "/posts/42?ref=x".to_string() }));
+        // 主机名不满足条件
+        let mut r = request("GET", "/old/42", "other.org");
+        assert_eq!(apply(&rules, &mut r), None);
+        assert_eq!(r.path(), "/old/42");
+
+        // 内部重写之后从第一条规则重新开始匹配
+        let mut r = request("POST", "/blog/hello", "other.org");
+        assert_eq!(apply(&rules, &mut r), None);
+        assert_eq!((r.path(), r.query(), r.target()), ("/v2/index.php", "post=hello", "/v2/index.php?post=hello"));
+        let mut r = request("GET", "/blog/hello", "other.org");
+        assert_eq!(apply(&rules, &mut r), None);
+        assert_eq!(r.path(), "/index.php");
+
+        let mut r = request("GET", "/loop/a", "other.org");
+        assert_eq!(apply(&rules, &mut r), Some(Outcome::Loop));
+    }
+
+    #[test]
+    fn test_encoded_captures() {
+        let rules = rules(r#"
+            [[rewrite]]
+            pattern = '^/old/([^/]+)$'
+            replacement = '/new/$1'
+            status = 301
+
+            [[rewrite]]
+            pattern = '(?s)^/api/(.+)$'
+            replacement = '/v2/${1}?cost=$$5'
+        "#);
+
+        // 解码后的CRLF不能进入Location头
+        let mut r = request("GET", "/old/x%0D%0ASet-Cookie:%20pwned=1", "example.com");
+        let location = "/new/x%0D%0ASet-Cookie%3A%20pwned%3D1".to_string();
+        assert_eq!(apply(&rules, &mut r), Some(Outcome::Redirect { status: 301, location }));
+
+        // 内部重写后，请求目标保持编码，路径只解码一次
+        let mut r = request("GET", "/api/a%2525/b%0D%0A", "example.com");
+        assert_eq!(apply(&rules, &mut r), None);
+        assert_eq!(r.target(), "/v2/a%2525/b%0D%0A?cost=$5");
+        assert_eq!(r.path(), "/v2/a%25/b\r\n");
+
+        // 原样保留的查询字符串含有控制字符
+        let mut r = request("GET", "/old/x?a\r\nb", "example.com");
+        assert_eq!(apply(&rules, &mut r), Some(Outcome::Invalid));
+        assert!(RewriteRule::new(&toml::from_str("pattern = '^/a$'\nreplacement = '/b c'").unwrap()).is_err());
+    }
+}
@@ -0,0 +1,233 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    io,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use bytes::Bytes;
+use log::{debug, warn};
+use tokio::{
+    sync::{broadcast::{self, error::RecvError}, mpsc::Sender},
+    time::{interval, MissedTickBehavior},
+};
+
+use crate::config::SseConfig;
+
+/// 每个频道的广播缓冲区大小。订阅者落后超过该数量的事件时，连接会被关闭，浏览器重连后通过`Last-Event-ID`补发
+const BROADCAST_CAPACITY: usize = 256;
+
+/// 一个事件
+///
+/// - `id`: 事件编号，在频道内从1开始递增
+/// - `event`: 事件类型，为`None`时浏览器将其当作`message`事件
+/// - `data`: 事件数据，可以有多行
+#[derive(Debug, Clone, PartialEq)]
+pub struct Event {
+    pub id: u64,
+    pub event: Option<String>,
+    pub data: String,
+}
+
+impl Event {
+    /// 按照`text/event-stream`格式编码。多行数据被拆分为多个`data`字段
+    pub fn encode(&self) -> Bytes {
+        let mut text = format!("id: {}\n", self.id);
+        if let Some(event) = &self.event {
+            text.push_str(&format!("event: {}\n", event));
+        }
+        for line in self.data.lines() {
+            text.push_str(&format!("data: {}\n", line));
+        }
+        if self.data.is_empty() {
+            text.push_str("data\n");
+        }
+        text.push('\n');
+        Bytes::from(text)
+    }
+}
+
+/// 频道的历史记录和下一个事件编号，发布和订阅都需要持有这把锁，以保证补发的事件和广播的事件之间不重复、不遗漏
+#[derive(Debug)]
+struct ChannelState {
+    next_id: u64,
+    history: VecDeque<Arc<Event>>,
+}
+
+/// 一个频道
+#[derive(Debug)]
+struct Channel {
+    sender: broadcast::Sender<Arc<Event>>,
+    state: Mutex<ChannelState>,
+}
+
+/// 一次订阅
+///
+/// - `backlog`: 需要补发的历史事件
+/// - `receiver`: 之后发布的事件
+#[derive(Debug)]
+pub struct Subscription {
+    backlog: Vec<Arc<Event>>,
+    receiver: broadcast::Receiver<Arc<Event>>,
+}
+
+/// ### 事件中心 EventHub
+///
+/// 管理Server-Sent Events的命名频道。服务器的其他部分可以通过`publish`向频道发布事件，
+/// 订阅该频道的浏览器会立即收到。每个频道保留最近的若干个事件，用于浏览器重连时按照`Last-Event-ID`补发。
+///
+/// 频道在第一次发布或订阅时创建。
+#[derive(Debug)]
+pub struct EventHub {
+    channels: Mutex<HashMap<String, Arc<Channel>>>,
+    history: usize,
+}
+
+impl EventHub {
+    pub fn new(config: &SseConfig) -> Self {
+        Self {
+            channels: Mutex::new(HashMap::new()),
+            history: config.history(),
+        }
+    }
+
+    /// 获取频道，不存在时创建
+    fn channel(&self, name: &str) -> Arc<Channel> {
+        let mut channels = self.channels.lock().unwrap();
+        let channel = channels.entry(name.to_string()).or_insert_with(|| Arc::new(Channel {
+            sender: broadcast::channel(BROADCAST_CAPACITY).0,
+            state: Mutex::new(ChannelState { next_id: 1, history: VecDeque::new() }),
+        }));
+        Arc::clone(channel)
+    }
+
+    /// 向频道发布一个事件
+    ///
+    /// ## 参数
+    /// - `channel`: 频道名称
+    /// - `event`: 事件类型，为`None`时浏览器将其当作`message`事件
+    /// - `data`: 事件数据
+    ///
+    /// ## 返回
+    /// - 事件编号
+    pub fn publish(&self, channel: &str, event: Option<&str>, data: &str) -> u64 {
+        let channel = self.channel(channel);
+        let mut state = channel.state.lock().unwrap();
+        let event = Arc::new(Event {
+            id: state.next_id,
+            event: event.map(|e| e.to_string()),
+            data: data.to_string(),
+        });
+        state.next_id += 1;
+        state.history.push_back(Arc::clone(&event));
+        while state.history.len() > self.history {
+            state.history.pop_front();
+        }
+        // 没有订阅者时发送会失败，这不是错误
+        let _ = channel.sender.send(Arc::clone(&event));
+        event.id
+    }
+
+    /// 订阅频道
+    ///
+    /// ## 参数
+    /// - `channel`: 频道名称
+    /// - `last_event_id`: 浏览器收到的最后一个事件编号。历史记录中比它新的事件会被补发
+    pub fn subscribe(&self, channel: &str, last_event_id: Option<u64>) -> Subscription {
+        let channel = self.channel(channel);
+        let state = channel.state.lock().unwrap();
+        let backlog = match last_event_id {
+            Some(last) => state.history.iter().filter(|e| e.id > last).cloned().collect(),
+            None => Vec::new(),
+        };
+        Subscription {
+            backlog,
+            receiver: channel.sender.subscribe(),
+        }
+    }
+
+    /// 生成事件中心的状态描述，用于控制台的`status`命令
+    pub fn status(&self) -> Vec<String> {
+        let channels = self.channels.lock().unwrap();
+        let mut names: Vec<_> = channels.keys().collect();
+        names.sort();
+        names.into_iter().map(|name| {
+            let channel = &channels[name];
+            let next_id = channel.state.lock().unwrap().next_id;
+            format!("SSE频道{}：{}个订阅者，已发布{}个事件", name, channel.sender.receiver_count(), next_id - 1)
+        }).collect()
+    }
+}
+
+/// 将订阅到的事件写入流式响应体，直到浏览器断开连接
+///
+/// 先发送`retry`字段和需要补发的事件，之后每收到一个事件就发送一个，空闲时定期发送注释作为心跳。
+/// 浏览器断开后，写回响应失败，响应体的接收端随之被丢弃，该函数在下一次发送（最迟是下一次心跳）时返回。
+///
+/// ## 参数
+/// - `subscription`: 订阅
+/// - `keep_alive`: 心跳间隔
+/// - `retry`: 建议浏览器的重连间隔，以毫秒为单位
+/// - `sender`: 响应体的发送端
+/// - `id`: 用于日志的TCP连接编号
+pub async fn pump(subscription: Subscription, keep_alive: Duration, retry: u64, sender: Sender<io::Result<Bytes>>, id: u128) {
+    let Subscription { backlog, mut receiver } = subscription;
+    let mut head = format!("retry: {}\n\n", retry).into_bytes();
+    for event in &backlog {
+        head.extend_from_slice(&event.encode());
+    }
+    if sender.send(Ok(Bytes::from(head))).await.is_err() {
+        return;
+    }
+    let mut ticker = interval(keep_alive);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    // interval的第一次tick立即完成
+    ticker.tick().await;
+    loop {
+        let chunk = tokio::select! {
+            event = receiver.recv() => match event {
+                Ok(event) => {
+                    ticker.reset();
+                    event.encode()
+                },
+                Err(RecvError::Lagged(n)) => {
+                    // 关闭连接，浏览器重连后可以通过Last-Event-ID补发
+                    warn!("[ID{}]SSE订阅者落后了{}个事件，关闭连接", id, n);
+                    return;
+                },
+                Err(RecvError::Closed) => return,
+            },
+            _ = ticker.tick() => Bytes::from_static(b": keep-alive\n\n"),
+            _ = sender.closed() => break,
+        };
+        if sender.send(Ok(chunk)).await.is_err() {
+            break;
+        }
+    }
+    debug!("[ID{}]SSE客户端已断开", id);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_publish_and_replay() {
+        let config: SseConfig = toml::from_str("history = 2").unwrap();
+        let hub = EventHub::new(&config);
+        assert_eq!(hub.publish("builds", None, "a"), 1);
+        assert_eq!(hub.publish("builds", Some("done"), "b\nc"), 2);
+        assert_eq!(hub.publish("builds", None, "d"), 3);
+
+        // 历史记录只保留最近2个事件
+        let subscription = hub.subscribe("builds", Some(1));
+        let ids: Vec<_> = subscription.backlog.iter().map(|e| e.id).collect();
+        assert_eq!(ids, [2, 3]);
+        assert_eq!(subscription.backlog[0].encode(), Bytes::from("id: 2\nevent: done\ndata: b\ndata: c\n\n"));
+
+        let mut subscription = hub.subscribe("builds", None);
+        assert!(subscription.backlog.is_empty());
+        hub.publish("builds", None, "e");
+        assert_eq!(subscription.receiver.try_recv().unwrap().data, "e");
+    }
+}
@@ -0,0 +1,289 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    fmt,
+    hash::{Hash, Hasher},
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Instant,
+};
+
+use log::{debug, info, warn};
+use serde_derive::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+    time::timeout,
+};
+
+use crate::{
+    config::UpstreamConfig,
+    param::{CRLF, SERVER_NAME},
+};
+
+/// 负载均衡策略
+///
+/// - `RoundRobin`: 轮询
+/// - `LeastConn`: 选择当前连接数最少的后端
+/// - `IpHash`: 按客户端IP的哈希选择后端，同一客户端总是访问同一个后端
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Strategy {
+    #[default]
+    RoundRobin,
+    LeastConn,
+    IpHash,
+}
+
+impl fmt::Display for Strategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Strategy::RoundRobin => write!(f, "round_robin"),
+            Strategy::LeastConn => write!(f, "least_conn"),
+            Strategy::IpHash => write!(f, "ip_hash"),
+        }
+    }
+}
+
+/// 上游组中的一个后端
+///
+/// - `address`: 后端地址
+/// - `active`: 当前正在处理的请求数量
+/// - `fails`: 连续失败的次数
+/// - `healthy`: 最近一次主动健康检查的结果
+/// - `ejected_until`: 因连续失败被暂时移出的截止时间
+#[derive(Debug)]
+pub struct Backend {
+    address: String,
+    active: AtomicUsize,
+    fails: AtomicU32,
+    healthy: AtomicBool,
+    ejected_until: Mutex<Option<Instant>>,
+}
+
+impl Backend {
+    fn new(address: &str) -> Self {
+        Self {
+            address: address.to_string(),
+            active: AtomicUsize::new(0),
+            fails: AtomicU32::new(0),
+            healthy: AtomicBool::new(true),
+            ejected_until: Mutex::new(None),
+        }
+    }
+
+    /// 获取后端地址
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
+    /// 后端是否可用：健康检查通过，并且没有因连续失败被移出
+    fn available(&self) -> bool {
+        let ejected = matches!(*self.ejected_until.lock().unwrap(), Some(t) if Instant::now() < t);
+        self.healthy.load(Ordering::Relaxed) && !ejected
+    }
+}
+
+/// 选中的后端。在被丢弃之前，后端的连接数计数加一，用于`LeastConn`策略
+#[derive(Debug)]
+pub struct BackendGuard {
+    group: Arc<UpstreamGroup>,
+    index: usize,
+}
+
+impl BackendGuard {
+    /// 获取后端地址
+    pub fn address(&self) -> &str {
+        self.group.backends[self.index].address()
+    }
+
+    /// 请求成功，清除连续失败计数
+    pub fn mark_success(&self) {
+        self.group.backends[self.index].fails.store(0, Ordering::Relaxed);
+    }
+
+    /// 请求失败。连续失败达到`max_fails`次时，后端在`fail_timeout`内不再被选中
+    pub fn mark_failure(&self) {
+        let backend = &self.group.backends[self.index];
+        let fails = backend.fails.fetch_add(1, Ordering::Relaxed) + 1;
+        if fails >= self.group.config.max_fails() {
+            backend.fails.store(0, Ordering::Relaxed);
+            *backend.ejected_until.lock().unwrap() = Some(Instant::now() + self.group.config.fail_timeout());
+            warn!("上游组{}的后端{}连续失败{}次，在{}秒内不再使用",
+                self.group.name, backend.address, fails, self.group.config.fail_timeout().as_secs());
+        }
+    }
+}
+
+impl Drop for BackendGuard {
+    fn drop(&mut self) {
+        self.group.backends[self.index].active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// ### 上游组 UpstreamGroup
+///
+/// 一组提供相同服务的后端，反向代理按照负载均衡策略从中选择一个后端转发请求。
+///
+/// - `name`: 上游组的名称
+/// - `config`: 上游组的配置
+/// - `backends`: 全部后端
+/// - `next`: 轮询策略的下一个位置
+#[derive(Debug)]
+pub struct UpstreamGroup {
+    name: String,
+    config: UpstreamConfig,
+    backends: Vec<Backend>,
+    next: AtomicUsize,
+}
+
+impl UpstreamGroup {
+    pub fn new(name: &str, config: &UpstreamConfig) -> Self {
+        Self {
+            name: name.to_string(),
+            config: config.clone(),
+            backends: config.servers().iter().map(|s| Backend::new(s)).collect(),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// 获取上游组的名称
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// 按照负载均衡策略选择一个可用的后端，没有可用的后端时返回`None`
+    ///
+    /// ## 参数
+    /// - `client`: 客户端的IP，用于`IpHash`策略
+    pub fn select(self: &Arc<Self>, client: Option<IpAddr>) -> Option<BackendGuard> {
+        let count = self.backends.len();
+        if count == 0 {
+            return None;
+        }
+        let index = match self.config.strategy() {
+            Strategy::RoundRobin => {
+                let start = self.next.fetch_add(1, Ordering::Relaxed);
+                (0..count).map(|i| (start + i) % count)
+                    .find(|&i| self.backends[i].available())
+            },
+            Strategy::LeastConn => {
+                (0..count).filter(|&i| self.backends[i].available())
+                    .min_by_key(|&i| self.backends[i].active.load(Ordering::Relaxed))
+            },
+            Strategy::IpHash => {
+                let mut hasher = DefaultHasher::new();
+                client.hash(&mut hasher);
+                let start = hasher.finish() as usize;
+                // 首选的后端不可用时，依次尝试后面的后端
+                (0..count).map(|i| (start + i) % count)
+                    .find(|&i| self.backends[i].available())
+            },
+        }?;
+        self.backends[index].active.fetch_add(1, Ordering::Relaxed);
+        Some(BackendGuard {
+            group: Arc::clone(self),
+            index,
+        })
+    }
+
+    /// 生成上游组的状态描述，用于控制台的`status`命令
+    pub fn status(&self) -> Vec<String> {
+        let mut lines = vec![format!("上游组{}（{}）", self.name, self.config.strategy())];
+        for backend in &self.backends {
+            let state = match (backend.healthy.load(Ordering::Relaxed), backend.available()) {
+                (false, _) => "健康检查失败",
+                (true, false) => "暂时移出",
+                (true, true) => "正常",
+            };
+            lines.push(format!("  {}: {}，连接数{}，连续失败{}次",
+                backend.address,
+                state,
+                backend.active.load(Ordering::Relaxed),
+                backend.fails.load(Ordering::Relaxed),
+            ));
+        }
+        lines
+    }
+
+    /// 主动健康检查。按照配置的间隔向每个后端请求`health_check`路径，2xx和3xx响应视为健康
+    ///
+    /// 该函数不会返回，应当在单独的任务中运行。没有配置`health_check`时直接返回
+    pub async fn health_check(self: Arc<Self>) {
+        let path = match self.config.health_check() {
+            Some(p) => p.to_string(),
+            None => return,
+        };
+        info!("开始对上游组{}进行健康检查，检查路径：{}，间隔{}秒", self.name, path, self.config.health_interval().as_secs());
+        loop {
+            for backend in &self.backends {
+                let healthy = match timeout(self.config.health_timeout(), probe(&backend.address, &path)).await {
+                    Ok(Ok(status)) => (200..400).contains(&status),
+                    Ok(Err(e)) => {
+                        debug!("上游组{}的后端{}健康检查失败：{}", self.name, backend.address, e);
+                        false
+                    },
+                    Err(_) => false,
+                };
+                let previous = backend.healthy.swap(healthy, Ordering::Relaxed);
+                match (previous, healthy) {
+                    (true, false) => warn!("上游组{}的后端{}未通过健康检查，暂停使用", self.name, backend.address),
+                    (false, true) => {
+                        backend.fails.store(0, Ordering::Relaxed);
+                        *backend.ejected_until.lock().unwrap() = None;
+                        info!("上游组{}的后端{}已恢复", self.name, backend.address);
+                    },
+                    _ => {},
+                }
+            }
+            tokio::time::sleep(self.config.health_interval()).await;
+        }
+    }
+}
+
+/// 向后端发送一次健康检查请求，返回状态码
+async fn probe(address: &str, path: &str) -> std::io::Result<u16> {
+    let stream = TcpStream::connect(address).await?;
+    let mut stream = BufReader::new(stream);
+    let request = format!("GET {} HTTP/1.1{crlf}Host: {}{crlf}User-Agent: {}{crlf}Connection: close{crlf}{crlf}",
+        path, address, SERVER_NAME, crlf = CRLF);
+    stream.write_all(request.as_bytes()).await?;
+    let mut status_line = String::new();
+    stream.read_line(&mut status_line).await?;
+    status_line.split(' ')
+        .nth(1)
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, "状态行不合法"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::UpstreamGroup;
+    use crate::config::UpstreamConfig;
+
+    #[test]
+    fn test_select_and_eject() {
+        let config: UpstreamConfig = toml::from_str("servers = [\"a:1\", \"b:2\"]\nmax_fails = 1").unwrap();
+        let group = Arc::new(UpstreamGroup::new("test", &config));
+
+        let first = group.select(None).unwrap();
+        let second = group.select(None).unwrap();
+        assert_ne!(first.address(), second.address());
+
+        // 失败一次后被移出，之后只会选中另一个后端
+        first.mark_failure();
+        let failed = first.address().to_string();
+        for _ in 0..4 {
+            assert_ne!(group.select(None).unwrap().address(), failed);
+        }
+
+        let config: UpstreamConfig = toml::from_str("servers = [\"a:1\", \"b:2\"]\nstrategy = \"least_conn\"").unwrap();
+        let group = Arc::new(UpstreamGroup::new("test", &config));
+        let busy = group.select(None).unwrap();
+        assert_ne!(group.select(None).unwrap().address(), busy.address());
+    }
+}
@@ -1,14 +1,16 @@
 use std::{
-    path::PathBuf,
-    process::Command,
+    fs,
+    io,
+    path::{Component, Path, PathBuf},
 };
 
 use chrono::{DateTime, Local};
-use log::error;
+use serde_json::{json, Value};
 
 use crate::{
     param::STATUS_CODES,
-    exception::Exception,
+    mime::MimeTable,
+    request::Request,
 };
 
 /// `HtmlBuilder`
@@ -75,82 +77,75 @@ impl HtmlBuilder {
     /// 通过文件列表创建一个`HtmlBuilder`
     /// 
     /// ## 参数
-    /// - `path`: 路径名
-    /// - `dir_vec`: 文件列表
+    /// - `url_path`: 目录的URL路径（而不是磁盘路径），用于标题、面包屑导航和超链接
+    /// - `entries`: 文件列表，应当已经按`options`排好序
+    /// - `options`: 排序选项，用于生成表头的排序链接
+    /// - `archive`: 是否显示打包下载的链接
     /// 
     /// ## 返回
     /// - 一个`HtmlBuilder`对象。要构建它，使用`build()`。
-    pub fn from_dir(path: &str, dir_vec: &mut Vec<PathBuf>) -> Self {
+    pub fn from_dir(url_path: &str, entries: &[DirEntryInfo], options: &ListingOptions, archive: bool) -> Self {
         let mut body = String::new();
-        sort_dir_entries(dir_vec);
+        let base = dir_url(url_path);
 
-        let mut path_mut = path;
-        // 如果path是以"/"结尾的，就移除它
-        if path_mut.ends_with("/") {
-            let len = path_mut.len();
-            path_mut = &path_mut[..(len-1)];
-        }
         // 下面的`<hr>`添加了一条水平分割线
-        body.push_str(&format!("<h1>{}的文件列表</h1><hr>", path_mut));
-        body.push_str("<table>");
-        body.push_str(r#"
+        body.push_str(&format!("<h1>{}的文件列表</h1>", breadcrumbs(&base)));
+        if archive {
+            body.push_str(r#"<p>下载此文件夹：<a href="?archive=zip">zip</a> | <a href="?archive=tar.gz">tar.gz</a></p>"#);
+        }
+        body.push_str("<hr><table>");
+        body.push_str(&format!(
+            r#"
             <tr>
-                <td>文件名</td>
-                <td>大小</td>
-                <td>修改时间</td>
+                <td><a href="{}">文件名</a></td>
+                <td><a href="{}">大小</a></td>
+                <td><a href="{}">修改时间</a></td>
             </tr>
+            "#,
+            options.header_link(SortKey::Name),
+            options.header_link(SortKey::Size),
+            options.header_link(SortKey::Mtime),
+        ));
+        if let Some((parent, _)) = base.trim_end_matches('/').rsplit_once('/') {
+            body.push_str(&format!(r#"
             <tr>
-                <td><a href="../">..</a></td>
+                <td><a href="{}/">..</a></td>
                 <td></td>
                 <td></td>
             </tr>
-            "#
-        );
-        for entry in dir_vec {
-            let metadata = entry.metadata().unwrap();
-            // 使用本地时区格式化为当前本地时间
-            let local_time: DateTime<Local> = metadata.modified().unwrap().into();
-            let formatted_time = local_time.format("%Y-%m-%d %H:%M:%S %Z").to_string();
-        
-            let filename = entry.file_name().unwrap().to_string_lossy();
-
-            if entry.is_file() {
-                let size = metadata.len();
-                let formatted_size = format_file_size(size);
-                body.push_str(&format!(
-                    r#"
-                    <tr>
-                        <td><a href="{}">{}</a></td>
-                        <td>{}</td>
-                        <td>{}</td>
-                    </tr>
-                    "#,
-                    &filename,
-                    &filename,
-                    &formatted_size,
-                    &formatted_time
-                ));
-            } else if entry.is_dir() {
-                let filename = [&filename, "/"].concat();
-                body.push_str(&format!(
-                    r#"
-                    <tr>
+            "#, escape_html(&percent_encode_path(parent))));
+        }
+        for entry in entries {
+            let formatted_time = match entry.modified {
+                // 使用本地时区格式化为当前本地时间
+                Some(t) => t.format("%Y-%m-%d %H:%M:%S %Z").to_string(),
+                None => "-".to_string(),
+            };
+            let formatted_size = match (entry.is_dir, entry.size) {
+                (true, _) => "文件夹".to_string(),
+                (false, Some(size)) => format_file_size(size),
+                (false, None) => "-".to_string(),
+            };
+            let display_name = match entry.is_dir {
+                true => format!("{}/", entry.name),
+                false => entry.name.clone(),
+            };
+            body.push_str(&format!(
+                r#"
+                <tr>
                     <td><a href="{}">{}</a></td>
-                        <td>文件夹</td>
-                        <td>{}</td>
-                    </tr>
-                    "#,
-                    &filename,
-                    &filename,
-                    &formatted_time
-                ));
-            } else {
-                // 虽然我觉得这个条件永远不会被访问到。
-                panic!();
-            }
+                    <td>{}</td>
+                    <td>{}</td>
+                </tr>
+                "#,
+                escape_html(&entry.url(&base)),
+                escape_html(&display_name),
+                &formatted_size,
+                &formatted_time
+            ));
         }
         body.push_str("</table>");
-        let title = format!("{}的文件列表", path);
+        let title = format!("{}的文件列表", escape_html(&base));
         let css = r"
             table {
                 border-collapse: collapse;
@@ -215,49 +210,320 @@ fn format_file_size(size: u64) -> String {
     format!("{:.1} {}", size, units[unit_index])
 }
 
+/// 目录列表中的一项
+/// 
+/// - `name`: 文件名
+/// - `is_dir`: 是否为文件夹
+/// - `size`: 文件大小。无法获取元数据时（如失效的符号链接）为`None`
+/// - `modified`: 修改时间。无法获取元数据时为`None`
+#[derive(Debug, Clone)]
+pub struct DirEntryInfo {
+    pub name: String,
+    pub is_dir: bool,
+    pub size: Option<u64>,
+    pub modified: Option<DateTime<Local>>,
+}
+
+impl DirEntryInfo {
+    /// 获取该项的URL，`base`为所在目录的URL路径，必须以`/`结尾
+    pub fn url(&self, base: &str) -> String {
+        let mut url = [base, &percent_encode(&self.name)].concat();
+        if self.is_dir {
+            url.push('/');
+        }
+        url
+    }
+}
+
+/// 读取目录下的所有项
+/// 
+/// 单个项的元数据读取失败时不会中断整个列表，而是将其大小和修改时间置为`None`。
+/// `show_hidden`为`false`时，隐藏文件不会出现在列表中。
+pub fn read_dir_entries(path: &str, show_hidden: bool) -> io::Result<Vec<DirEntryInfo>> {
+    let mut entries = Vec::new();
+    for entry in fs::read_dir(path)? {
+        let entry = match entry {
+            Ok(e) => e,
+            Err(_) => continue,
+        };
+        let name = entry.file_name().to_string_lossy().to_string();
+        if !show_hidden && is_hidden(&name) {
+            continue;
+        }
+        // fs::metadata会跟随符号链接，失效的符号链接会在这里返回错误
+        let info = match fs::metadata(entry.path()) {
+            Ok(metadata) => DirEntryInfo {
+                name,
+                is_dir: metadata.is_dir(),
+                size: Some(metadata.len()),
+                modified: metadata.modified().ok().map(DateTime::<Local>::from),
+            },
+            Err(_) => DirEntryInfo {
+                name,
+                is_dir: false,
+                size: None,
+                modified: None,
+            },
+        };
+        entries.push(info);
+    }
+    Ok(entries)
+}
+
+/// 判断文件名是否为隐藏文件，即以`.`开头（`.`和`..`除外）
+pub fn is_hidden(name: &str) -> bool {
+    name.starts_with('.') && name != "." && name != ".."
+}
+
+/// 目录列表的排序依据
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SortKey {
+    Name,
+    Size,
+    Mtime,
+}
+
+impl SortKey {
+    fn as_str(&self) -> &'static str {
+        match self {
+            SortKey::Name => "name",
+            SortKey::Size => "size",
+            SortKey::Mtime => "mtime",
+        }
+    }
+}
+
+/// 目录列表的输出格式
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ListingFormat {
+    Html,
+    Json,
+}
+
+/// 目录列表的选项
+/// 
+/// - 排序方式通过`?sort=name|size|mtime&order=asc|desc`指定
+/// - 输出格式通过`?format=html|json`指定。没有指定时，如果`Accept`请求头要求`application/json`，则输出JSON
+#[derive(Debug, Clone, Copy)]
+pub struct ListingOptions {
+    sort: SortKey,
+    descending: bool,
+    format: ListingFormat,
+}
+
+impl ListingOptions {
+    /// 从请求中解析列表选项，无法识别的值使用默认值（按名称升序，HTML格式）
+    pub fn from_request(request: &Request) -> Self {
+        let sort = match request.query_param("sort").as_deref() {
+            Some("size") => SortKey::Size,
+            Some("mtime") => SortKey::Mtime,
+            _ => SortKey::Name,
+        };
+        let descending = request.query_param("order").as_deref() == Some("desc");
+        let format = match request.query_param("format").as_deref() {
+            Some("json") => ListingFormat::Json,
+            Some(_) => ListingFormat::Html,
+            None => match request.header("accept") {
+                Some(accept) if accept.contains("application/json") && !accept.contains("text/html") => ListingFormat::Json,
+                _ => ListingFormat::Html,
+            },
+        };
+        Self { sort, descending, format }
+    }
+
+    /// 获取输出格式
+    pub fn format(&self) -> ListingFormat {
+        self.format
+    }
+
+    /// 生成表头的排序链接。点击当前的排序列会切换升降序
+    fn header_link(&self, key: SortKey) -> String {
+        let order = match key == self.sort && !self.descending {
+            true => "desc",
+            false => "asc",
+        };
+        format!("?sort={}&amp;order={}", key.as_str(), order)
+    }
+}
+
 /// 对文件列表进行排序，使满足：
 /// 
 /// - 文件夹在前面
 /// - 文件在后面
-/// - 文件夹和文件按照各自的顺序排列
-fn sort_dir_entries(vec: &mut Vec<PathBuf>) {
-    vec.sort_by(|a, b| {
-        let a_is_dir = a.is_dir();
-        let b_is_dir = b.is_dir();
-
-        if a_is_dir && !b_is_dir {
-            std::cmp::Ordering::Less
-        } else if !a_is_dir && b_is_dir {
-            std::cmp::Ordering::Greater
-        } else {
-            a.cmp(b)
+/// - 文件夹和文件按照`options`指定的依据各自排列
+pub fn sort_dir_entries(entries: &mut [DirEntryInfo], options: &ListingOptions) {
+    entries.sort_by(|a, b| {
+        if a.is_dir != b.is_dir {
+            return b.is_dir.cmp(&a.is_dir);
+        }
+        let ordering = match options.sort {
+            SortKey::Name => a.name.cmp(&b.name),
+            SortKey::Size => a.size.cmp(&b.size).then_with(|| a.name.cmp(&b.name)),
+            SortKey::Mtime => a.modified.cmp(&b.modified).then_with(|| a.name.cmp(&b.name)),
+        };
+        match options.descending {
+            true => ordering.reverse(),
+            false => ordering,
         }
     });
 }
 
-/// 处理对PHP文件的请求
-pub fn handle_php(path: &str, id: u128) -> Result<String, Exception> {
-    let result = Command::new("php")
-        .arg(path) // PHP文件路径
-        .output();
-    let output = match result {
-        Ok(o) => o,
-        Err(_) => return Err(Exception::PHPExecuteFailed)
-    };
-
-    if output.status.success() {    // 执行完毕
-        let stdout = String::from_utf8_lossy(&output.stdout);
-        Ok(String::from(stdout))
-    } else {    // 解释器出错
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        error!("[ID{}]PHP解释器出错：{}", id, stderr);
-        Err(Exception::PHPCodeError)
+/// 生成JSON格式的目录列表
+/// 
+/// ## 参数
+/// - `url_path`: 目录的URL路径
+/// - `dir_path`: 目录在磁盘上的路径，用于确定文件的MIME类型
+/// - `entries`: 文件列表，应当已经排好序
+/// - `mime`: MIME表
+/// 
+/// ## 返回
+/// - JSON字符串，格式为`{"path": ..., "entries": [{"name", "type", "size", "mtime", "mime", "url"}, ...]}`。
+///   `type`为`file`或`dir`，无法获取的字段为`null`，`mtime`使用RFC 3339格式。
+pub fn dir_to_json(url_path: &str, dir_path: &str, entries: &[DirEntryInfo], mime: &MimeTable) -> String {
+    let base = dir_url(url_path);
+    let items: Vec<Value> = entries.iter().map(|entry| {
+        let mime_type = match entry.is_dir {
+            true => None,
+            false => Some(mime.lookup(&Path::new(dir_path).join(&entry.name))),
+        };
+        json!({
+            "name": entry.name,
+            "type": if entry.is_dir { "dir" } else { "file" },
+            "size": if entry.is_dir { None } else { entry.size },
+            "mtime": entry.modified.map(|t| t.to_rfc3339()),
+            "mime": mime_type,
+            "url": entry.url(&base),
+        })
+    }).collect();
+    json!({
+        "path": base,
+        "entries": items,
+    }).to_string()
+}
+
+/// 将URL路径规范为以`/`结尾的目录URL
+fn dir_url(url_path: &str) -> String {
+    match url_path.ends_with('/') {
+        true => url_path.to_string(),
+        false => format!("{}/", url_path),
+    }
+}
+
+/// 生成面包屑导航，每一级目录都是一个超链接
+fn breadcrumbs(base: &str) -> String {
+    let mut html = String::from(r#"<a href="/">/</a>"#);
+    let mut href = String::from("/");
+    for segment in base.split('/').filter(|s| !s.is_empty()) {
+        href.push_str(&percent_encode(segment));
+        href.push('/');
+        html.push_str(&format!(r#"<a href="{}">{}</a>/"#, escape_html(&href), escape_html(segment)));
+    }
+    html
+}
+
+/// 对URL路径的一段进行百分号编码，保留RFC 3986中的非保留字符
+pub fn percent_encode(text: &str) -> String {
+    let mut encoded = String::with_capacity(text.len());
+    for byte in text.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+/// 对URL路径进行百分号编码，保留路径分隔符`/`
+pub fn percent_encode_path(path: &str) -> String {
+    path.split('/').map(percent_encode).collect::<Vec<_>>().join("/")
+}
+
+/// 对百分号编码的文本进行解码。非法的编码原样保留，解码结果不是合法UTF-8时进行有损转换
+pub fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            let hex = (char::from(bytes[i + 1]).to_digit(16), char::from(bytes[i + 2]).to_digit(16));
+            if let (Some(high), Some(low)) = hex {
+                decoded.push((high * 16 + low) as u8);
+                i += 3;
+                continue;
+            }
+        }
+        decoded.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+/// 将来自请求的路径拼接到根目录之下
+///
+/// 路径已经过百分号解码，去掉开头的一个`/`之后，只允许由普通的路径组件构成。
+/// 含有`..`、根目录（如`//etc/passwd`或`/%2Fetc/passwd`）等组件的路径会离开根目录，返回`None`
+pub fn join_under(root: &Path, path: &str) -> Option<PathBuf> {
+    let relative = Path::new(path.strip_prefix('/').unwrap_or(path));
+    if !relative.components().all(|c| matches!(c, Component::Normal(_))) {
+        return None;
+    }
+    Some(root.join(relative))
+}
+
+/// 对文本进行HTML转义，使其可以安全地嵌入HTML页面
+pub fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// 填充自定义错误页面中的占位符
+/// 
+/// ## 参数
+/// - `template`: 错误页面的原始内容
+/// - `code`: 状态码，替换`{{status}}`
+/// - `reason`: 状态码说明，替换`{{reason}}`
+/// - `path`: 请求路径，替换`{{path}}`。路径来自客户端，因此会先进行HTML转义
+pub fn fill_error_page(template: &str, code: u16, reason: &str, path: &str) -> String {
+    template
+        .replace("{{status}}", &code.to_string())
+        .replace("{{reason}}", reason)
+        .replace("{{path}}", &escape_html(path))
+}
+
+/// 去掉`Host`请求头中的端口号，如`example.com:8080`得到`example.com`，`[::1]:8080`得到`[::1]`
+pub fn strip_port(host: &str) -> &str {
+    match host.rsplit_once(':') {
+        Some((name, port)) if port.chars().all(|c| c.is_ascii_digit()) && !name.ends_with(':') => name,
+        _ => host,
     }
 }
 
+/// 判断主机名是否匹配`*.example.com`形式的通配符。通配符只匹配一级子域名
+/// 
+/// ## 参数
+/// - `pattern`: 小写的通配符
+/// - `name`: 小写的主机名
+pub fn wildcard_matches(pattern: &str, name: &str) -> bool {
+    pattern.strip_prefix("*.").is_some_and(|suffix| {
+        name.split_once('.').is_some_and(|(_, rest)| rest == suffix)
+    })
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::util::format_file_size;
+    use std::path::Path;
+
+    use crate::util::{format_file_size, fill_error_page, join_under, percent_decode, percent_encode, strip_port, wildcard_matches};
 
     #[test]
     fn test_file_size() {
@@ -266,4 +532,42 @@ mod tests {
         assert_eq!(format_file_size(a), "9.7 KB".to_string());
         assert_eq!(format_file_size(b), "50.6 KB".to_string());
     }
+
+    #[test]
+    fn test_percent_coding() {
+        assert_eq!(percent_decode("/a%20b/%E4%BD%A0%3c"), "/a b/你<".to_string());
+        assert_eq!(percent_decode("100%"), "100%".to_string());
+        assert_eq!(percent_decode("%zz%2"), "%zz%2".to_string());
+        assert_eq!(percent_encode("a b<你>.txt"), "a%20b%3C%E4%BD%A0%3E.txt".to_string());
+    }
+
+    #[test]
+    fn test_join_under() {
+        let root = Path::new("/srv/www");
+        assert_eq!(join_under(root, "/a/b.txt"), Some(root.join("a/b.txt")));
+        assert_eq!(join_under(root, "/"), Some(root.to_path_buf()));
+        assert_eq!(join_under(root, "/docs/"), Some(root.join("docs")));
+        assert_eq!(join_under(root, "//etc/passwd"), None);
+        assert_eq!(join_under(root, "/a/../../etc/passwd"), None);
+        assert_eq!(join_under(root, "/./a"), None);
+    }
+
+    #[test]
+    fn test_fill_error_page() {
+        let template = "<h1>{{status}} {{reason}}</h1><p>{{path}}</p>";
+        assert_eq!(
+            fill_error_page(template, 404, "Not Found", "/<script>"),
+            "<h1>404 Not Found</h1><p>/&lt;script&gt;</p>".to_string()
+        );
+    }
+
+    #[test]
+    fn test_host_matching() {
+        assert_eq!(strip_port("example.com:8080"), "example.com");
+        assert_eq!(strip_port("[::1]:8080"), "[::1]");
+        assert_eq!(strip_port("[::1]"), "[::1]");
+        assert!(wildcard_matches("*.example.com", "www.example.com"));
+        assert!(!wildcard_matches("*.example.com", "example.com"));
+        assert!(!wildcard_matches("*.example.com", "a.b.example.com"));
+    }
 }
\ No newline at end of file
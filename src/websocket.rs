@@ -0,0 +1,403 @@
+use std::{
+    fmt,
+    future::Future,
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use bytes::Bytes;
+use log::{debug, info, warn};
+use sha1::{Digest, Sha1};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+    net::TcpStream,
+};
+
+use crate::{param::HttpVersion, request::Request, upstream::BackendGuard};
+
+/// RFC 6455 规定的用于计算`Sec-WebSocket-Accept`的GUID
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// 本服务器支持的WebSocket协议版本
+pub const WEBSOCKET_VERSION: &str = "13";
+
+/// 帧的操作码
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xA;
+
+/// 关闭帧的状态码
+const CLOSE_NORMAL: u16 = 1000;
+const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+const CLOSE_INVALID_DATA: u16 = 1007;
+const CLOSE_TOO_BIG: u16 = 1009;
+
+/// 判断请求是否要求升级到WebSocket协议
+pub fn is_upgrade(request: &Request) -> bool {
+    let has_token = |name: &str, token: &str| request.header(name)
+        .is_some_and(|v| v.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)));
+    // 协议升级需要HTTP/1.1
+    *request.version() == HttpVersion::V1_1 && has_token("upgrade", "websocket") && has_token("connection", "upgrade")
+}
+
+/// 根据客户端的`Sec-WebSocket-Key`计算`Sec-WebSocket-Accept`
+pub fn accept_key(key: &str) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(key.trim().as_bytes());
+    hasher.update(WEBSOCKET_GUID.as_bytes());
+    STANDARD.encode(hasher.finalize())
+}
+
+/// 检查`Sec-WebSocket-Key`是否合法：必须是16字节随机数的base64编码
+pub fn is_valid_key(key: &str) -> bool {
+    STANDARD.decode(key.trim()).is_ok_and(|k| k.len() == 16)
+}
+
+/// 数据消息。控制帧（ping、pong、close）由[WebSocket]自动处理，不会交给处理器
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message {
+    Text(String),
+    Binary(Bytes),
+}
+
+/// WebSocket连接可以建立在任何双向的字节流上
+pub trait Transport: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Transport for T {}
+
+/// 处理器返回的Future
+pub type HandlerFuture<'a> = Pin<Box<dyn Future<Output = io::Result<()>> + Send + 'a>>;
+
+/// WebSocket 处理器
+///
+/// 握手完成后，服务器调用`handle`，处理器通过`socket`收发消息。`handle`返回后，服务器发送关闭帧（如果还没有发送）并关闭连接。
+/// 新的处理器需要在[handler]中注册名称，之后就可以在配置文件的`[websocket.endpoints]`中使用
+pub trait WebSocketHandler: fmt::Debug + Send + Sync {
+    fn handle<'a>(&'a self, socket: &'a mut WebSocket<'_>, request: &'a Request) -> HandlerFuture<'a>;
+}
+
+/// 内置的回显处理器，将收到的每条消息原样发回
+#[derive(Debug)]
+pub struct EchoHandler;
+
+impl WebSocketHandler for EchoHandler {
+    fn handle<'a>(&'a self, socket: &'a mut WebSocket<'_>, _request: &'a Request) -> HandlerFuture<'a> {
+        Box::pin(async move {
+            while let Some(message) = socket.recv().await? {
+                socket.send(message).await?;
+            }
+            Ok(())
+        })
+    }
+}
+
+/// 根据名称查找处理器，名称未知时返回`None`
+///
+/// ## 参数
+/// - `name`: 处理器的名称，目前只有`echo`
+pub fn handler(name: &str) -> Option<Arc<dyn WebSocketHandler>> {
+    match name {
+        "echo" => Some(Arc::new(EchoHandler)),
+        _ => None,
+    }
+}
+
+/// 握手完成后要做的事
+///
+/// - `Handler`: 由本服务器的处理器处理
+/// - `Tunnel`: 上游服务器接受了升级，在浏览器和上游之间双向转发字节流。后端在隧道关闭之前保持占用
+pub enum Upgrade {
+    Handler(Arc<dyn WebSocketHandler>),
+    Tunnel(BufReader<TcpStream>, Option<BackendGuard>),
+}
+
+impl fmt::Debug for Upgrade {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Upgrade::Handler(h) => f.debug_tuple("Handler").field(h).finish(),
+            Upgrade::Tunnel(_, backend) => f.debug_tuple("Tunnel").field(backend).finish(),
+        }
+    }
+}
+
+/// 挂在101响应上的升级操作
+///
+/// `Response`需要能够被克隆，因此和`BodyStream`一样被包装在`Arc<Mutex<Option<_>>>`中，只能被取出一次。
+#[derive(Debug, Clone)]
+pub struct PendingUpgrade(Arc<Mutex<Option<Upgrade>>>);
+
+impl PendingUpgrade {
+    pub fn new(upgrade: Upgrade) -> Self {
+        Self(Arc::new(Mutex::new(Some(upgrade))))
+    }
+
+    /// 取出升级操作，只有第一次调用会返回`Some`
+    pub fn take(&self) -> Option<Upgrade> {
+        self.0.lock().unwrap().take()
+    }
+}
+
+/// 在101响应写回之后接管连接
+///
+/// ## 参数
+/// - `upgrade`: 升级操作
+/// - `stream`: 到浏览器的连接
+/// - `leftover`: 读取请求头时多读到的字节，属于WebSocket数据
+/// - `request`: 升级请求
+/// - `max_message_size`: 单条消息的大小上限
+/// - `id`: 用于日志的TCP连接编号
+pub async fn serve<S: Transport>(upgrade: Upgrade, stream: &mut S, leftover: Vec<u8>, request: &Request, max_message_size: usize, id: u128) {
+    match upgrade {
+        Upgrade::Handler(handler) => {
+            let mut socket = WebSocket::new(stream, leftover, max_message_size);
+            if let Err(e) = handler.handle(&mut socket, request).await {
+                debug!("[ID{}]WebSocket处理器返回错误：{}", id, e);
+            }
+            if let Err(e) = socket.close(CLOSE_NORMAL, "").await {
+                debug!("[ID{}]关闭WebSocket连接时出错：{}", id, e);
+            }
+            info!("[ID{}]WebSocket连接已关闭", id);
+        },
+        Upgrade::Tunnel(mut upstream, backend) => {
+            if !leftover.is_empty() && upstream.write_all(&leftover).await.is_err() {
+                return;
+            }
+            match tokio::io::copy_bidirectional(stream, &mut upstream).await {
+                Ok((sent, received)) => info!("[ID{}]WebSocket隧道已关闭，发送{}字节，接收{}字节", id, sent, received),
+                Err(e) => debug!("[ID{}]WebSocket隧道出错：{}", id, e),
+            }
+            drop(backend);
+        },
+    }
+}
+
+/// 一帧数据
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+/// ### WebSocket 连接
+///
+/// 服务器一侧的RFC 6455帧编解码：
+/// - 浏览器发来的帧必须带掩码，服务器发出的帧不带掩码
+/// - 分片的消息会被重新组装，中间插入的控制帧会被立即处理
+/// - 收到ping时自动回复pong，收到关闭帧时回复关闭帧
+/// - 协议错误、非法的UTF-8文本和过大的消息会以相应的状态码关闭连接
+pub struct WebSocket<'s> {
+    stream: &'s mut dyn Transport,
+    buffer: Vec<u8>,
+    max_message_size: usize,
+    closed: bool,
+}
+
+impl<'s> WebSocket<'s> {
+    /// ## 参数
+    /// - `stream`: 已完成握手的连接
+    /// - `buffer`: 握手时多读到的字节
+    /// - `max_message_size`: 单条消息的大小上限，以字节为单位
+    pub fn new(stream: &'s mut dyn Transport, buffer: Vec<u8>, max_message_size: usize) -> Self {
+        Self {
+            stream,
+            buffer,
+            max_message_size,
+            closed: false,
+        }
+    }
+
+    /// 接收下一条数据消息
+    ///
+    /// ## 返回
+    /// - `Ok(Some(message))`: 收到一条消息
+    /// - `Ok(None)`: 连接已经关闭
+    /// - `Err`: 读取出错，或者对方违反了协议。后者会先发送关闭帧
+    pub async fn recv(&mut self) -> io::Result<Option<Message>> {
+        let mut message: Option<(u8, Vec<u8>)> = None;
+        while !self.closed {
+            let frame = self.read_frame().await?;
+            match frame.opcode {
+                OP_PING => self.write_frame(OP_PONG, &frame.payload).await?,
+                OP_PONG => {},
+                OP_CLOSE => {
+                    // 回复对方的状态码
+                    let code = frame.payload.get(..2).map(|c| u16::from_be_bytes([c[0], c[1]])).unwrap_or(CLOSE_NORMAL);
+                    self.close(code, "").await?;
+                    return Ok(None);
+                },
+                OP_TEXT | OP_BINARY if message.is_none() => message = Some((frame.opcode, frame.payload)),
+                OP_CONTINUATION if message.is_some() => {
+                    let (_, data) = message.as_mut().unwrap();
+                    data.extend_from_slice(&frame.payload);
+                },
+                _ => return Err(self.fail(CLOSE_PROTOCOL_ERROR, "分片顺序错误").await),
+            }
+            if message.as_ref().is_some_and(|(_, data)| data.len() > self.max_message_size) {
+                return Err(self.fail(CLOSE_TOO_BIG, "消息过大").await);
+            }
+            // 控制帧可以插在分片之间，只有数据帧的FIN位表示消息结束
+            if frame.fin && frame.opcode < OP_CLOSE {
+                match message.take() {
+                    Some((OP_TEXT, data)) => match String::from_utf8(data) {
+                        Ok(text) => return Ok(Some(Message::Text(text))),
+                        Err(_) => return Err(self.fail(CLOSE_INVALID_DATA, "文本消息不是合法的UTF-8").await),
+                    },
+                    Some((_, data)) => return Ok(Some(Message::Binary(Bytes::from(data)))),
+                    None => {},
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// 发送一条数据消息
+    pub async fn send(&mut self, message: Message) -> io::Result<()> {
+        match message {
+            Message::Text(text) => self.write_frame(OP_TEXT, text.as_bytes()).await,
+            Message::Binary(data) => self.write_frame(OP_BINARY, &data).await,
+        }
+    }
+
+    /// 发送关闭帧。已经发送过关闭帧时什么也不做
+    pub async fn close(&mut self, code: u16, reason: &str) -> io::Result<()> {
+        if self.closed {
+            return Ok(());
+        }
+        let mut payload = code.to_be_bytes().to_vec();
+        payload.extend_from_slice(reason.as_bytes());
+        payload.truncate(125);
+        self.write_frame(OP_CLOSE, &payload).await?;
+        self.closed = true;
+        Ok(())
+    }
+
+    /// 以指定的状态码关闭连接，返回描述错误的`io::Error`
+    async fn fail(&mut self, code: u16, reason: &str) -> io::Error {
+        warn!("WebSocket连接出错，关闭连接：{}", reason);
+        let _ = self.close(code, reason).await;
+        io::Error::new(io::ErrorKind::InvalidData, reason.to_string())
+    }
+
+    /// 确保缓冲区中至少有`n`个字节
+    async fn fill(&mut self, n: usize) -> io::Result<()> {
+        let mut chunk = [0; 4096];
+        while self.buffer.len() < n {
+            let read = self.stream.read(&mut chunk).await?;
+            if read == 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "WebSocket连接被意外关闭"));
+            }
+            self.buffer.extend_from_slice(&chunk[..read]);
+        }
+        Ok(())
+    }
+
+    /// 读取并解码一帧
+    async fn read_frame(&mut self) -> io::Result<Frame> {
+        self.fill(2).await?;
+        let (first, second) = (self.buffer[0], self.buffer[1]);
+        let fin = first & 0x80 != 0;
+        let opcode = first & 0x0F;
+        // 没有协商任何扩展，RSV位必须为0；浏览器发来的帧必须带掩码
+        if first & 0x70 != 0 || second & 0x80 == 0 {
+            return Err(self.fail(CLOSE_PROTOCOL_ERROR, "帧头不合法").await);
+        }
+        let (length, mut offset) = match second & 0x7F {
+            126 => {
+                self.fill(4).await?;
+                (u16::from_be_bytes([self.buffer[2], self.buffer[3]]) as u64, 4)
+            },
+            127 => {
+                self.fill(10).await?;
+                let mut bytes = [0; 8];
+                bytes.copy_from_slice(&self.buffer[2..10]);
+                (u64::from_be_bytes(bytes), 10)
+            },
+            n => (n as u64, 2),
+        };
+        if opcode >= OP_CLOSE && (!fin || length > 125) {
+            return Err(self.fail(CLOSE_PROTOCOL_ERROR, "控制帧不能分片，长度不能超过125字节").await);
+        }
+        if length > self.max_message_size as u64 {
+            return Err(self.fail(CLOSE_TOO_BIG, "消息过大").await);
+        }
+        let length = length as usize;
+        self.fill(offset + 4 + length).await?;
+        let mut mask = [0; 4];
+        mask.copy_from_slice(&self.buffer[offset..offset + 4]);
+        offset += 4;
+        let mut payload: Vec<u8> = self.buffer.drain(..offset + length).skip(offset).collect();
+        for (i, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[i % 4];
+        }
+        Ok(Frame { fin, opcode, payload })
+    }
+
+    /// 编码并发送一帧。服务器发出的帧不分片，也不带掩码
+    async fn write_frame(&mut self, opcode: u8, payload: &[u8]) -> io::Result<()> {
+        let mut frame = Vec::with_capacity(payload.len() + 10);
+        frame.push(0x80 | opcode);
+        match payload.len() {
+            n if n < 126 => frame.push(n as u8),
+            n if n <= u16::MAX as usize => {
+                frame.push(126);
+                frame.extend_from_slice(&(n as u16).to_be_bytes());
+            },
+            n => {
+                frame.push(127);
+                frame.extend_from_slice(&(n as u64).to_be_bytes());
+            },
+        }
+        frame.extend_from_slice(payload);
+        self.stream.write_all(&frame).await?;
+        self.stream.flush().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    /// 按照浏览器的方式编码一帧：带掩码
+    fn client_frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [1, 2, 3, 4];
+        let mut frame = vec![if fin { 0x80 } else { 0 } | opcode, 0x80 | payload.len() as u8];
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        frame
+    }
+
+    #[tokio::test]
+    async fn test_handshake_and_frames() {
+        // RFC 6455 中的示例
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+        assert!(is_valid_key("dGhlIHNhbXBsZSBub25jZQ=="));
+        assert!(!is_valid_key("short"));
+
+        let (mut client, mut server) = duplex(1024);
+        // 分片的文本消息，中间插入一个ping；第一帧在握手时已经被读入缓冲区
+        let leftover = client_frame(false, OP_TEXT, b"hel");
+        client.write_all(&client_frame(true, OP_PING, b"p")).await.unwrap();
+        client.write_all(&client_frame(true, OP_CONTINUATION, b"lo")).await.unwrap();
+        client.write_all(&client_frame(true, OP_CLOSE, &1001u16.to_be_bytes())).await.unwrap();
+
+        let mut socket = WebSocket::new(&mut server, leftover, 1024);
+        assert_eq!(socket.recv().await.unwrap(), Some(Message::Text("hello".to_string())));
+        socket.send(Message::Text("hi".to_string())).await.unwrap();
+        assert_eq!(socket.recv().await.unwrap(), None);
+
+        let mut received = [0; 11];
+        client.read_exact(&mut received).await.unwrap();
+        assert_eq!(received, [
+            0x8A, 1, b'p',          // pong
+            0x81, 2, b'h', b'i',    // 文本消息
+            0x88, 2, 0x03, 0xE9,    // 关闭帧，回复1001
+        ]);
+    }
+}